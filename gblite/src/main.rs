@@ -1,80 +1,207 @@
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::panic;
 use std::thread;
 use std::time;
 use std::fs;
+use std::io;
 
-use libgblite::memory::Memory;
+use libgblite::memory::{Memory, Model, RamInit, REGION_NAMES};
 use libgblite::cpu::CPU;
-use libgblite::ppu::PPU;
-use libgblite::util::create_file_name;
+use libgblite::ppu::{self, PPU};
+use libgblite::input;
+use libgblite::serial::TcpTransport;
+use libgblite::util;
+use libgblite::RuntimeConfig;
 
-fn print_help_and_exit() {
+fn print_help_and_exit() -> ! {
     println!("{} version v{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    println!("Option -B [file]: Run the given boot ROM before the cartridge.");
+    println!("Option --patch [file]: Apply the given IPS patch to the ROM before running it.");
+    println!("Option --cheat [code]: Apply a Game Genie (XXX-XXX[-XXX]) or GameShark (XXXXXXXX) code. Can be specified multiple times.");
     println!("Option -d: Dump system memory to a log file upon termination.");
-    println!("Option -b [address]: Break at the given PC address. Can be specified multiple times.");
+    println!("Option --access-stats: Count CPU reads and writes to each memory region, and print them on exit.");
+    println!("Option --coverage: Record which ROM addresses run, and write the map to a .cov file beside the save file.");
+    println!("Option -b [address[:ignore=N]]: Break at the given PC address, optionally skipping the first N hits. Can be specified multiple times.");
+    println!("          Addresses are hex, with or without a 0x prefix, or decimal with a # prefix.");
+    println!("Option --break-int [vblank|stat|timer|serial|joypad]: Break when the given interrupt is dispatched.");
+    println!("Option --break-sram-write: Break after the first write to enabled cartridge RAM, usually the save routine.");
+    println!("Option --pause-at-vblank [N]: Break into the debugger once the Nth VBlank starts.");
     println!("Option -k [address]: Kill the program at the given PC address. Can only be specified once.");
     println!("Option -t: Log all instruction output to a trace file.");
+    println!("Option -a: Annotate each trace line with the disassembled instruction.");
+    println!("Option --trace-every [N]: Only trace every Nth instruction.");
     println!("Option -v: Enable verbose instruction execution output.");
+    println!("Option --log-call-depth [N]: Log the CALL/RET nesting depth whenever it changes while at least N.");
+    println!("Option -m: Log every PPU mode transition.");
+    println!("Option -i: Log each interrupt dispatch with the PC it interrupted, and print a count of each type on exit.");
+    println!("Option --palette [file]: Draw DMG shades with the four RGB hex colors in the given file, lightest first.");
+    println!("Option --scanlines: Dim every other line to mimic an LCD grid. Press F to toggle it while running.");
+    println!("Option --vsync: Sync the window to the display's refresh to avoid tearing, at the cost of latency.");
+    println!("Option --unlimited-sprites: Draw every sprite on a line instead of the first 10, to remove flicker.");
+    println!("Option --fifo-renderer: Draw through the pixel FIFOs a dot at a time, for accurate mid-line effects. Slower.");
+    println!("Option --verbose-ppu: Log writes to LCDC, scroll, palette, and window registers with the PC responsible.");
+    println!("Option --model [dmg|cgb]: The hardware to emulate, CGB by default. DMG games always use DMG palettes.");
+    println!("Option -r [zero|random[:seed]|byte]: Initialize RAM to zero, random values, or a hex byte.");
+    println!("Option --warn-slow-frames: Log frames that take longer to emulate than on real hardware.");
+    println!("Option --link-listen [address:port]: Wait for another instance to connect a link cable over TCP.");
+    println!("Option --link-connect [address:port]: Connect a link cable to a listening instance over TCP.");
+    println!("Option --stdin-input: Run without a window, reading press/release/frame commands from stdin.");
+    println!("Option --deterministic: Drive real-time clocks from emulated time, so runs are reproducible.");
+    println!("Option --check-sync: Abort if the CPU and the rest of the machine fall out of step.");
+    println!("Option -s [dir]: Read and write save files and logs in the given directory.");
+    println!("Option --autosave [secs]: Also write the save file this often while running, if it changed.");
     std::process::exit(1);
 }
 
-fn main() {
-    let mut cfg = libgblite::RuntimeConfig::new();
-    let mut arg_skip = 0;
-    let mut arg_id = 1;
-
-    for arg in std::env::args().skip(1) {
-        if arg_skip > 0 {
-            arg_skip -= 1;
-        } else {
-            match arg.as_str() {
-                "-d" => { cfg.dump_mem = true; },
-                "-b" => {
-                    arg_skip = 1;
-                    let addr_str = std::env::args().nth(arg_id+1).unwrap();
-                    let addr_str = addr_str.trim_start_matches("0x");
-                    match u16::from_str_radix(addr_str, 16) {
-                        Ok(addr) => {   println!("Parsed as: {}", addr);
-                                        cfg.breakpoints.insert(addr); },
-                        Err(e) => { println!("Error parsing breakpoint argument \"{}\": {}", addr_str, e); },
-                    }
-                },
-                "-k" => {
-                    arg_skip = 1;
-                    let addr_str = std::env::args().nth(arg_id+1).unwrap();
-                    let addr_str = addr_str.trim_start_matches("0x");
-                    match u16::from_str_radix(addr_str, 16) {
-                        Ok(addr) => { cfg.killpoint = Some(addr); },
-                        Err(e) => { println!("Error parsing breakpoint argument \"{}\": {}", addr_str, e); },
-                    }
-                },
-                "-t" => { cfg.dump_trace = true; },
-                "-v" => { cfg.verbose  = true; },
-                other => {
-                    if &other[0..1] != "-" {
-                        cfg.rom_file = Some(arg.clone());
-                    } else {
-                        eprintln!("Read invalid argument, {}\n", other);
-                        print_help_and_exit();
-                    }
-                },
-            }
-        }
+fn parse_ram_init(arg: &str) -> Option<RamInit> {
+    match arg {
+        "zero" => Some(RamInit::Zero),
+        "random" => Some(RamInit::Random(0)),
+        _ if arg.starts_with("random:") => arg["random:".len()..].parse().ok().map(RamInit::Random),
+        _ => u8::from_str_radix(arg.trim_start_matches("0x"), 16).ok().map(RamInit::Pattern),
+    }
+}
 
-        arg_id += 1;
+// Map an interrupt name to its IF bit.
+fn parse_interrupt(arg: &str) -> Option<u8> {
+    match arg.to_lowercase().as_str() {
+        "vblank" => Some(0),
+        "stat" | "lcd" => Some(1),
+        "timer" => Some(2),
+        "serial" => Some(3),
+        "joypad" => Some(4),
+        _ => None,
     }
+}
 
-    let fname = match &cfg.rom_file {
-        Some(f) => f,
-        None => {
+// Parse an address given as hex, with or without a 0x prefix, or as decimal with a # prefix. Any
+// other decimal marker, like 0d, could start a bare hex address.
+fn parse_addr(arg: &str) -> Result<u16, String> {
+    let parsed = if let Some(dec) = arg.strip_prefix('#') {
+        dec.parse::<u16>()
+    } else {
+        u16::from_str_radix(arg.strip_prefix("0x").unwrap_or(arg), 16)
+    };
+    parsed.map_err(|e| format!("Error parsing address \"{}\": {}", arg, e))
+}
+
+// Parse the command line arguments, not including the program name.
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<RuntimeConfig, String> {
+    let mut cfg = RuntimeConfig::new();
+
+    while let Some(arg) = args.next() {
+        // Take the value following the current option.
+        let mut value = || args.next().ok_or_else(|| format!("Missing value for option {}", arg));
+
+        match arg.as_str() {
+            "-d" => { cfg.dump_mem = true; },
+            "--coverage" => { cfg.coverage = true; },
+            "--access-stats" => { cfg.access_stats = true; },
+            "-B" => { cfg.boot_rom_file = Some(value()?); },
+            "--patch" => { cfg.patch_file = Some(value()?); },
+            "--cheat" => { cfg.cheats.push(value()?); },
+            "-b" => {
+                let arg_str = value()?;
+                let mut parts = arg_str.splitn(2, ":ignore=");
+                let addr = parse_addr(parts.next().unwrap())?;
+                cfg.breakpoints.insert(addr);
+                if let Some(n) = parts.next() {
+                    let n = n.parse::<u32>()
+                        .map_err(|e| format!("Error parsing ignore count in \"{}\": {}", arg_str, e))?;
+                    cfg.breakpoint_ignores.insert(addr, n);
+                }
+            },
+            "--break-int" => {
+                let int_str = value()?;
+                let bit = parse_interrupt(&int_str)
+                    .ok_or_else(|| format!("Error parsing interrupt argument \"{}\"", int_str))?;
+                cfg.interrupt_breaks.insert(bit);
+            },
+            "--break-sram-write" => { cfg.break_on_sram_write = true; },
+            "--pause-at-vblank" => {
+                let n_str = value()?;
+                let n = n_str.parse::<u64>()
+                    .map_err(|e| format!("Error parsing VBlank count \"{}\": {}", n_str, e))?;
+                cfg.pause_at_vblank = Some(n);
+            },
+            "-k" => { cfg.killpoint = Some(parse_addr(&value()?)?); },
+            "-t" => { cfg.dump_trace = true; },
+            "-a" => { cfg.trace_disasm = true; },
+            "--trace-every" => {
+                let n_str = value()?;
+                cfg.trace_every = match n_str.parse::<usize>() {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err(format!("Error parsing trace interval \"{}\"", n_str)),
+                };
+            },
+            "-v" => { cfg.verbose  = true; },
+            "-m" => { cfg.log_ppu_modes = true; },
+            "--verbose-ppu" => { cfg.verbose_ppu = true; },
+            "--unlimited-sprites" => { cfg.unlimited_sprites = true; },
+            "--fifo-renderer" => { cfg.fifo_renderer = true; },
+            "--vsync" => { cfg.vsync = true; },
+            "--scanlines" => { cfg.scanline_filter = true; },
+            "--palette" => { cfg.palette_file = Some(value()?); },
+            "-i" => { cfg.log_interrupts = true; },
+            "--log-call-depth" => {
+                let n_str = value()?;
+                let n = n_str.parse::<u32>()
+                    .map_err(|e| format!("Error parsing call depth \"{}\": {}", n_str, e))?;
+                cfg.call_depth_log = Some(n);
+            },
+            "--deterministic" => { cfg.deterministic = true; },
+            "--warn-slow-frames" => { cfg.warn_slow_frames = true; },
+            "--stdin-input" => { cfg.stdin_input = true; },
+            "--check-sync" => { cfg.check_sync = true; },
+            "--autosave" => {
+                let n_str = value()?;
+                let n = n_str.parse::<u64>()
+                    .map_err(|e| format!("Error parsing autosave interval \"{}\": {}", n_str, e))?;
+                cfg.autosave_secs = Some(n);
+            },
+            "--link-listen" => { cfg.link_listen = Some(value()?); },
+            "--link-connect" => { cfg.link_connect = Some(value()?); },
+            "-s" => { cfg.save_dir = Some(value()?); },
+            "-r" => {
+                let init_str = value()?;
+                cfg.ram_init = parse_ram_init(&init_str)
+                    .ok_or_else(|| format!("Error parsing RAM init argument \"{}\"", init_str))?;
+            },
+            "--model" => {
+                let model_str = value()?;
+                cfg.model = match model_str.as_str() {
+                    "dmg" => Model::Dmg,
+                    "cgb" => Model::Cgb,
+                    _ => return Err(format!("Error parsing model argument \"{}\"", model_str)),
+                };
+            },
+            other if other.starts_with('-') => {
+                return Err(format!("Read invalid argument, {}", other));
+            },
+            _ => { cfg.rom_file = Some(arg.clone()); },
+        }
+    }
+
+    Ok(cfg)
+}
+
+fn main() {
+    let cfg = match parse_args(std::env::args().skip(1)) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprintln!("{}\n", e);
             print_help_and_exit();
-            unreachable!();
         }
     };
 
-    match fs::metadata(&fname) {
+    let fname = match &cfg.rom_file {
+        Some(f) => f,
+        None => print_help_and_exit(),
+    };
+
+    match fs::metadata(fname) {
         Ok(meta) => {
             if !meta.is_file() { print_help_and_exit(); }
         },
@@ -92,24 +219,99 @@ fn main() {
     }).expect("Error setting Ctrl-C handler");
 
     let mut mem = Memory::new(0x10000);
-    mem.load_rom_file(&fname);
+    mem.init_ram(cfg.ram_init);
+    mem.set_model(cfg.model);
+    mem.set_deterministic(cfg.deterministic);
+    if cfg.access_stats {
+        mem.enable_access_stats();
+    }
+    if cfg.break_on_sram_write {
+        mem.watch_first_sram_write();
+    }
+    mem.load_rom_file(fname);
+    if let Some(patch_fname) = &cfg.patch_file {
+        if let Err(e) = mem.apply_ips(patch_fname) {
+            eprintln!("Error applying patch \"{}\": {}", patch_fname, e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(boot_fname) = &cfg.boot_rom_file {
+        match fs::read(boot_fname) {
+            Ok(boot_rom) => mem.load_boot_rom(boot_rom),
+            Err(e) => eprintln!("Error reading boot ROM \"{}\": {}", boot_fname, e),
+        }
+    }
+
+    let link = match (&cfg.link_listen, &cfg.link_connect) {
+        (Some(addr), _) => { println!("Waiting for a link cable connection on {}", addr); Some(TcpTransport::listen(addr)) },
+        (None, Some(addr)) => Some(TcpTransport::connect(addr)),
+        (None, None) => None,
+    };
+    match link {
+        Some(Ok(transport)) => mem.connect_serial(Box::new(transport)),
+        Some(Err(e)) => eprintln!("Error connecting link cable: {}", e),
+        None => (),
+    }
     let mem = Arc::new(Mutex::new(mem));
 
-    let ppu = PPU::new(mem.clone());
+    let mut ppu = if cfg.stdin_input { PPU::new_headless(mem.clone(), &cfg) } else { PPU::new(mem.clone(), &cfg) };
+    if let Some(palette_fname) = &cfg.palette_file {
+        let palette = fs::read_to_string(palette_fname).map_err(|e| e.to_string())
+            .and_then(|text| ppu::parse_dmg_palette(&text));
+        match palette {
+            Ok(palette) => ppu.set_dmg_palette(palette),
+            Err(e) => {
+                eprintln!("Error reading palette \"{}\": {}", palette_fname, e);
+                std::process::exit(1);
+            },
+        }
+    }
     let mut z80 = CPU::new(mem.clone(), ppu, &cfg);
+    for code in &cfg.cheats {
+        if let Err(e) = z80.add_cheat(code) {
+            eprintln!("Error adding cheat: {}", e);
+            std::process::exit(1);
+        }
+    }
 
-    // Run instructions until the end of time
-    loop {
-        if !running.load(Ordering::SeqCst) {
-            println!("Received Ctrl+C signal, exiting!");
-            break;
+    // Restore battery-backed cartridge RAM from the last session.
+    z80.load_save();
+
+    // Run instructions until the end of time. If the emulator panics, the trace and save file are
+    // still written before unwinding any further.
+    let result = z80.run_guarded(|z80| {
+        if cfg.stdin_input {
+            let stdin = io::stdin();
+            if let Err(e) = input::run_script(z80, stdin.lock()) {
+                eprintln!("{}", e);
+            }
+            return;
         }
 
-        if !z80.tick() { break; }
+        loop {
+            if !running.load(Ordering::SeqCst) {
+                println!("Received Ctrl+C signal, exiting!");
+                break;
+            }
+
+            if z80.tick().is_none() { break; }
+        }
+    });
+
+    if let Err(e) = result {
+        panic::resume_unwind(e);
+    }
+
+    if let Some(stats) = mem.lock().unwrap().access_stats() {
+        println!("Memory accesses:");
+        println!("  {:<6} {:>12} {:>12}", "Region", "Reads", "Writes");
+        for (name, (reads, writes)) in REGION_NAMES.iter().zip(stats.iter()) {
+            println!("  {:<6} {:>12} {:>12}", name, reads, writes);
+        }
     }
 
     if cfg.dump_mem {
-        let fname = create_file_name("_mem");
+        let fname = util::create_file_name(cfg.save_dir.as_deref(), "_mem");
         let mref = mem.lock().unwrap();
         match (*mref).dump_to_file(&fname) {
             Ok(_r) => (),
@@ -119,3 +321,43 @@ fn main() {
 
     thread::sleep(time::Duration::from_millis(100));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<RuntimeConfig, String> {
+        parse_args(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn address_formats() {
+        assert_eq!(parse_addr("0x150"), Ok(0x150));
+        assert_eq!(parse_addr("c000"), Ok(0xc000));
+        assert_eq!(parse_addr("#336"), Ok(0x150));
+        assert_eq!(parse_addr("0d00"), Ok(0x0d00));
+    }
+
+    #[test]
+    fn breakpoint_and_killpoint() {
+        let cfg = parse(&["-b", "0x150:ignore=3", "-k", "1ff", "rom.gb"]).unwrap();
+        assert!(cfg.breakpoints.contains(&0x150));
+        assert_eq!(cfg.breakpoint_ignores.get(&0x150), Some(&3));
+        assert_eq!(cfg.killpoint, Some(0x1ff));
+        assert_eq!(cfg.rom_file.as_deref(), Some("rom.gb"));
+    }
+
+    #[test]
+    fn missing_value() {
+        assert!(parse(&["rom.gb", "-b"]).is_err());
+        assert!(parse(&["rom.gb", "-k"]).is_err());
+    }
+
+    #[test]
+    fn malformed_address() {
+        assert!(parse(&["-b", "0xg00", "rom.gb"]).is_err());
+        assert!(parse(&["-k", "10000", "rom.gb"]).is_err());
+        assert!(parse(&["-b", "#70000", "rom.gb"]).is_err());
+        assert!(parse(&["-b", "150:ignore=x", "rom.gb"]).is_err());
+    }
+}
@@ -0,0 +1,67 @@
+// Instruction throughput benchmark. Runs a synthetic ROM on a headless machine for a fixed
+// number of clocks and reports instructions per second. Run with `cargo bench`.
+
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use libgblite::RuntimeConfig;
+use libgblite::cpu::CPU;
+use libgblite::memory::Memory;
+use libgblite::ppu::PPU;
+
+const CLOCKS_PER_RUN: u64 = 10_000_000;
+const RUNS: usize = 5;
+
+// A loop of common loads, ALU ops, (HL) accesses, and stack ops, starting at the 0x100 entry point.
+fn synthetic_rom() -> Vec<u8> {
+    let program: &[u8] = &[
+        0x21, 0x00, 0xc0, // LD HL,0xc000
+        0x06, 0x40,       // LD B,0x40
+        0x3e, 0x01,       // LD A,0x01
+        0x80,             // ADD A,B
+        0xa8,             // XOR B
+        0x77,             // LD (HL),A
+        0x23,             // INC HL
+        0x86,             // ADD A,(HL)
+        0xcb, 0x37,       // SWAP A
+        0xc5,             // PUSH BC
+        0xc1,             // POP BC
+        0x05,             // DEC B
+        0x20, 0xf4,       // JR NZ,-12
+        0xc3, 0x00, 0x01, // JP 0x100
+    ];
+
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + program.len()].copy_from_slice(program);
+    rom
+}
+
+fn build_cpu(rcfg: &RuntimeConfig) -> CPU {
+    let mut mem = Memory::new(0x10000);
+    mem.load_rom(synthetic_rom());
+    let mem = Arc::new(Mutex::new(mem));
+    let ppu = PPU::new_headless(mem.clone(), rcfg);
+    CPU::new(mem, ppu, rcfg)
+}
+
+fn main() {
+    let rcfg = RuntimeConfig::new();
+    let mut best = 0.0;
+
+    for run in 0..RUNS {
+        let mut cpu = build_cpu(&rcfg);
+        let mut instructions: u64 = 0;
+
+        let start = Instant::now();
+        cpu.run_until(|_, _| { instructions += 1; false }, CLOCKS_PER_RUN);
+        let secs = start.elapsed().as_secs_f64();
+
+        let rate = instructions as f64 / secs;
+        println!("run {}: {} instructions in {:.3}s ({:.0} inst/s)", run, instructions, secs, rate);
+        if rate > best {
+            best = rate;
+        }
+    }
+
+    println!("best: {:.0} inst/s", best);
+}
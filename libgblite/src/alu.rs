@@ -57,14 +57,13 @@ pub struct AluOutput {
     pub flag_cy: bool,
 }
 
+// H and CY are always computed from the operands, and Z is passed through unchanged.
 pub struct AluInput16 {
     pub subtract: bool,
     pub op_a: u16,
     pub op_b: u16,
     pub flag_z: bool,
     pub flag_n: bool,
-    pub flag_h: bool,
-    pub flag_cy: bool,
 }
 
 pub struct AluOutput16 {
@@ -184,7 +183,7 @@ pub fn alu16(input: AluInput16) -> AluOutput16 {
 
     AluOutput16 {
         result: result.0,
-        flag_z: result.0 == 0,
+        flag_z: input.flag_z,
         flag_n: input.flag_n,
         flag_h: hresult > 0xfff,
         flag_cy: result.1,
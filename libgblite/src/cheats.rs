@@ -0,0 +1,65 @@
+// Cheat codes. Game Genie codes patch ROM reads, and are written "VVA-AAA" or "VVA-AAA-CCC": two
+// digits of value, four scrambled digits of address, and an optional scrambled byte that the ROM
+// must hold for the patch to apply, so a code only hits the intended bank. GameShark codes are
+// "TTVVLLHH": a type, the value, and a little endian RAM address that's rewritten every frame.
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Cheat {
+    GameGenie { addr: u16, value: u8, compare: Option<u8> },
+    GameShark { addr: u16, value: u8 },
+}
+
+// Parse a Game Genie or GameShark code. Dashes tell the two formats apart.
+pub fn parse(code: &str) -> Result<Cheat, String> {
+    let code = code.trim();
+    let digits = code.chars()
+        .filter(|c| *c != '-')
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| format!("cheat code \"{}\" isn't hex", code))?;
+
+    if code.contains('-') {
+        if digits.len() != 6 && digits.len() != 9 {
+            return Err(format!("Game Genie code \"{}\" should have 6 or 9 digits", code));
+        }
+        let value = (digits[0] << 4) | digits[1];
+        let addr = ((digits[5] ^ 0xf) as u16) << 12 | (digits[2] as u16) << 8 |
+                   (digits[3] as u16) << 4 | digits[4] as u16;
+        if addr >= 0x8000 {
+            return Err(format!("Game Genie code \"{}\" doesn't patch ROM", code));
+        }
+        // The compare byte is digits 7 and 9, rotated and XORed. Digit 8 isn't used.
+        let compare = if digits.len() == 9 {
+            Some(((digits[6] << 4) | digits[8]).rotate_right(2) ^ 0xba)
+        } else {
+            None
+        };
+        Ok(Cheat::GameGenie { addr, value, compare })
+    } else {
+        if digits.len() != 8 {
+            return Err(format!("GameShark code \"{}\" should have 8 digits", code));
+        }
+        // The type selects a CGB WRAM bank for some codes. Banking isn't emulated, so it's ignored.
+        let value = (digits[2] << 4) | digits[3];
+        let addr = ((digits[6] as u16) << 12) | ((digits[7] as u16) << 8) |
+                   ((digits[4] as u16) << 4) | digits[5] as u16;
+        Ok(Cheat::GameShark { addr, value })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_codes() {
+        assert_eq!(parse("3E1-50F"), Ok(Cheat::GameGenie { addr: 0x0150, value: 0x3e, compare: None }));
+        assert_eq!(parse("3e1-50f-e6a"), Ok(Cheat::GameGenie { addr: 0x0150, value: 0x3e, compare: Some(0x00) }));
+        assert_eq!(parse("010F34C1"), Ok(Cheat::GameShark { addr: 0xc134, value: 0x0f }));
+
+        assert!(parse("3E1-507").is_err()); // Address 0x8150
+        assert!(parse("3E1-50").is_err());
+        assert!(parse("010F34C").is_err());
+        assert!(parse("010G34C1").is_err());
+    }
+}
@@ -0,0 +1,58 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// The time source for anything that depends on real time, like the MBC3 RTC and the PPU's
+// framerate logging. A deterministic clock is driven only by emulated cycles, so two runs with
+// the same inputs see the same times.
+
+// Machine clock frequency in Hz, used to convert emulated cycles to time.
+const CLOCK_HZ: u64 = 4_194_304;
+
+pub struct Clock {
+    start: Option<Instant>, // None for a deterministic clock.
+    cycles: u64,            // Emulated clock cycles elapsed.
+}
+
+impl Clock {
+    pub fn new(deterministic: bool) -> Clock {
+        Clock {
+            start: if deterministic { None } else { Some(Instant::now()) },
+            cycles: 0,
+        }
+    }
+
+    // Start over from zero, with the same time source.
+    pub fn restart(&mut self) {
+        *self = Clock::new(self.start.is_none());
+    }
+
+    pub fn advance(&mut self, cycles: u64) {
+        self.cycles += cycles;
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Restore the cycle count from a save state. A real time clock keeps running from now.
+    pub fn set_cycles(&mut self, cycles: u64) {
+        self.cycles = cycles;
+    }
+
+    // Seconds since the Unix epoch on the host, or None for a deterministic clock, which can't
+    // depend on when it's run.
+    pub fn unix_time(&self) -> Option<u64> {
+        self.start?;
+        SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+    }
+
+    // Time elapsed since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        match self.start {
+            Some(start) => start.elapsed(),
+            None => {
+                let nanos = (self.cycles % CLOCK_HZ) * 1_000_000_000 / CLOCK_HZ;
+                Duration::from_secs(self.cycles / CLOCK_HZ) + Duration::from_nanos(nanos)
+            },
+        }
+    }
+}
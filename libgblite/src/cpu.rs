@@ -1,14 +1,22 @@
 use std::io;
 use std::io::{Write, BufWriter};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::fs::File;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::Duration;
 
+#[cfg(feature = "cli")]
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
-use crate::memory::Memory;
+use crate::memory::{Memory, RamInit, SaveFit};
 use crate::memory::MemClient;
+use crate::memory::{IE_ADDR, IF_ADDR};
 use crate::ppu::{PPU, PPUReg};
 use crate::lookup::Instruction;
 use crate::registers::*;
@@ -17,6 +25,9 @@ use crate::lookup;
 use crate::RuntimeConfig;
 use crate::alu;
 use crate::alu::AluOp;
+use crate::cheats::{self, Cheat};
+use crate::state::StateReader;
+use crate::timer::DIV_ADDR;
 
 pub struct CPU {
     pub regs: RegisterCache,
@@ -26,25 +37,134 @@ pub struct CPU {
     flagmod: FlagStatus,
     pc: u16,
     ir_enabled: bool,
+    ei_pending: bool, // Set by EI, interrupts are enabled once the next instruction starts.
+    stopped: bool,   // Set by STOP, cleared by a joypad press.
+    halted: bool,    // Set by HALT, cleared once an enabled interrupt is requested.
+    halt_bug: bool,  // HALT didn't halt with IME clear, so the next opcode byte is read twice.
     quit: bool,
     flag_z: bool,
     flag_n: bool,
     flag_h: bool,
     flag_cy: bool,
     stepinto: bool,
+    step_count: u32,
     breaks: HashSet<u16>,
+    break_ignores: HashMap<u16, u32>,
+    break_hits: HashMap<u16, u32>, // Number of times each breakpoint has been reached.
+    int_breaks: HashSet<u8>,
+    int_break: Option<u8>, // Set when an interrupt we break on was just dispatched.
+    broke: bool,           // Set when execution stops at a break, for step_frame to end early.
     killpoint: Option<u16>,
+    pause_at_vblank: Option<u64>, // Break once this many VBlanks have started.
     stepover_break: Option<u16>,
+    #[cfg(feature = "cli")]
     last_break_arg: Option<String>,
     trace_file: Option<BufWriter<File>>,
+    trace_disasm: bool,
+    trace_every: usize,
+    trace_count: usize,       // Instructions seen by the trace, to pick every trace_every-th.
+    save_dir: Option<String>,
+    rom_file: Option<String>, // The loaded ROM, used to name its save file.
+    autosave_secs: Option<u64>,
+    last_autosave: Duration,  // Machine time the current autosave interval started.
+    autosave_check: u64,      // Clock count to check for an autosave at next.
+    ram_init: RamInit,
+    clocks: u64,              // Clock cycles run, including interrupt dispatches.
+    step_clocks: u32,         // Clock cycles taken by the last process call.
+    check_sync: bool,         // Assert clocks matches the PPU and machine clock after each instruction.
+    rom_swapped: bool,        // Set when a ROM or state is loaded, so a fetched instruction isn't run.
     verbose: bool,
+    log_interrupts: bool,
+    call_depth: u32,          // CALLs, RSTs, and interrupts not yet returned from.
+    call_depth_log: Option<u32>, // Log call depth changes at or above this depth.
+    break_callback: Option<BreakCallback>, // Replaces the interactive prompt when set.
+    interrupt_counts: [u64; 5], // Number of dispatches for each interrupt, indexed by IF bit.
+    coverage: Option<Vec<u8>>,  // One bit per ROM byte, set when an instruction is fetched from it.
+    snapshots: HashMap<String, RegisterSnapshot>, // Saved by the debugger's snap command.
+    ram_cheats: Vec<(u16, u8)>, // GameShark writes, reapplied at the start of every VBlank.
+    cheat_frame: u64,           // The frame the RAM cheats were last applied in.
 }
 
+// Called with the CPU whenever execution breaks, for library users that can't use the prompt.
+pub type BreakCallback = Box<dyn FnMut(&mut CPU)>;
+
+// What a single step_described call ran, ready for a front end to display.
+#[derive(Clone, PartialEq, Debug)]
+pub struct StepInfo {
+    pub mnemonic: String,       // The instruction run, or the HALT or STOP still waiting.
+    pub pc_before: u16,
+    pub pc_after: u16,
+    pub clocks: u32,            // Clock cycles taken, including any interrupt dispatch.
+    pub interrupt: Option<u8>,  // IF bit of the interrupt dispatched before the instruction.
+}
+
+// The registers and flags at one point in time, to compare against later.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct RegisterSnapshot {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub flag_z: bool,
+    pub flag_n: bool,
+    pub flag_h: bool,
+    pub flag_cy: bool,
+}
+
+impl RegisterSnapshot {
+    // Each register's name and value, formatted for display.
+    fn entries(&self) -> [(&'static str, String); 13] {
+        let flag = |f: bool| String::from(if f { "1" } else { "0" });
+        [
+            ("A", format!("0x{:02x}", self.a)), ("B", format!("0x{:02x}", self.b)),
+            ("C", format!("0x{:02x}", self.c)), ("D", format!("0x{:02x}", self.d)),
+            ("E", format!("0x{:02x}", self.e)), ("H", format!("0x{:02x}", self.h)),
+            ("L", format!("0x{:02x}", self.l)),
+            ("SP", format!("0x{:04x}", self.sp)), ("PC", format!("0x{:04x}", self.pc)),
+            ("Z", flag(self.flag_z)), ("N", flag(self.flag_n)),
+            ("H flag", flag(self.flag_h)), ("CY", flag(self.flag_cy)),
+        ]
+    }
+
+    // Describe each register that differs in the later snapshot, as "B: 0x00 -> 0x05".
+    pub fn diff(&self, later: &RegisterSnapshot) -> Vec<String> {
+        self.entries().iter().zip(later.entries().iter())
+            .filter(|(old, new)| old.1 != new.1)
+            .map(|(old, new)| format!("{}: {} -> {}", old.0, old.1, new.1))
+            .collect()
+    }
+}
+
+// Clocks between autosave checks, about one frame.
+const AUTOSAVE_CHECK_CLOCKS: u64 = 70224;
+
+// Save states start with this magic and format version, then the loaded ROM's header and global
+// checksums, so a state can't be restored into a different game or by an incompatible build.
+const STATE_MAGIC: &[u8] = b"GBLS";
+const STATE_VERSION: u8 = 3;
+
+// A CGB speed switch stalls the CPU for 2050 machine cycles while the clock settles.
+const SPEED_SWITCH_CLOCKS: u32 = 2050 * 4;
+
+// Interrupt names, indexed by their IF/IE bit.
+const INTERRUPT_NAMES: [&str; 5] = ["VBlank", "LCD STAT", "Timer", "Serial", "Joypad"];
+
 impl Drop for CPU {
     fn drop(&mut self) {
-        match &mut self.trace_file {
-            Some(f) => f.flush().unwrap(),
-            _ => ()
+        if let Err(e) = self.flush_trace() {
+            println!("Couldn't flush trace file: {}", e);
+        }
+
+        if self.log_interrupts {
+            println!("Interrupt dispatch summary:");
+            for (name, count) in INTERRUPT_NAMES.iter().zip(self.interrupt_counts.iter()) {
+                println!("  {:<8} {}", name, count);
+            }
         }
     }
 }
@@ -53,7 +173,7 @@ impl CPU {
     pub fn new(mem: Arc<Mutex<Memory>>, ppu: PPU, rcfg: &RuntimeConfig) -> CPU {
 
         let trace_file = if rcfg.dump_trace {
-            let trace_fname = util::create_file_name("_trace");
+            let trace_fname = util::create_file_name(rcfg.save_dir.as_deref(), "_trace");
             match File::create(trace_fname.as_str()) {
                 Ok(f) => Some(BufWriter::new(f)),
                 Err(why) => {
@@ -65,44 +185,343 @@ impl CPU {
             None
         };
 
+        // With a boot ROM, start from its entry point instead of the cartridge's.
+        let start_pc = if mem.lock().unwrap().boot_rom_mapped() { 0x0000 } else { 0x0100 };
+        let coverage = if rcfg.coverage { Some(vec![0; mem.lock().unwrap().rom_len().div_ceil(8)]) } else { None };
+
         let mut c = CPU {
             regs: RegisterCache::new(),
             mem: mem,
             ppu: ppu,
             inst: lookup::get_instruction(0x0),
-            flagmod: lookup::get_flagmod(0x0),
-            pc: 0x100,
-            ir_enabled: true,
+            flagmod: lookup::get_flags(0x0),
+            pc: start_pc,
+            ir_enabled: false,
+            ei_pending: false,
+            stopped: false,
+            halted: false,
+            halt_bug: false,
             quit: false,
             flag_z: true,
             flag_n: false,
             flag_h: true,
             flag_cy: true,
             stepinto: false,
+            step_count: 0,
             breaks: rcfg.breakpoints.clone(),
+            break_ignores: rcfg.breakpoint_ignores.clone(),
+            break_hits: HashMap::new(),
+            int_breaks: rcfg.interrupt_breaks.clone(),
+            int_break: None,
+            broke: false,
             killpoint: rcfg.killpoint,
+            pause_at_vblank: rcfg.pause_at_vblank,
             stepover_break: None,
+            #[cfg(feature = "cli")]
             last_break_arg: None,
             trace_file: trace_file,
+            trace_disasm: rcfg.trace_disasm,
+            trace_every: rcfg.trace_every.max(1),
+            trace_count: 0,
+            save_dir: rcfg.save_dir.clone(),
+            rom_file: rcfg.rom_file.clone(),
+            autosave_secs: rcfg.autosave_secs,
+            last_autosave: Duration::from_secs(0),
+            autosave_check: 0,
+            ram_init: rcfg.ram_init,
+            rom_swapped: false,
             verbose: rcfg.verbose,
+            log_interrupts: rcfg.log_interrupts,
+            call_depth: 0,
+            call_depth_log: rcfg.call_depth_log,
+            break_callback: None,
+            interrupt_counts: [0; 5],
+            snapshots: HashMap::new(),
+            ram_cheats: Vec::new(),
+            cheat_frame: 0,
+            coverage,
+            clocks: 0,
+            step_clocks: 0,
+            check_sync: rcfg.check_sync,
         };
 
-        // Setup initial register values
-        // TODO: modify this for GBC
-        c.regs.set(Reg8::A, 0x01);
-        c.regs.set(Reg8::C, 0x13);
-        c.regs.set(Reg8::E, 0xd8);
-        c.regs.set(Reg16::HL, 0x014D);
-        c.regs.set(Reg16::SP, 0xFFFE);
-        c.regs.set(Reg16::PC, c.pc);
-        c.regs.set_flag(Flag::Z, c.flag_z);
-        c.regs.set_flag(Flag::N, c.flag_n);
-        c.regs.set_flag(Flag::H, c.flag_h);
-        c.regs.set_flag(Flag::CY, c.flag_cy);
-
+        c.reset_registers();
         c
     }
 
+    // Setup initial register values
+    // TODO: modify this for GBC
+    fn reset_registers(&mut self) {
+        self.regs = RegisterCache::new();
+        self.regs.set(Reg8::A, 0x01);
+        self.regs.set(Reg8::C, 0x13);
+        self.regs.set(Reg8::E, 0xd8);
+        self.regs.set(Reg16::HL, 0x014D);
+        self.regs.set(Reg16::SP, 0xFFFE);
+        self.regs.set(Reg16::PC, self.pc);
+        self.regs.set_flag(Flag::Z, self.flag_z);
+        self.regs.set_flag(Flag::N, self.flag_n);
+        self.regs.set_flag(Flag::H, self.flag_h);
+        self.regs.set_flag(Flag::CY, self.flag_cy);
+    }
+
+    // Reset the machine and load the ROM at the given path in place of the current one, writing
+    // the current game's save file first. Memory settings like the model and link cable carry
+    // over. Boot ROMs aren't rerun, the new ROM starts at 0x100.
+    pub fn load_rom(&mut self, path: &str) -> io::Result<()> {
+        let rom = fs::read(path)?;
+        self.write_save()?;
+
+        {
+            let mut mref = self.mem.lock().unwrap();
+            mref.reset(self.ram_init);
+            mref.load_rom(rom);
+        }
+        self.rom_file = Some(path.to_string());
+        self.load_save();
+        self.ppu.reset();
+
+        self.pc = 0x0100;
+        self.ir_enabled = false;
+        self.ei_pending = false;
+        self.stopped = false;
+        self.halted = false;
+        self.halt_bug = false;
+        self.inst = lookup::get_instruction(0x0);
+        self.flagmod = lookup::get_flags(0x0);
+        self.flag_z = true;
+        self.flag_n = false;
+        self.flag_h = true;
+        self.flag_cy = true;
+        self.break_hits.clear();
+        self.ram_cheats.clear();
+        self.interrupt_counts = [0; 5];
+        self.call_depth = 0;
+        self.clocks = 0;
+        self.last_autosave = Duration::from_secs(0);
+        self.autosave_check = 0;
+        if let Some(coverage) = &mut self.coverage {
+            *coverage = vec![0; self.mem.lock().unwrap().rom_len().div_ceil(8)];
+        }
+        self.reset_registers();
+        self.rom_swapped = true;
+        Ok(())
+    }
+
+    // Restore battery-backed cartridge RAM from the ROM's save file, if there is one.
+    pub fn load_save(&self) {
+        let rom_file = match &self.rom_file {
+            Some(f) => f,
+            None => return,
+        };
+        let mut mref = self.mem.lock().unwrap();
+        if mref.has_battery() {
+            let save_path = util::save_file_path(self.save_dir.as_deref(), rom_file, "sav");
+            if let Ok(data) = fs::read(&save_path) {
+                println!("Loading save file \"{}\"", save_path.display());
+                match mref.load_ext_ram(&data) {
+                    Ok(SaveFit::Exact) => (),
+                    Ok(SaveFit::Padded) => println!("Save file is shorter than the cartridge RAM, zeroing the rest"),
+                    Ok(SaveFit::Truncated) => println!("Save file is longer than the cartridge RAM, ignoring the extra bytes"),
+                    Err(e) => println!("Not loading corrupt save file: {}", e),
+                }
+            }
+        }
+    }
+
+    fn mark_executed(&mut self, addr: u16) {
+        if let Some(coverage) = &mut self.coverage {
+            if let Some(offset) = self.mem.lock().unwrap().rom_offset(addr) {
+                coverage[offset / 8] |= 1 << (offset % 8);
+            }
+        }
+    }
+
+    // True if coverage is on, and an instruction was fetched from the given ROM offset.
+    pub fn executed(&self, offset: usize) -> bool {
+        match &self.coverage {
+            Some(coverage) => coverage.get(offset / 8).is_some_and(|b| (b >> (offset % 8)) & 1 != 0),
+            None => false,
+        }
+    }
+
+    // Write the coverage bitmap next to the ROM's save file, with bit N of byte M set if an
+    // instruction was fetched from ROM offset 8*M + N. Returns the path written, if any.
+    pub fn write_coverage(&self) -> io::Result<Option<PathBuf>> {
+        match (&self.coverage, &self.rom_file) {
+            (Some(coverage), Some(rom_file)) => {
+                let path = util::save_file_path(self.save_dir.as_deref(), rom_file, "cov");
+                fs::write(&path, coverage)?;
+                Ok(Some(path))
+            },
+            _ => Ok(None),
+        }
+    }
+
+    // Write battery-backed cartridge RAM to the ROM's save file. Safe to call after a panic.
+    pub fn write_save(&self) -> io::Result<()> {
+        let rom_file = match &self.rom_file {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+        // A panic while memory was locked poisons it, but its contents are still worth saving.
+        let mut mref = self.mem.lock().unwrap_or_else(|e| e.into_inner());
+        if mref.has_battery() {
+            fs::write(util::save_file_path(self.save_dir.as_deref(), rom_file, "sav"), mref.save_data())?;
+            mref.take_ext_ram_dirty();
+        }
+        Ok(())
+    }
+
+    // Write the registers, PPU, and memory to a save state file, tagged with the loaded ROM's
+    // checksums.
+    pub fn save_state(&self, path: &str) -> io::Result<()> {
+        let mref = self.mem.lock().unwrap();
+        let (header, global) = mref.rom_checksums();
+        let flags = (self.flag_z as u8) << 7 | (self.flag_n as u8) << 6 |
+                    (self.flag_h as u8) << 5 | (self.flag_cy as u8) << 4;
+
+        let mut state = STATE_MAGIC.to_vec();
+        state.push(STATE_VERSION);
+        state.push(header);
+        state.extend_from_slice(&global.to_be_bytes());
+        for reg in &[Reg8::A, Reg8::B, Reg8::C, Reg8::D, Reg8::E, Reg8::H, Reg8::L] {
+            state.push(self.regs.get(*reg));
+        }
+        state.push(flags);
+        state.extend_from_slice(&self.regs.get(Reg16::SP).to_le_bytes());
+        state.extend_from_slice(&self.regs.get(Reg16::PC).to_le_bytes());
+        state.extend_from_slice(&[self.ir_enabled as u8, self.ei_pending as u8,
+                                  self.halted as u8, self.stopped as u8, self.halt_bug as u8]);
+        state.extend_from_slice(&self.clocks.to_le_bytes());
+        let ppu_state = self.ppu.save_state();
+        state.extend_from_slice(&(ppu_state.len() as u32).to_le_bytes());
+        state.extend_from_slice(&ppu_state);
+        state.extend_from_slice(&mref.save_state());
+        fs::write(path, state)
+    }
+
+    // Restore a save state file. Fails without changing anything if the file isn't a save state,
+    // was written by a different format version, or was saved from a different ROM.
+    pub fn load_state(&mut self, path: &str) -> io::Result<()> {
+        let state = fs::read(path)?;
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        let header_len = STATE_MAGIC.len() + 4;
+        if state.len() < header_len || !state.starts_with(STATE_MAGIC) {
+            return Err(invalid(format!("\"{}\" is not a save state", path)));
+        }
+        let version = state[STATE_MAGIC.len()];
+        if version != STATE_VERSION {
+            return Err(invalid(format!("save state format version {} isn't supported, expected {}",
+                                       version, STATE_VERSION)));
+        }
+
+        let mut mref = self.mem.lock().unwrap();
+        let saved = (state[header_len - 3], u16::from_be_bytes([state[header_len - 2], state[header_len - 1]]));
+        let loaded = mref.rom_checksums();
+        if saved != loaded {
+            return Err(invalid(format!(
+                "save state is for a different ROM: checksums 0x{:02x}/0x{:04x}, loaded ROM has 0x{:02x}/0x{:04x}",
+                saved.0, saved.1, loaded.0, loaded.1)));
+        }
+
+        let mut r = StateReader::new(&state[header_len..]);
+        let regs = r.take(17).map_err(invalid)?;
+        let clocks = r.u64().map_err(invalid)?;
+        let ppu_len = r.u32().map_err(invalid)? as usize;
+        let ppu_state = r.take(ppu_len).map_err(invalid)?;
+        let mem_state = r.rest();
+        if mem_state.len() != mref.state_len() {
+            return Err(invalid(format!("memory state is {} bytes, expected {}", mem_state.len(), mref.state_len())));
+        }
+        self.ppu.load_state(ppu_state).map_err(invalid)?;
+        mref.load_state(mem_state).map_err(invalid)?;
+        drop(mref);
+
+        for (reg, val) in [Reg8::A, Reg8::B, Reg8::C, Reg8::D, Reg8::E, Reg8::H, Reg8::L].iter().zip(regs) {
+            self.regs.set(*reg, *val);
+        }
+        self.flag_z = regs[7] & 0x80 != 0;
+        self.flag_n = regs[7] & 0x40 != 0;
+        self.flag_h = regs[7] & 0x20 != 0;
+        self.flag_cy = regs[7] & 0x10 != 0;
+        self.regs.set_flag(Flag::Z, self.flag_z);
+        self.regs.set_flag(Flag::N, self.flag_n);
+        self.regs.set_flag(Flag::H, self.flag_h);
+        self.regs.set_flag(Flag::CY, self.flag_cy);
+        self.regs.set(Reg16::SP, u16::from_le_bytes([regs[8], regs[9]]));
+        self.pc = u16::from_le_bytes([regs[10], regs[11]]);
+        self.regs.set(Reg16::PC, self.pc);
+        self.ir_enabled = regs[12] != 0;
+        self.ei_pending = regs[13] != 0;
+        self.halted = regs[14] != 0;
+        self.stopped = regs[15] != 0;
+        self.halt_bug = regs[16] != 0;
+        self.clocks = clocks;
+        self.call_depth = 0;
+        self.rom_swapped = true;
+
+        // Time moved with the restore, so start the autosave interval and cheat frame over.
+        self.last_autosave = self.mem.lock().unwrap().elapsed();
+        self.autosave_check = self.clocks;
+        self.cheat_frame = self.ppu.frame_count();
+        Ok(())
+    }
+
+    // Write the save file if the autosave interval has passed and battery RAM changed since the
+    // last write. Only looks at the clock about once a frame, to keep it off the hot path.
+    fn autosave(&mut self) {
+        let secs = match self.autosave_secs {
+            Some(secs) if self.clocks >= self.autosave_check => secs,
+            _ => return,
+        };
+        self.autosave_check = self.clocks + AUTOSAVE_CHECK_CLOCKS;
+
+        let (now, dirty) = {
+            let mut mref = self.mem.lock().unwrap();
+            let now = mref.elapsed();
+            if now < self.last_autosave + Duration::from_secs(secs) { return; }
+            (now, mref.has_battery() && mref.take_ext_ram_dirty())
+        };
+        self.last_autosave = now;
+        if dirty {
+            if let Err(e) = self.write_save() {
+                println!("Error autosaving: {}", e);
+            }
+        }
+    }
+
+    // Write any buffered trace lines to the trace file. Safe to call after a panic, so the trace
+    // leading up to a crash isn't lost.
+    pub fn flush_trace(&mut self) -> io::Result<()> {
+        match &mut self.trace_file {
+            Some(f) => f.flush(),
+            None => Ok(()),
+        }
+    }
+
+    // Run the given loop, then write the save file and coverage map. If the loop panics, the trace
+    // is flushed and the save written anyway, and the panic is returned for the caller to resume.
+    pub fn run_guarded<F>(&mut self, run: F) -> thread::Result<()>
+        where F: FnOnce(&mut CPU) {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| run(self)));
+        if result.is_err() {
+            eprintln!("Emulator panicked, flushing trace and save file before exiting.");
+            if let Err(e) = self.flush_trace() {
+                eprintln!("Error flushing trace file: {}", e);
+            }
+        }
+
+        if let Err(e) = self.write_save() {
+            eprintln!("Error writing save file: {}", e);
+        }
+        if let Err(e) = self.write_coverage() {
+            eprintln!("Error writing coverage map: {}", e);
+        }
+        result
+    }
+
     // Lock the memory object and return byte at the given memory address.
     fn mem_get(&self, addr: u16) -> u8 {
         let mref = self.mem.lock().unwrap();
@@ -147,6 +566,7 @@ impl CPU {
         };
 
         if flag_val ^ if_unset {
+            self.step_clocks += self.inst.clocks_extra as u32;
             self.call(addr);
         }
     }
@@ -155,6 +575,8 @@ impl CPU {
     fn call(&mut self, jump_addr: u16) {
         self.push(Reg16::PC);
         self.regs.set(Reg16::PC, jump_addr);
+        self.call_depth += 1;
+        self.log_call_depth();
     }
 
     // Execute a return if given flag is set, or unset.
@@ -166,6 +588,7 @@ impl CPU {
         };
 
         if flag_val ^ if_unset {
+            self.step_clocks += self.inst.clocks_extra as u32;
             self.ret(false);
         }
     }
@@ -176,6 +599,95 @@ impl CPU {
         if enable_ir {
             self.ir_enabled = true;
         }
+        // Code that pops its return address and jumps can unbalance the count, so stop at 0.
+        self.call_depth = self.call_depth.saturating_sub(1);
+        self.log_call_depth();
+    }
+
+    fn log_call_depth(&self) {
+        if self.call_depth_log.is_some_and(|min| self.call_depth >= min) {
+            println!("Call depth {} at PC=0x{:04x}", self.call_depth, self.pc);
+        }
+    }
+
+    // The logical call nesting: CALLs, RSTs, and interrupt dispatches, less returns.
+    pub fn call_depth(&self) -> u32 {
+        self.call_depth
+    }
+
+    // Service the interrupt for the given IF bit [0, 4]: clear its request, disable interrupts,
+    // and call its vector.
+    pub fn dispatch_interrupt(&mut self, bit: u8) {
+        let pc = self.regs.get(Reg16::PC);
+        self.mem.lock().unwrap().clear_interrupt(bit);
+        self.ir_enabled = false;
+        self.call(0x40 + 8 * bit as u16);
+
+        self.interrupt_counts[bit as usize] += 1;
+        if self.int_breaks.contains(&bit) {
+            self.int_break = Some(bit);
+        }
+        if self.log_interrupts {
+            println!("Interrupt {} dispatched at PC=0x{:04x}", INTERRUPT_NAMES[bit as usize], pc);
+        }
+    }
+
+    // If interrupts are enabled, dispatch the highest priority interrupt that is both requested
+    // and enabled. Returns true if one was dispatched.
+    fn check_interrupts(&mut self) -> bool {
+        if !self.ir_enabled { return false; }
+
+        let pending = {
+            let mref = self.mem.lock().unwrap();
+            mref.get(IE_ADDR, MemClient::CPU) & mref.get(IF_ADDR, MemClient::CPU) & 0x1f
+        };
+        if pending == 0 { return false; }
+
+        self.dispatch_interrupt(pending.trailing_zeros() as u8);
+        true
+    }
+
+    // The interrupt master enable flag, set by EI and RETI and cleared by DI and dispatch.
+    pub fn ime(&self) -> bool {
+        self.ir_enabled
+    }
+
+    // True while waiting in HALT for an interrupt.
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn register_snapshot(&self) -> RegisterSnapshot {
+        RegisterSnapshot {
+            a: self.regs.get(Reg8::A),
+            b: self.regs.get(Reg8::B),
+            c: self.regs.get(Reg8::C),
+            d: self.regs.get(Reg8::D),
+            e: self.regs.get(Reg8::E),
+            h: self.regs.get(Reg8::H),
+            l: self.regs.get(Reg8::L),
+            sp: self.regs.get(Reg16::SP),
+            pc: self.regs.get(Reg16::PC),
+            flag_z: self.flag_z,
+            flag_n: self.flag_n,
+            flag_h: self.flag_h,
+            flag_cy: self.flag_cy,
+        }
+    }
+
+    // Clock cycles run since power on, including interrupt dispatches and time spent halted.
+    pub fn clocks(&self) -> u64 {
+        self.clocks
+    }
+
+    // The same, in machine cycles of 4 clocks.
+    pub fn cycles_elapsed(&self) -> u64 {
+        self.clocks / 4
+    }
+
+    // The number of times each interrupt has been dispatched, indexed by IF bit.
+    pub fn interrupt_counts(&self) -> [u64; 5] {
+        self.interrupt_counts
     }
 
     // Copy from given register into the memory address pointed to by given Reg16
@@ -194,9 +706,9 @@ impl CPU {
     // Copy value between A and (HL), then add or subtract HL.
     fn ldd_special(&mut self, is_set: bool, is_add: bool) {
         if is_set {
-            self.set_reg_ptr(Reg16::HL, Reg8::A); // LD (HL+/-), A
+            self.load8(Operand8::HlPtr, Reg8::A); // LD (HL+/-), A
         } else {
-            self.get_reg_ptr(Reg8::A, Reg16::HL); // LD A, (HL+/-)
+            self.load8(Reg8::A, Operand8::HlPtr); // LD A, (HL+/-)
         }
 
         if is_add {
@@ -218,40 +730,13 @@ impl CPU {
         }
     }
 
-    // Write the stack pointer address to memory (two bytes).
+    // Write the stack pointer address to memory (two bytes), low byte first.
     fn write_sp_to_ptr(&mut self, addr: u16) {
         let split_addr = util::split_u16(self.regs.get(Reg16::SP));
         self.mem_set(split_addr.0, addr);
         self.mem_set(split_addr.1, addr+1);
     }
 
-    // Increment/decrement for (HL) value.
-    // TODO: We need some massive overhaul to handle (HL) properly. ALU functions should be more
-    // generic. All function calls for the (HL) register should be the same as any other 8-bit reg.
-    fn hl_ptr_inc_dec(&mut self, inc: bool) {
-        let op = match inc {
-            true  => AluOp::Add(false),
-            false => AluOp::Sub(false)
-        };
-        let addr = self.regs.get(Reg16::HL);
-        let operand_a = self.mem_get(addr);
-        let alu_out = alu::alu({ alu::AluInput {
-            op: op,
-            op_a: operand_a,
-            op_b: 1,
-            flag_z: self.flag_z,
-            flag_n: self.flag_n,
-            flag_h: self.flag_h,
-            flag_cy: self.flag_cy,
-        }});
-
-        self.mem_set(alu_out.result, addr);
-        self.flag_z = alu_out.flag_z;
-        self.flag_n = alu_out.flag_n;
-        self.flag_h = alu_out.flag_h;
-        self.flag_cy = alu_out.flag_cy;
-    }
-
     // Jump to the given address if Z or CY match what we expect
     fn jump_flag(&mut self, flag: Flag, if_unset: bool, addr: u16) {
         let flag_val = match flag {
@@ -261,6 +746,7 @@ impl CPU {
         };
 
         if flag_val ^ if_unset {
+            self.step_clocks += self.inst.clocks_extra as u32;
             self.regs.set(Reg16::PC, addr);
         }
     }
@@ -274,6 +760,7 @@ impl CPU {
         };
 
         if flag_val ^ if_unset {
+            self.step_clocks += self.inst.clocks_extra as u32;
             self.jump_relative(offset);
         }
     }
@@ -291,41 +778,39 @@ impl CPU {
         self.regs.set(Reg16::PC, addr as u16);
     }
 
-    // Perform ALU op on accumulator and input register, and handle flags.
-    fn arith_op(&mut self, op: AluOp, src: Reg8) {
-        let operand_b = self.regs.get(src);
-        self.arith_imm(op, Reg8::A, operand_b);
+    // Read an 8-bit operand, from either a register or (HL).
+    fn read8(&self, src: Operand8) -> u8 {
+        match src {
+            Operand8::Reg(r) => self.regs.get(r),
+            Operand8::HlPtr  => self.mem_get(self.regs.get(Reg16::HL)),
+        }
     }
 
-    fn arith_hl_ptr(&mut self, op: AluOp) {
-        let operand_b = self.regs.get(Reg16::HL);
-        let operand_b = self.mem_get(operand_b);
-        self.arith_imm(op, Reg8::A, operand_b);
+    // Write an 8-bit operand, to either a register or (HL).
+    fn write8(&mut self, dst: Operand8, val: u8) {
+        match dst {
+            Operand8::Reg(r) => self.regs.set(r, val),
+            Operand8::HlPtr  => { let hl = self.regs.get(Reg16::HL); self.mem_set(val, hl); },
+        }
     }
 
-    fn bitwise_hl_ptr(&mut self, op: AluOp) {
-        let addr = self.regs.get(Reg16::HL);
-        let operand_a = self.mem_get(addr);
-        let alu_out = alu::alu({ alu::AluInput {
-            op: op,
-            op_a: operand_a,
-            op_b: 0,
-            flag_z: self.flag_z,
-            flag_n: self.flag_n,
-            flag_h: self.flag_h,
-            flag_cy: self.flag_cy,
-        }});
+    // Copy one 8-bit operand into another.
+    fn load8<D: Into<Operand8>, S: Into<Operand8>>(&mut self, dst: D, src: S) {
+        let val = self.read8(src.into());
+        self.write8(dst.into(), val);
+    }
 
-        self.flag_z = alu_out.flag_z;
-        self.flag_n = alu_out.flag_n;
-        self.flag_h = alu_out.flag_h;
-        self.flag_cy = alu_out.flag_cy;
-        self.mem_set(alu_out.result, addr);
+    // Perform ALU op on accumulator and input operand, and handle flags.
+    fn arith_op<S: Into<Operand8>>(&mut self, op: AluOp, src: S) {
+        let operand_b = self.read8(src.into());
+        self.arith_imm(op, Reg8::A, operand_b);
     }
 
-    // Take an immediate u8 instead of a register.
-    fn arith_imm(&mut self, op: AluOp, dst_reg: Reg8, val: u8) {
-        let operand_a = self.regs.get(dst_reg);
+    // Perform ALU op on the given operand and an immediate u8, storing the result back to it.
+    // BIT only reads its operand, so a BIT on (HL) never writes memory.
+    fn arith_imm<D: Into<Operand8>>(&mut self, op: AluOp, dst: D, val: u8) {
+        let dst = dst.into();
+        let operand_a = self.read8(dst);
         let alu_out = alu::alu({ alu::AluInput {
             op: op,
             op_a: operand_a,
@@ -341,7 +826,10 @@ impl CPU {
         self.flag_h = alu_out.flag_h;
         self.flag_cy = alu_out.flag_cy;
 
-        self.regs.set(dst_reg, alu_out.result);
+        if let AluOp::Test(_) = op {
+            return;
+        }
+        self.write8(dst, alu_out.result);
     }
 
     // Add a 16 bit register to HL
@@ -354,8 +842,6 @@ impl CPU {
             op_b: operand_b,
             flag_z: self.flag_z,
             flag_n: self.flag_n,
-            flag_h: self.flag_h,
-            flag_cy: self.flag_cy,
         }});
 
         self.flag_z = alu_out.flag_z;
@@ -366,29 +852,18 @@ impl CPU {
         self.regs.set(Reg16::HL, alu_out.result);
     }
 
-    // Add SP and immediate signed, and store to given Reg16.
+    // ADD SP,r8 and LD HL,SP+r8. Z and N are always cleared, and H and CY come from adding the
+    // operand byte to the low byte of SP as unsigned values, whatever the offset's sign.
     fn add_sp_signed(&mut self, dest: Reg16, offset: i8) {
-        let sub = offset < 0;
-        let m: i8 = if sub { -1 } else { 1 };
-        let offset_u = ((m*offset) as u8) as u16;
         let sp_val = self.regs.get(Reg16::SP);
+        let operand = offset as u8 as u16;
 
-        let alu_out = alu::alu16({ alu::AluInput16 {
-            subtract: sub,
-            op_a: sp_val,
-            op_b: offset_u,
-            flag_z: self.flag_z,
-            flag_n: self.flag_n,
-            flag_h: self.flag_h,
-            flag_cy: self.flag_cy,
-        }});
+        self.flag_z = false;
+        self.flag_n = false;
+        self.flag_h = (sp_val & 0x0f) + (operand & 0x0f) > 0x0f;
+        self.flag_cy = (sp_val & 0xff) + operand > 0xff;
 
-        self.flag_z = alu_out.flag_z;
-        self.flag_n = alu_out.flag_n;
-        self.flag_h = alu_out.flag_h;
-        self.flag_cy = alu_out.flag_cy;
-
-        self.regs.set(dest, alu_out.result);
+        self.regs.set(dest, sp_val.wrapping_add(offset as i16 as u16));
     }
 
     // We modify a local copy of each register value, then sync them using this function after the
@@ -442,32 +917,35 @@ impl CPU {
         }
     }
 
+    // Adjust A back to packed BCD after an addition or subtraction, using the N, H, and CY flags that
+    // operation left. After an addition, CY is set if the BCD result carried past 99.
     fn decimal_adjust(&mut self) {
-        let lo = self.regs.get(Reg8::A);
-        let hi = lo.wrapping_shl(4);
-        let lo = lo & 0xF;
+        let a = self.regs.get(Reg8::A);
         let mut adjust = 0;
-        if !self.flag_n {
-            if self.flag_cy || hi > 0x9 || lo > 0x9 {
-                adjust += 0x60;
+        let mut carry = self.flag_cy;
+        let result = if !self.flag_n {
+            if self.flag_cy || a > 0x99 {
+                adjust |= 0x60;
+                carry = true;
             }
-            if self.flag_h || lo > 0x9 {
-                adjust += 0x6;
+            if self.flag_h || (a & 0xf) > 0x9 {
+                adjust |= 0x06;
             }
+            a.wrapping_add(adjust)
         } else {
             if self.flag_cy {
-                if self.flag_h {
-                    adjust += 0x9a;
-                } else {
-                    adjust += 0xa0;
-                }
-            } else if self.flag_h {
-                adjust += 0xfa;
+                adjust |= 0x60;
             }
-        }
+            if self.flag_h {
+                adjust |= 0x06;
+            }
+            a.wrapping_sub(adjust)
+        };
 
-        // TODO: arith_imm modifies flags, but should this instruction be doing that? look up
-        self.arith_imm(AluOp::Add(false), Reg8::A, adjust);
+        self.regs.set(Reg8::A, result);
+        self.flag_z = result == 0;
+        self.flag_h = false;
+        self.flag_cy = carry;
     }
 
     // Toggle the CY flag, used for CCF instruction
@@ -475,37 +953,253 @@ impl CPU {
         self.flag_cy = !self.flag_cy;
     }
 
-    // For HALT, just exit the program for now. TODO: Add accurate HALT emulation here.
+    // HALT stops executing instructions until an interrupt is both requested and enabled in IE.
+    // The wake doesn't depend on IME, which only decides whether the interrupt is then serviced.
+    // With an enabled interrupt already requested, HALT doesn't halt at all. With IME set it takes
+    // its usual 4 clocks, and the interrupt is dispatched before the next instruction with no
+    // wake-up delay. With IME clear, the HALT bug hits: PC isn't incremented after the next opcode
+    // fetch, so the byte after HALT is read twice.
     fn halt(&mut self) {
-        println!("Encountered HALT instruction, exiting!");
-        self.quit = true;
+        let pending = {
+            let mref = self.mem.lock().unwrap();
+            mref.get(IE_ADDR, MemClient::CPU) & mref.get(IF_ADDR, MemClient::CPU) & 0x1f
+        };
+        self.halted = pending == 0;
+        self.halt_bug = pending != 0 && !self.ir_enabled;
     }
 
-    fn stop(&mut self) {
-        println!("Encountered STOP instruction, exiting!");
-        self.quit = true;
+    // STOP is encoded as 0x10 0x00, and the table already advances PC past both bytes. Hardware
+    // ignores the second byte, so a nonzero one (a corrupted STOP) behaves the same way.
+    fn stop(&mut self, second_byte: u8) {
+        if second_byte != 0 && self.verbose {
+            println!("Corrupted STOP at 0x{:04x}, second byte is 0x{:02x}", self.pc, second_byte);
+        }
+
+        // Entering STOP resets the divider.
+        self.mem_set(0, DIV_ADDR);
+
+        // With a speed switch armed in KEY1, STOP switches speed instead. The CPU runs nothing
+        // during the stall, so it's charged to this instruction, which has the PPU, timer, and
+        // clock run through it. Then the CPU carries on without stopping.
+        if self.mem.lock().unwrap().speed_switch() {
+            self.step_clocks += SPEED_SWITCH_CLOCKS;
+            return;
+        }
+        self.stopped = true;
     }
 
-    // Run the LCD, then process the current instruction.
-    // TODO: This should eventually be cycle-accurate
-    pub fn tick(&mut self) -> bool {
-        self.ppu.tick();
+    // Add a Game Genie or GameShark code. Cheats last until a different ROM is loaded.
+    pub fn add_cheat(&mut self, code: &str) -> Result<(), String> {
+        match cheats::parse(code)? {
+            Cheat::GameGenie { addr, value, compare } => {
+                self.mem.lock().unwrap().add_rom_cheat(addr, value, compare);
+            },
+            Cheat::GameShark { addr, value } => {
+                self.ram_cheats.push((addr, value));
+                self.cheat_frame = self.ppu.frame_count();
+                self.mem_set(value, addr);
+            },
+        }
+        Ok(())
+    }
+
+    // GameShark codes hold their values by rewriting RAM once a frame, as VBlank starts.
+    fn apply_ram_cheats(&mut self) {
+        let frame = self.ppu.frame_count();
+        if self.ram_cheats.is_empty() || frame == self.cheat_frame { return; }
+        self.cheat_frame = frame;
 
+        let mut mref = self.mem.lock().unwrap();
+        for &(addr, value) in &self.ram_cheats {
+            mref.set(value, addr, MemClient::CPU);
+        }
+    }
+
+    // Process the current instruction, then run the LCD and the rest of the machine for the
+    // cycles it took. Returns the machine cycles taken, or None if emulation should stop.
+    pub fn tick(&mut self) -> Option<u32> {
         if !self.ppu.is_alive() {
             println!("Closed PPU window!");
-            false
-        } else {
-            self.process()
+            return None;
         }
+
+        let taken = self.process();
+        self.ppu.set_cpu_pc(self.pc);
+        for _ in 0..self.step_clocks / 4 {
+            self.ppu.tick();
+        }
+        self.apply_ram_cheats();
+        {
+            let mut mref = self.mem.lock().unwrap();
+            mref.advance_clock(self.step_clocks as u64);
+            mref.step_serial(self.step_clocks as u64);
+            mref.step_timer(self.step_clocks as u64);
+
+            // Catch any path that advances the CPU without the PPU or the machine clock, which
+            // the timers and RTC run on, or the other way around. Timing is single speed in both
+            // CGB speeds for now, so the PPU runs a machine cycle for every 4 clocks.
+            self.clocks += self.step_clocks as u64;
+            if self.check_sync {
+                assert_eq!(self.clocks, self.ppu.cycles() * 4,
+                    "CPU and PPU out of step after PC=0x{:04x}", self.pc);
+                assert_eq!(self.clocks, mref.clock_cycles(),
+                    "CPU and machine clock out of step after PC=0x{:04x}", self.pc);
+            }
+        }
+        self.autosave();
+        taken
+    }
+
+    // Tick until the predicate returns true, or until at least max_clocks clocks have elapsed.
+    // Returns true iff the predicate matched. The predicate is called while memory is locked, so
+    // it must not call back into anything that locks memory (like cpu.mem).
+    pub fn run_until<F>(&mut self, mut pred: F, max_clocks: u64) -> bool
+        where F: FnMut(&CPU, &Memory) -> bool {
+        let mut ran: u64 = 0;
+        loop {
+            let matched = {
+                let mref = self.mem.lock().unwrap();
+                pred(self, &mref)
+            };
+
+            if matched { return true; }
+            if ran >= max_clocks || self.tick().is_none() { return false; }
+            ran += self.step_clocks as u64;
+        }
+    }
+
+    // Run instructions until at least the given number of clocks have elapsed, finishing the
+    // instruction in progress. Returns the clocks actually run, so the caller can carry the
+    // overshoot into the next call. Stops early if emulation ends.
+    pub fn run_clocks(&mut self, clocks: u64) -> u64 {
+        let mut ran: u64 = 0;
+        while ran < clocks {
+            if self.tick().is_none() { break; }
+            ran += self.step_clocks as u64;
+        }
+        ran
+    }
+
+    // Run until the given number of frames have finished, counted at the start of each VBlank.
+    // Returns false if emulation ended first.
+    pub fn run_frames(&mut self, frames: u64) -> bool {
+        let target = self.ppu.frame_count() + frames;
+        while self.ppu.frame_count() < target {
+            if self.tick().is_none() { return false; }
+        }
+        true
     }
 
-    // Run the instruction at the current PC, return true if successful.
-    pub fn process(&mut self) -> bool {
-        if self.quit { return false; }
+    // Run until the next frame is complete, as VBlank starts, and return its framebuffer as from
+    // PPU::framebuffer. Returns None if a break stopped it early or emulation ended.
+    pub fn step_frame(&mut self) -> Option<Vec<u8>> {
+        let target = self.ppu.frame_count() + 1;
+        self.broke = false;
+        while self.ppu.frame_count() < target {
+            if self.tick().is_none() || self.broke { return None; }
+        }
+        Some(self.ppu.framebuffer())
+    }
+
+    // Run this CPU and another a frame at a time, comparing registers, clock counts, memory, and
+    // the framebuffer after each. Both must be set up with the same ROM and inputs. Returns a
+    // description of the first divergence, to check a refactor against the old code path.
+    pub fn run_lockstep(&mut self, other: &mut CPU, frames: u64) -> Result<(), String> {
+        for frame in 1..=frames {
+            let (ours, theirs) = match (self.step_frame(), other.step_frame()) {
+                (Some(ours), Some(theirs)) => (ours, theirs),
+                _ => return Err(format!("Frame {}: emulation stopped early", frame)),
+            };
+
+            let diffs = self.register_snapshot().diff(&other.register_snapshot());
+            if !diffs.is_empty() {
+                return Err(format!("Frame {}: registers differ: {}", frame, diffs.join(", ")));
+            }
+            if self.clocks != other.clocks {
+                return Err(format!("Frame {}: clock counts differ, {} and {}", frame, self.clocks, other.clocks));
+            }
+
+            let ours_mem = self.mem.lock().unwrap().read_range(0x0000, 0x10000);
+            let theirs_mem = other.mem.lock().unwrap().read_range(0x0000, 0x10000);
+            if let Some(addr) = (0..ours_mem.len()).find(|&a| ours_mem[a] != theirs_mem[a]) {
+                return Err(format!("Frame {}: memory differs at 0x{:04x}, 0x{:02x} and 0x{:02x}, PC=0x{:04x}",
+                    frame, addr, ours_mem[addr], theirs_mem[addr], self.pc));
+            }
+
+            if ours != theirs {
+                let hash = |pixels: &[u8]| {
+                    let mut hasher = DefaultHasher::new();
+                    pixels.hash(&mut hasher);
+                    hasher.finish()
+                };
+                // Framebuffer rows are 160 pixels of 3 bytes.
+                let row = (0..ours.len()).find(|&i| ours[i] != theirs[i]).unwrap_or(0) / (160 * 3);
+                return Err(format!("Frame {}: framebuffers differ from row {}, hashes {:016x} and {:016x}",
+                    frame, row, hash(&ours), hash(&theirs)));
+            }
+        }
+        Ok(())
+    }
+
+    // Tick once and describe what ran. Returns None if emulation ended.
+    pub fn step_described(&mut self) -> Option<StepInfo> {
+        let pc_before = self.regs.get(Reg16::PC);
+        let counts = self.interrupt_counts;
+        self.tick()?;
+
+        // self.pc is the address the instruction was fetched from, after any dispatch. It's
+        // disassembled after running, so code that rewrites itself shows the new bytes.
+        let interrupt = (0..5u8).find(|&bit| self.interrupt_counts[bit as usize] != counts[bit as usize]);
+        Some(StepInfo {
+            mnemonic: self.disassemble(self.pc).0,
+            pc_before,
+            pc_after: self.regs.get(Reg16::PC),
+            clocks: self.step_clocks,
+            interrupt,
+        })
+    }
+
+    // Run the instruction at the current PC. Returns the machine cycles taken, including any
+    // interrupt dispatch and a conditional branch being taken, or None if emulation should stop.
+    pub fn process(&mut self) -> Option<u32> {
+        // Waiting in HALT or STOP still takes a machine cycle.
+        self.step_clocks = 4;
+        if self.quit { return None; }
+
+        // Stay stopped until a button press requests the joypad interrupt.
+        if self.stopped {
+            if self.mem_get(IF_ADDR) & 0x10 == 0 { return Some(1); }
+            self.stopped = false;
+        }
+
+        // Stay halted until an enabled interrupt is requested. With IME clear, execution just
+        // resumes after the HALT, leaving the request pending.
+        if self.halted {
+            let pending = {
+                let mref = self.mem.lock().unwrap();
+                mref.get(IE_ADDR, MemClient::CPU) & mref.get(IF_ADDR, MemClient::CPU) & 0x1f
+            };
+            if pending == 0 { return Some(1); }
+            self.halted = false;
+        }
+
+        // Service interrupts before fetching the next opcode. An EI from the last instruction only
+        // takes effect after this check, so the instruction following EI always runs first.
+        let dispatch_clocks = if self.check_interrupts() { 20 } else { 0 };
+        if self.ei_pending {
+            self.ei_pending = false;
+            self.ir_enabled = true;
+        }
+
         self.pc = self.regs.get(Reg16::PC);
+        self.mark_executed(self.pc);
         let opcode = self.mem_get(self.pc);
-        let _operand8  = self.mem_get(self.pc+1);
-        let _operand16 = self.parse_u16(self.pc+1);
+
+        // After the HALT bug, the bytes following the opcode are read starting from the opcode.
+        let halt_bug = std::mem::take(&mut self.halt_bug);
+        let operand_addr = if halt_bug { self.pc } else { self.pc + 1 };
+        let _operand8  = self.mem_get(operand_addr);
+        let _operand16 = self.parse_u16(operand_addr);
 
         // Adjust opcode if it's a 0xcb prefixed instruction
         let opcode = if opcode == 0xcb {
@@ -518,17 +1212,25 @@ impl CPU {
         };
 
         self.inst = lookup::get_instruction(opcode);
-        self.flagmod = lookup::get_flagmod(opcode);
-
-        // TODO: Check here to see if we need to process an interrupt
+        self.flagmod = lookup::get_flags(opcode);
+        self.step_clocks = dispatch_clocks + self.inst.clocks as u32;
 
         // Handle debugging here
+        self.rom_swapped = false;
         self.handle_debugging();
-        if self.quit { return false; }
+        if self.quit { return None; }
+
+        // A ROM or state loaded from the debugger replaces the instruction we just fetched, so no
+        // time passes for it.
+        if self.rom_swapped {
+            self.step_clocks = 0;
+            return Some(0);
+        }
 
         // Increment PC before we process the instruction. During execution the current PC will
         // represent the next instruction to process.
-        self.regs.set(Reg16::PC, self.pc + (self.inst.bytes as u16));
+        let bytes = if halt_bug { self.inst.bytes - 1 } else { self.inst.bytes };
+        self.regs.set(Reg16::PC, self.pc + (bytes as u16));
 
         match opcode {
             // [0x00, 0x3f] - Load, INC/DEC, some jumps, and other various instructions.
@@ -548,7 +1250,7 @@ impl CPU {
             0x0d => self.arith_imm(AluOp::Sub(false), Reg8::C, 1),
             0x0e => self.regs.set(Reg8::C, _operand8),
             0x0f => self.arith_imm(AluOp::RotateRight(true), Reg8::A, 0),
-            0x10 => self.stop(),
+            0x10 => self.stop(_operand8),
             0x11 => self.regs.set(Reg16::DE, _operand16),
             0x12 => self.set_reg_ptr(Reg16::DE, Reg8::A),
             0x13 => self.regs.add(Reg16::DE, 1),
@@ -584,9 +1286,9 @@ impl CPU {
             0x31 => self.regs.set(Reg16::SP, _operand16),
             0x32 => self.ldd_special(true, false),
             0x33 => self.regs.add(Reg16::SP, 1),
-            0x34 => self.hl_ptr_inc_dec(true),
-            0x35 => self.hl_ptr_inc_dec(false),
-            0x36 => {let hl = self.regs.get(Reg16::HL); self.mem_set(_operand8, hl)},
+            0x34 => self.arith_imm(AluOp::Add(false), Operand8::HlPtr, 1),
+            0x35 => self.arith_imm(AluOp::Sub(false), Operand8::HlPtr, 1),
+            0x36 => self.write8(Operand8::HlPtr, _operand8),
             0x37 => (), // Handled in the upcoming call to sync_flags
             0x38 => self.jump_relative_flag(Flag::CY, false, _operand8),
             0x39 => self.add_hl(Reg16::SP),
@@ -604,7 +1306,7 @@ impl CPU {
             0x43 => self.regs.copy(Reg8::B, Reg8::E),
             0x44 => self.regs.copy(Reg8::B, Reg8::H),
             0x45 => self.regs.copy(Reg8::B, Reg8::L),
-            0x46 => self.get_reg_ptr(Reg8::B, Reg16::HL),
+            0x46 => self.load8(Reg8::B, Operand8::HlPtr),
             0x47 => self.regs.copy(Reg8::B, Reg8::A),
             0x48 => self.regs.copy(Reg8::C, Reg8::B),
             0x49 => self.regs.copy(Reg8::C, Reg8::C),
@@ -612,7 +1314,7 @@ impl CPU {
             0x4b => self.regs.copy(Reg8::C, Reg8::E),
             0x4c => self.regs.copy(Reg8::C, Reg8::H),
             0x4d => self.regs.copy(Reg8::C, Reg8::L),
-            0x4e => self.get_reg_ptr(Reg8::C, Reg16::HL),
+            0x4e => self.load8(Reg8::C, Operand8::HlPtr),
             0x4f => self.regs.copy(Reg8::C, Reg8::A),
             0x50 => self.regs.copy(Reg8::D, Reg8::B),
             0x51 => self.regs.copy(Reg8::D, Reg8::C),
@@ -620,7 +1322,7 @@ impl CPU {
             0x53 => self.regs.copy(Reg8::D, Reg8::E),
             0x54 => self.regs.copy(Reg8::D, Reg8::H),
             0x55 => self.regs.copy(Reg8::D, Reg8::L),
-            0x56 => self.get_reg_ptr(Reg8::D, Reg16::HL),
+            0x56 => self.load8(Reg8::D, Operand8::HlPtr),
             0x57 => self.regs.copy(Reg8::D, Reg8::A),
             0x58 => self.regs.copy(Reg8::E, Reg8::B),
             0x59 => self.regs.copy(Reg8::E, Reg8::C),
@@ -628,7 +1330,7 @@ impl CPU {
             0x5b => self.regs.copy(Reg8::E, Reg8::E),
             0x5c => self.regs.copy(Reg8::E, Reg8::H),
             0x5d => self.regs.copy(Reg8::E, Reg8::L),
-            0x5e => self.get_reg_ptr(Reg8::E, Reg16::HL),
+            0x5e => self.load8(Reg8::E, Operand8::HlPtr),
             0x5f => self.regs.copy(Reg8::E, Reg8::A),
             0x60 => self.regs.copy(Reg8::H, Reg8::B),
             0x61 => self.regs.copy(Reg8::H, Reg8::C),
@@ -636,7 +1338,7 @@ impl CPU {
             0x63 => self.regs.copy(Reg8::H, Reg8::E),
             0x64 => self.regs.copy(Reg8::H, Reg8::H),
             0x65 => self.regs.copy(Reg8::H, Reg8::L),
-            0x66 => self.get_reg_ptr(Reg8::H, Reg16::HL),
+            0x66 => self.load8(Reg8::H, Operand8::HlPtr),
             0x67 => self.regs.copy(Reg8::H, Reg8::A),
             0x68 => self.regs.copy(Reg8::L, Reg8::B),
             0x69 => self.regs.copy(Reg8::L, Reg8::C),
@@ -644,23 +1346,23 @@ impl CPU {
             0x6b => self.regs.copy(Reg8::L, Reg8::E),
             0x6c => self.regs.copy(Reg8::L, Reg8::H),
             0x6d => self.regs.copy(Reg8::L, Reg8::L),
-            0x6e => self.get_reg_ptr(Reg8::L, Reg16::HL),
+            0x6e => self.load8(Reg8::L, Operand8::HlPtr),
             0x6f => self.regs.copy(Reg8::L, Reg8::A),
-            0x70 => self.set_reg_ptr(Reg16::HL, Reg8::B),
-            0x71 => self.set_reg_ptr(Reg16::HL, Reg8::C),
-            0x72 => self.set_reg_ptr(Reg16::HL, Reg8::D),
-            0x73 => self.set_reg_ptr(Reg16::HL, Reg8::E),
-            0x74 => self.set_reg_ptr(Reg16::HL, Reg8::H),
-            0x75 => self.set_reg_ptr(Reg16::HL, Reg8::L),
+            0x70 => self.load8(Operand8::HlPtr, Reg8::B),
+            0x71 => self.load8(Operand8::HlPtr, Reg8::C),
+            0x72 => self.load8(Operand8::HlPtr, Reg8::D),
+            0x73 => self.load8(Operand8::HlPtr, Reg8::E),
+            0x74 => self.load8(Operand8::HlPtr, Reg8::H),
+            0x75 => self.load8(Operand8::HlPtr, Reg8::L),
             0x76 => self.halt(),
-            0x77 => self.set_reg_ptr(Reg16::HL, Reg8::A),
+            0x77 => self.load8(Operand8::HlPtr, Reg8::A),
             0x78 => self.regs.copy(Reg8::A, Reg8::B),
             0x79 => self.regs.copy(Reg8::A, Reg8::C),
             0x7a => self.regs.copy(Reg8::A, Reg8::D),
             0x7b => self.regs.copy(Reg8::A, Reg8::E),
             0x7c => self.regs.copy(Reg8::A, Reg8::H),
             0x7d => self.regs.copy(Reg8::A, Reg8::L),
-            0x7e => self.get_reg_ptr(Reg8::A, Reg16::HL),
+            0x7e => self.load8(Reg8::A, Operand8::HlPtr),
             0x7f => self.regs.copy(Reg8::A, Reg8::A),
 
             // [0x80, 0xbf] - Arithmetic operations
@@ -670,7 +1372,7 @@ impl CPU {
             0x83 => self.arith_op(AluOp::Add(false), Reg8::E),
             0x84 => self.arith_op(AluOp::Add(false), Reg8::H),
             0x85 => self.arith_op(AluOp::Add(false), Reg8::L),
-            0x86 => self.arith_hl_ptr(AluOp::Add(false)),
+            0x86 => self.arith_op(AluOp::Add(false), Operand8::HlPtr),
             0x87 => self.arith_op(AluOp::Add(false), Reg8::A),
             0x88 => self.arith_op(AluOp::Add(true), Reg8::B),
             0x89 => self.arith_op(AluOp::Add(true), Reg8::C),
@@ -678,7 +1380,7 @@ impl CPU {
             0x8b => self.arith_op(AluOp::Add(true), Reg8::E),
             0x8c => self.arith_op(AluOp::Add(true), Reg8::H),
             0x8d => self.arith_op(AluOp::Add(true), Reg8::L),
-            0x8e => self.arith_hl_ptr(AluOp::Add(true)),
+            0x8e => self.arith_op(AluOp::Add(true), Operand8::HlPtr),
             0x8f => self.arith_op(AluOp::Add(true), Reg8::A),
             0x90 => self.arith_op(AluOp::Sub(false), Reg8::B),
             0x91 => self.arith_op(AluOp::Sub(false), Reg8::C),
@@ -686,7 +1388,7 @@ impl CPU {
             0x93 => self.arith_op(AluOp::Sub(false), Reg8::E),
             0x94 => self.arith_op(AluOp::Sub(false), Reg8::H),
             0x95 => self.arith_op(AluOp::Sub(false), Reg8::L),
-            0x96 => self.arith_hl_ptr(AluOp::Sub(false)),
+            0x96 => self.arith_op(AluOp::Sub(false), Operand8::HlPtr),
             0x97 => self.arith_op(AluOp::Sub(false), Reg8::A),
             0x98 => self.arith_op(AluOp::Sub(true), Reg8::B),
             0x99 => self.arith_op(AluOp::Sub(true), Reg8::C),
@@ -694,7 +1396,7 @@ impl CPU {
             0x9b => self.arith_op(AluOp::Sub(true), Reg8::E),
             0x9c => self.arith_op(AluOp::Sub(true), Reg8::H),
             0x9d => self.arith_op(AluOp::Sub(true), Reg8::L),
-            0x9e => self.arith_hl_ptr(AluOp::Sub(true)),
+            0x9e => self.arith_op(AluOp::Sub(true), Operand8::HlPtr),
             0x9f => self.arith_op(AluOp::Sub(true), Reg8::A),
             0xa0 => self.arith_op(AluOp::And, Reg8::B),
             0xa1 => self.arith_op(AluOp::And, Reg8::C),
@@ -702,7 +1404,7 @@ impl CPU {
             0xa3 => self.arith_op(AluOp::And, Reg8::E),
             0xa4 => self.arith_op(AluOp::And, Reg8::H),
             0xa5 => self.arith_op(AluOp::And, Reg8::L),
-            0xa6 => self.arith_hl_ptr(AluOp::And),
+            0xa6 => self.arith_op(AluOp::And, Operand8::HlPtr),
             0xa7 => self.arith_op(AluOp::And, Reg8::A),
             0xa8 => self.arith_op(AluOp::Xor, Reg8::B),
             0xa9 => self.arith_op(AluOp::Xor, Reg8::C),
@@ -710,7 +1412,7 @@ impl CPU {
             0xab => self.arith_op(AluOp::Xor, Reg8::E),
             0xac => self.arith_op(AluOp::Xor, Reg8::H),
             0xad => self.arith_op(AluOp::Xor, Reg8::L),
-            0xae => self.arith_hl_ptr(AluOp::Xor),
+            0xae => self.arith_op(AluOp::Xor, Operand8::HlPtr),
             0xaf => self.arith_op(AluOp::Xor, Reg8::A),
             0xb0 => self.arith_op(AluOp::Or, Reg8::B),
             0xb1 => self.arith_op(AluOp::Or, Reg8::C),
@@ -718,7 +1420,7 @@ impl CPU {
             0xb3 => self.arith_op(AluOp::Or, Reg8::E),
             0xb4 => self.arith_op(AluOp::Or, Reg8::H),
             0xb5 => self.arith_op(AluOp::Or, Reg8::L),
-            0xb6 => self.arith_hl_ptr(AluOp::Or),
+            0xb6 => self.arith_op(AluOp::Or, Operand8::HlPtr),
             0xb7 => self.arith_op(AluOp::Or, Reg8::A),
             0xb8 => self.arith_op(AluOp::Comp, Reg8::B),
             0xb9 => self.arith_op(AluOp::Comp, Reg8::C),
@@ -726,7 +1428,7 @@ impl CPU {
             0xbb => self.arith_op(AluOp::Comp, Reg8::E),
             0xbc => self.arith_op(AluOp::Comp, Reg8::H),
             0xbd => self.arith_op(AluOp::Comp, Reg8::L),
-            0xbe => self.arith_hl_ptr(AluOp::Comp),
+            0xbe => self.arith_op(AluOp::Comp, Operand8::HlPtr),
             0xbf => self.arith_op(AluOp::Comp, Reg8::A),
 
             // [0xc0, 0xff] - Flow control, push/pop/call/ret, and other various instructions.
@@ -764,7 +1466,7 @@ impl CPU {
             0xdf => self.call(0x18),
             0xe0 => {let a = self.regs.get(Reg8::A); self.mem_set(a, 0xff00 + (_operand8 as u16))},
             0xe1 => self.pop(Reg16::HL),
-            0xe2 => self.ld_fast_page(true),
+            0xe2 => self.ld_fast_page(false),
             0xe3 => panic!("Received invalid instruction UNKNOWN_{:02X}", opcode),
             0xe4 => panic!("Received invalid instruction UNKNOWN_{:02X}", opcode),
             0xe5 => self.push(Reg16::HL),
@@ -780,8 +1482,8 @@ impl CPU {
             0xef => self.call(0x28),
             0xf0 => {let val = self.mem_get(0xff00 + (_operand8 as u16)); self.regs.set(Reg8::A, val)},
             0xf1 => self.pop(Reg16::AF),
-            0xf2 => self.ld_fast_page(false),
-            0xf3 => self.ir_enabled = false,
+            0xf2 => self.ld_fast_page(true),
+            0xf3 => { self.ir_enabled = false; self.ei_pending = false; },
             0xf4 => panic!("Received invalid instruction UNKNOWN_{:02X}", opcode),
             0xf5 => self.push(Reg16::AF),
             0xf6 => self.arith_imm(AluOp::Or, Reg8::A, _operand8),
@@ -789,7 +1491,7 @@ impl CPU {
             0xf8 => self.add_sp_signed(Reg16::HL, _operand8 as i8),
             0xf9 => self.regs.copy(Reg16::SP, Reg16::HL),
             0xfa => {let val = self.mem_get(_operand16); self.regs.set(Reg8::A, val)},
-            0xfb => self.ir_enabled = true,
+            0xfb => self.ei_pending = true,
             0xfc => panic!("Received invalid instruction UNKNOWN_{:02X}", opcode),
             0xfd => panic!("Received invalid instruction UNKNOWN_{:02X}", opcode),
             0xfe => self.arith_imm(AluOp::Comp, Reg8::A, _operand8),
@@ -802,7 +1504,7 @@ impl CPU {
             0xcb03 => self.arith_imm(AluOp::RotateLeft(true), Reg8::E, 0),
             0xcb04 => self.arith_imm(AluOp::RotateLeft(true), Reg8::H, 0),
             0xcb05 => self.arith_imm(AluOp::RotateLeft(true), Reg8::L, 0),
-            0xcb06 => self.bitwise_hl_ptr(AluOp::RotateLeft(true)),
+            0xcb06 => self.arith_imm(AluOp::RotateLeft(true), Operand8::HlPtr, 0),
             0xcb07 => self.arith_imm(AluOp::RotateLeft(true), Reg8::A, 0),
             0xcb08 => self.arith_imm(AluOp::RotateRight(true), Reg8::B, 0),
             0xcb09 => self.arith_imm(AluOp::RotateRight(true), Reg8::C, 0),
@@ -810,7 +1512,7 @@ impl CPU {
             0xcb0b => self.arith_imm(AluOp::RotateRight(true), Reg8::E, 0),
             0xcb0c => self.arith_imm(AluOp::RotateRight(true), Reg8::H, 0),
             0xcb0d => self.arith_imm(AluOp::RotateRight(true), Reg8::L, 0),
-            0xcb0e => self.bitwise_hl_ptr(AluOp::RotateRight(true)),
+            0xcb0e => self.arith_imm(AluOp::RotateRight(true), Operand8::HlPtr, 0),
             0xcb0f => self.arith_imm(AluOp::RotateRight(true), Reg8::A, 0),
             0xcb10 => self.arith_imm(AluOp::RotateLeft(false), Reg8::B, 0),
             0xcb11 => self.arith_imm(AluOp::RotateLeft(false), Reg8::C, 0),
@@ -818,7 +1520,7 @@ impl CPU {
             0xcb13 => self.arith_imm(AluOp::RotateLeft(false), Reg8::E, 0),
             0xcb14 => self.arith_imm(AluOp::RotateLeft(false), Reg8::H, 0),
             0xcb15 => self.arith_imm(AluOp::RotateLeft(false), Reg8::L, 0),
-            0xcb16 => self.bitwise_hl_ptr(AluOp::RotateLeft(false)),
+            0xcb16 => self.arith_imm(AluOp::RotateLeft(false), Operand8::HlPtr, 0),
             0xcb17 => self.arith_imm(AluOp::RotateLeft(false), Reg8::A, 0),
             0xcb18 => self.arith_imm(AluOp::RotateRight(false), Reg8::B, 0),
             0xcb19 => self.arith_imm(AluOp::RotateRight(false), Reg8::C, 0),
@@ -826,7 +1528,7 @@ impl CPU {
             0xcb1b => self.arith_imm(AluOp::RotateRight(false), Reg8::E, 0),
             0xcb1c => self.arith_imm(AluOp::RotateRight(false), Reg8::H, 0),
             0xcb1d => self.arith_imm(AluOp::RotateRight(false), Reg8::L, 0),
-            0xcb1e => self.bitwise_hl_ptr(AluOp::RotateRight(false)),
+            0xcb1e => self.arith_imm(AluOp::RotateRight(false), Operand8::HlPtr, 0),
             0xcb1f => self.arith_imm(AluOp::RotateRight(false), Reg8::A, 0),
             0xcb20 => self.arith_imm(AluOp::ShiftLeft, Reg8::B, 0),
             0xcb21 => self.arith_imm(AluOp::ShiftLeft, Reg8::C, 0),
@@ -834,7 +1536,7 @@ impl CPU {
             0xcb23 => self.arith_imm(AluOp::ShiftLeft, Reg8::E, 0),
             0xcb24 => self.arith_imm(AluOp::ShiftLeft, Reg8::H, 0),
             0xcb25 => self.arith_imm(AluOp::ShiftLeft, Reg8::L, 0),
-            0xcb26 => self.bitwise_hl_ptr(AluOp::ShiftLeft),
+            0xcb26 => self.arith_imm(AluOp::ShiftLeft, Operand8::HlPtr, 0),
             0xcb27 => self.arith_imm(AluOp::ShiftLeft, Reg8::A, 0),
             0xcb28 => self.arith_imm(AluOp::ShiftRight(true), Reg8::B, 0),
             0xcb29 => self.arith_imm(AluOp::ShiftRight(true), Reg8::C, 0),
@@ -842,7 +1544,7 @@ impl CPU {
             0xcb2b => self.arith_imm(AluOp::ShiftRight(true), Reg8::E, 0),
             0xcb2c => self.arith_imm(AluOp::ShiftRight(true), Reg8::H, 0),
             0xcb2d => self.arith_imm(AluOp::ShiftRight(true), Reg8::L, 0),
-            0xcb2e => self.bitwise_hl_ptr(AluOp::ShiftRight(true)),
+            0xcb2e => self.arith_imm(AluOp::ShiftRight(true), Operand8::HlPtr, 0),
             0xcb2f => self.arith_imm(AluOp::ShiftRight(true), Reg8::A, 0),
             0xcb30 => self.arith_imm(AluOp::Swap, Reg8::B, 0),
             0xcb31 => self.arith_imm(AluOp::Swap, Reg8::C, 0),
@@ -850,7 +1552,7 @@ impl CPU {
             0xcb33 => self.arith_imm(AluOp::Swap, Reg8::E, 0),
             0xcb34 => self.arith_imm(AluOp::Swap, Reg8::H, 0),
             0xcb35 => self.arith_imm(AluOp::Swap, Reg8::L, 0),
-            0xcb36 => self.bitwise_hl_ptr(AluOp::Swap),
+            0xcb36 => self.arith_imm(AluOp::Swap, Operand8::HlPtr, 0),
             0xcb37 => self.arith_imm(AluOp::Swap, Reg8::A, 0),
             0xcb38 => self.arith_imm(AluOp::ShiftRight(false), Reg8::B, 0),
             0xcb39 => self.arith_imm(AluOp::ShiftRight(false), Reg8::C, 0),
@@ -858,7 +1560,7 @@ impl CPU {
             0xcb3b => self.arith_imm(AluOp::ShiftRight(false), Reg8::E, 0),
             0xcb3c => self.arith_imm(AluOp::ShiftRight(false), Reg8::H, 0),
             0xcb3d => self.arith_imm(AluOp::ShiftRight(false), Reg8::L, 0),
-            0xcb3e => self.bitwise_hl_ptr(AluOp::ShiftRight(false)),
+            0xcb3e => self.arith_imm(AluOp::ShiftRight(false), Operand8::HlPtr, 0),
             0xcb3f => self.arith_imm(AluOp::ShiftRight(false), Reg8::A, 0),
 
             // [0xcb40, 0xcb7f] - Bit test, push value to Z flag
@@ -868,7 +1570,7 @@ impl CPU {
             0xcb43 => self.arith_imm(AluOp::Test(0), Reg8::E, 0),
             0xcb44 => self.arith_imm(AluOp::Test(0), Reg8::H, 0),
             0xcb45 => self.arith_imm(AluOp::Test(0), Reg8::L, 0),
-            0xcb46 => self.bitwise_hl_ptr(AluOp::Test(0)),
+            0xcb46 => self.arith_imm(AluOp::Test(0), Operand8::HlPtr, 0),
             0xcb47 => self.arith_imm(AluOp::Test(0), Reg8::A, 0),
             0xcb48 => self.arith_imm(AluOp::Test(1), Reg8::B, 0),
             0xcb49 => self.arith_imm(AluOp::Test(1), Reg8::C, 0),
@@ -876,7 +1578,7 @@ impl CPU {
             0xcb4b => self.arith_imm(AluOp::Test(1), Reg8::E, 0),
             0xcb4c => self.arith_imm(AluOp::Test(1), Reg8::H, 0),
             0xcb4d => self.arith_imm(AluOp::Test(1), Reg8::L, 0),
-            0xcb4e => self.bitwise_hl_ptr(AluOp::Test(1)),
+            0xcb4e => self.arith_imm(AluOp::Test(1), Operand8::HlPtr, 0),
             0xcb4f => self.arith_imm(AluOp::Test(1), Reg8::A, 0),
             0xcb50 => self.arith_imm(AluOp::Test(2), Reg8::B, 0),
             0xcb51 => self.arith_imm(AluOp::Test(2), Reg8::C, 0),
@@ -884,7 +1586,7 @@ impl CPU {
             0xcb53 => self.arith_imm(AluOp::Test(2), Reg8::E, 0),
             0xcb54 => self.arith_imm(AluOp::Test(2), Reg8::H, 0),
             0xcb55 => self.arith_imm(AluOp::Test(2), Reg8::L, 0),
-            0xcb56 => self.bitwise_hl_ptr(AluOp::Test(2)),
+            0xcb56 => self.arith_imm(AluOp::Test(2), Operand8::HlPtr, 0),
             0xcb57 => self.arith_imm(AluOp::Test(2), Reg8::A, 0),
             0xcb58 => self.arith_imm(AluOp::Test(3), Reg8::B, 0),
             0xcb59 => self.arith_imm(AluOp::Test(3), Reg8::C, 0),
@@ -892,7 +1594,7 @@ impl CPU {
             0xcb5b => self.arith_imm(AluOp::Test(3), Reg8::E, 0),
             0xcb5c => self.arith_imm(AluOp::Test(3), Reg8::H, 0),
             0xcb5d => self.arith_imm(AluOp::Test(3), Reg8::L, 0),
-            0xcb5e => self.bitwise_hl_ptr(AluOp::Test(3)),
+            0xcb5e => self.arith_imm(AluOp::Test(3), Operand8::HlPtr, 0),
             0xcb5f => self.arith_imm(AluOp::Test(3), Reg8::A, 0),
             0xcb60 => self.arith_imm(AluOp::Test(4), Reg8::B, 0),
             0xcb61 => self.arith_imm(AluOp::Test(4), Reg8::C, 0),
@@ -900,7 +1602,7 @@ impl CPU {
             0xcb63 => self.arith_imm(AluOp::Test(4), Reg8::E, 0),
             0xcb64 => self.arith_imm(AluOp::Test(4), Reg8::H, 0),
             0xcb65 => self.arith_imm(AluOp::Test(4), Reg8::L, 0),
-            0xcb66 => self.bitwise_hl_ptr(AluOp::Test(4)),
+            0xcb66 => self.arith_imm(AluOp::Test(4), Operand8::HlPtr, 0),
             0xcb67 => self.arith_imm(AluOp::Test(4), Reg8::A, 0),
             0xcb68 => self.arith_imm(AluOp::Test(5), Reg8::B, 0),
             0xcb69 => self.arith_imm(AluOp::Test(5), Reg8::C, 0),
@@ -908,7 +1610,7 @@ impl CPU {
             0xcb6b => self.arith_imm(AluOp::Test(5), Reg8::E, 0),
             0xcb6c => self.arith_imm(AluOp::Test(5), Reg8::H, 0),
             0xcb6d => self.arith_imm(AluOp::Test(5), Reg8::L, 0),
-            0xcb6e => self.bitwise_hl_ptr(AluOp::Test(5)),
+            0xcb6e => self.arith_imm(AluOp::Test(5), Operand8::HlPtr, 0),
             0xcb6f => self.arith_imm(AluOp::Test(5), Reg8::A, 0),
             0xcb70 => self.arith_imm(AluOp::Test(6), Reg8::B, 0),
             0xcb71 => self.arith_imm(AluOp::Test(6), Reg8::C, 0),
@@ -916,7 +1618,7 @@ impl CPU {
             0xcb73 => self.arith_imm(AluOp::Test(6), Reg8::E, 0),
             0xcb74 => self.arith_imm(AluOp::Test(6), Reg8::H, 0),
             0xcb75 => self.arith_imm(AluOp::Test(6), Reg8::L, 0),
-            0xcb76 => self.bitwise_hl_ptr(AluOp::Test(6)),
+            0xcb76 => self.arith_imm(AluOp::Test(6), Operand8::HlPtr, 0),
             0xcb77 => self.arith_imm(AluOp::Test(6), Reg8::A, 0),
             0xcb78 => self.arith_imm(AluOp::Test(7), Reg8::B, 0),
             0xcb79 => self.arith_imm(AluOp::Test(7), Reg8::C, 0),
@@ -924,7 +1626,7 @@ impl CPU {
             0xcb7b => self.arith_imm(AluOp::Test(7), Reg8::E, 0),
             0xcb7c => self.arith_imm(AluOp::Test(7), Reg8::H, 0),
             0xcb7d => self.arith_imm(AluOp::Test(7), Reg8::L, 0),
-            0xcb7e => self.bitwise_hl_ptr(AluOp::Test(7)),
+            0xcb7e => self.arith_imm(AluOp::Test(7), Operand8::HlPtr, 0),
             0xcb7f => self.arith_imm(AluOp::Test(7), Reg8::A, 0),
 
             // [0xcb80, 0xcbb9] - Reset bit to 0
@@ -934,7 +1636,7 @@ impl CPU {
             0xcb83 => self.arith_imm(AluOp::Set(0, false), Reg8::E, 0),
             0xcb84 => self.arith_imm(AluOp::Set(0, false), Reg8::H, 0),
             0xcb85 => self.arith_imm(AluOp::Set(0, false), Reg8::L, 0),
-            0xcb86 => self.bitwise_hl_ptr(AluOp::Set(0, false)),
+            0xcb86 => self.arith_imm(AluOp::Set(0, false), Operand8::HlPtr, 0),
             0xcb87 => self.arith_imm(AluOp::Set(0, false), Reg8::A, 0),
             0xcb88 => self.arith_imm(AluOp::Set(1, false), Reg8::B, 0),
             0xcb89 => self.arith_imm(AluOp::Set(1, false), Reg8::C, 0),
@@ -942,7 +1644,7 @@ impl CPU {
             0xcb8b => self.arith_imm(AluOp::Set(1, false), Reg8::E, 0),
             0xcb8c => self.arith_imm(AluOp::Set(1, false), Reg8::H, 0),
             0xcb8d => self.arith_imm(AluOp::Set(1, false), Reg8::L, 0),
-            0xcb8e => self.bitwise_hl_ptr(AluOp::Set(1, false)),
+            0xcb8e => self.arith_imm(AluOp::Set(1, false), Operand8::HlPtr, 0),
             0xcb8f => self.arith_imm(AluOp::Set(1, false), Reg8::A, 0),
             0xcb90 => self.arith_imm(AluOp::Set(2, false), Reg8::B, 0),
             0xcb91 => self.arith_imm(AluOp::Set(2, false), Reg8::C, 0),
@@ -950,7 +1652,7 @@ impl CPU {
             0xcb93 => self.arith_imm(AluOp::Set(2, false), Reg8::E, 0),
             0xcb94 => self.arith_imm(AluOp::Set(2, false), Reg8::H, 0),
             0xcb95 => self.arith_imm(AluOp::Set(2, false), Reg8::L, 0),
-            0xcb96 => self.bitwise_hl_ptr(AluOp::Set(2, false)),
+            0xcb96 => self.arith_imm(AluOp::Set(2, false), Operand8::HlPtr, 0),
             0xcb97 => self.arith_imm(AluOp::Set(2, false), Reg8::A, 0),
             0xcb98 => self.arith_imm(AluOp::Set(3, false), Reg8::B, 0),
             0xcb99 => self.arith_imm(AluOp::Set(3, false), Reg8::C, 0),
@@ -958,7 +1660,7 @@ impl CPU {
             0xcb9b => self.arith_imm(AluOp::Set(3, false), Reg8::E, 0),
             0xcb9c => self.arith_imm(AluOp::Set(3, false), Reg8::H, 0),
             0xcb9d => self.arith_imm(AluOp::Set(3, false), Reg8::L, 0),
-            0xcb9e => self.bitwise_hl_ptr(AluOp::Set(3, false)),
+            0xcb9e => self.arith_imm(AluOp::Set(3, false), Operand8::HlPtr, 0),
             0xcb9f => self.arith_imm(AluOp::Set(3, false), Reg8::A, 0),
             0xcba0 => self.arith_imm(AluOp::Set(4, false), Reg8::B, 0),
             0xcba1 => self.arith_imm(AluOp::Set(4, false), Reg8::C, 0),
@@ -966,7 +1668,7 @@ impl CPU {
             0xcba3 => self.arith_imm(AluOp::Set(4, false), Reg8::E, 0),
             0xcba4 => self.arith_imm(AluOp::Set(4, false), Reg8::H, 0),
             0xcba5 => self.arith_imm(AluOp::Set(4, false), Reg8::L, 0),
-            0xcba6 => self.bitwise_hl_ptr(AluOp::Set(4, false)),
+            0xcba6 => self.arith_imm(AluOp::Set(4, false), Operand8::HlPtr, 0),
             0xcba7 => self.arith_imm(AluOp::Set(4, false), Reg8::A, 0),
             0xcba8 => self.arith_imm(AluOp::Set(5, false), Reg8::B, 0),
             0xcba9 => self.arith_imm(AluOp::Set(5, false), Reg8::C, 0),
@@ -974,7 +1676,7 @@ impl CPU {
             0xcbab => self.arith_imm(AluOp::Set(5, false), Reg8::E, 0),
             0xcbac => self.arith_imm(AluOp::Set(5, false), Reg8::H, 0),
             0xcbad => self.arith_imm(AluOp::Set(5, false), Reg8::L, 0),
-            0xcbae => self.bitwise_hl_ptr(AluOp::Set(5, false)),
+            0xcbae => self.arith_imm(AluOp::Set(5, false), Operand8::HlPtr, 0),
             0xcbaf => self.arith_imm(AluOp::Set(5, false), Reg8::A, 0),
             0xcbb0 => self.arith_imm(AluOp::Set(6, false), Reg8::B, 0),
             0xcbb1 => self.arith_imm(AluOp::Set(6, false), Reg8::C, 0),
@@ -982,7 +1684,7 @@ impl CPU {
             0xcbb3 => self.arith_imm(AluOp::Set(6, false), Reg8::E, 0),
             0xcbb4 => self.arith_imm(AluOp::Set(6, false), Reg8::H, 0),
             0xcbb5 => self.arith_imm(AluOp::Set(6, false), Reg8::L, 0),
-            0xcbb6 => self.bitwise_hl_ptr(AluOp::Set(6, false)),
+            0xcbb6 => self.arith_imm(AluOp::Set(6, false), Operand8::HlPtr, 0),
             0xcbb7 => self.arith_imm(AluOp::Set(6, false), Reg8::A, 0),
             0xcbb8 => self.arith_imm(AluOp::Set(7, false), Reg8::B, 0),
             0xcbb9 => self.arith_imm(AluOp::Set(7, false), Reg8::C, 0),
@@ -990,7 +1692,7 @@ impl CPU {
             0xcbbb => self.arith_imm(AluOp::Set(7, false), Reg8::E, 0),
             0xcbbc => self.arith_imm(AluOp::Set(7, false), Reg8::H, 0),
             0xcbbd => self.arith_imm(AluOp::Set(7, false), Reg8::L, 0),
-            0xcbbe => self.bitwise_hl_ptr(AluOp::Set(7, false)),
+            0xcbbe => self.arith_imm(AluOp::Set(7, false), Operand8::HlPtr, 0),
             0xcbbf => self.arith_imm(AluOp::Set(7, false), Reg8::A, 0),
 
             // [0xcbc0, 0xcbf9] - Set bit to 1
@@ -1000,7 +1702,7 @@ impl CPU {
             0xcbc3 => self.arith_imm(AluOp::Set(0, true), Reg8::E, 0),
             0xcbc4 => self.arith_imm(AluOp::Set(0, true), Reg8::H, 0),
             0xcbc5 => self.arith_imm(AluOp::Set(0, true), Reg8::L, 0),
-            0xcbc6 => self.bitwise_hl_ptr(AluOp::Set(0, true)),
+            0xcbc6 => self.arith_imm(AluOp::Set(0, true), Operand8::HlPtr, 0),
             0xcbc7 => self.arith_imm(AluOp::Set(0, true), Reg8::A, 0),
             0xcbc8 => self.arith_imm(AluOp::Set(1, true), Reg8::B, 0),
             0xcbc9 => self.arith_imm(AluOp::Set(1, true), Reg8::C, 0),
@@ -1008,7 +1710,7 @@ impl CPU {
             0xcbcb => self.arith_imm(AluOp::Set(1, true), Reg8::E, 0),
             0xcbcc => self.arith_imm(AluOp::Set(1, true), Reg8::H, 0),
             0xcbcd => self.arith_imm(AluOp::Set(1, true), Reg8::L, 0),
-            0xcbce => self.bitwise_hl_ptr(AluOp::Set(1, true)),
+            0xcbce => self.arith_imm(AluOp::Set(1, true), Operand8::HlPtr, 0),
             0xcbcf => self.arith_imm(AluOp::Set(1, true), Reg8::A, 0),
             0xcbd0 => self.arith_imm(AluOp::Set(2, true), Reg8::B, 0),
             0xcbd1 => self.arith_imm(AluOp::Set(2, true), Reg8::C, 0),
@@ -1016,7 +1718,7 @@ impl CPU {
             0xcbd3 => self.arith_imm(AluOp::Set(2, true), Reg8::E, 0),
             0xcbd4 => self.arith_imm(AluOp::Set(2, true), Reg8::H, 0),
             0xcbd5 => self.arith_imm(AluOp::Set(2, true), Reg8::L, 0),
-            0xcbd6 => self.bitwise_hl_ptr(AluOp::Set(2, true)),
+            0xcbd6 => self.arith_imm(AluOp::Set(2, true), Operand8::HlPtr, 0),
             0xcbd7 => self.arith_imm(AluOp::Set(2, true), Reg8::A, 0),
             0xcbd8 => self.arith_imm(AluOp::Set(3, true), Reg8::B, 0),
             0xcbd9 => self.arith_imm(AluOp::Set(3, true), Reg8::C, 0),
@@ -1024,7 +1726,7 @@ impl CPU {
             0xcbdb => self.arith_imm(AluOp::Set(3, true), Reg8::E, 0),
             0xcbdc => self.arith_imm(AluOp::Set(3, true), Reg8::H, 0),
             0xcbdd => self.arith_imm(AluOp::Set(3, true), Reg8::L, 0),
-            0xcbde => self.bitwise_hl_ptr(AluOp::Set(3, true)),
+            0xcbde => self.arith_imm(AluOp::Set(3, true), Operand8::HlPtr, 0),
             0xcbdf => self.arith_imm(AluOp::Set(3, true), Reg8::A, 0),
             0xcbe0 => self.arith_imm(AluOp::Set(4, true), Reg8::B, 0),
             0xcbe1 => self.arith_imm(AluOp::Set(4, true), Reg8::C, 0),
@@ -1032,7 +1734,7 @@ impl CPU {
             0xcbe3 => self.arith_imm(AluOp::Set(4, true), Reg8::E, 0),
             0xcbe4 => self.arith_imm(AluOp::Set(4, true), Reg8::H, 0),
             0xcbe5 => self.arith_imm(AluOp::Set(4, true), Reg8::L, 0),
-            0xcbe6 => self.bitwise_hl_ptr(AluOp::Set(4, true)),
+            0xcbe6 => self.arith_imm(AluOp::Set(4, true), Operand8::HlPtr, 0),
             0xcbe7 => self.arith_imm(AluOp::Set(4, true), Reg8::A, 0),
             0xcbe8 => self.arith_imm(AluOp::Set(5, true), Reg8::B, 0),
             0xcbe9 => self.arith_imm(AluOp::Set(5, true), Reg8::C, 0),
@@ -1040,7 +1742,7 @@ impl CPU {
             0xcbeb => self.arith_imm(AluOp::Set(5, true), Reg8::E, 0),
             0xcbec => self.arith_imm(AluOp::Set(5, true), Reg8::H, 0),
             0xcbed => self.arith_imm(AluOp::Set(5, true), Reg8::L, 0),
-            0xcbee => self.bitwise_hl_ptr(AluOp::Set(5, true)),
+            0xcbee => self.arith_imm(AluOp::Set(5, true), Operand8::HlPtr, 0),
             0xcbef => self.arith_imm(AluOp::Set(5, true), Reg8::A, 0),
             0xcbf0 => self.arith_imm(AluOp::Set(6, true), Reg8::B, 0),
             0xcbf1 => self.arith_imm(AluOp::Set(6, true), Reg8::C, 0),
@@ -1048,7 +1750,7 @@ impl CPU {
             0xcbf3 => self.arith_imm(AluOp::Set(6, true), Reg8::E, 0),
             0xcbf4 => self.arith_imm(AluOp::Set(6, true), Reg8::H, 0),
             0xcbf5 => self.arith_imm(AluOp::Set(6, true), Reg8::L, 0),
-            0xcbf6 => self.bitwise_hl_ptr(AluOp::Set(6, true)),
+            0xcbf6 => self.arith_imm(AluOp::Set(6, true), Operand8::HlPtr, 0),
             0xcbf7 => self.arith_imm(AluOp::Set(6, true), Reg8::A, 0),
             0xcbf8 => self.arith_imm(AluOp::Set(7, true), Reg8::B, 0),
             0xcbf9 => self.arith_imm(AluOp::Set(7, true), Reg8::C, 0),
@@ -1056,7 +1758,7 @@ impl CPU {
             0xcbfb => self.arith_imm(AluOp::Set(7, true), Reg8::E, 0),
             0xcbfc => self.arith_imm(AluOp::Set(7, true), Reg8::H, 0),
             0xcbfd => self.arith_imm(AluOp::Set(7, true), Reg8::L, 0),
-            0xcbfe => self.bitwise_hl_ptr(AluOp::Set(7, true)),
+            0xcbfe => self.arith_imm(AluOp::Set(7, true), Operand8::HlPtr, 0),
             0xcbff => self.arith_imm(AluOp::Set(7, true), Reg8::A, 0),
 
             _ => {
@@ -1069,12 +1771,33 @@ impl CPU {
         // After instruction, sync flag changes to register cache
         self.sync_flags();
 
-        !self.quit
+        // Break again once we've stepped the requested number of instructions.
+        if self.step_count > 0 {
+            self.step_count -= 1;
+            self.stepinto = self.step_count == 0;
+        }
+
+        if self.quit { None } else { Some(self.step_clocks / 4) }
     }
 
     fn handle_debugging(&mut self) {
         let mut should_break = false;
-        if self.breaks.contains(&self.pc) { should_break = true; }
+        if self.hit_breakpoint() { should_break = true; }
+        if let Some((bit, ret_addr)) = self.take_interrupt_break() {
+            println!("Break on {} interrupt, returning to 0x{:04x}", INTERRUPT_NAMES[bit as usize], ret_addr);
+            should_break = true;
+        }
+        if let Some(addr) = self.mem.lock().unwrap().take_first_sram_write() {
+            println!("Break on first SRAM write, to 0x{:04x}", addr);
+            should_break = true;
+        }
+        if let Some(n) = self.pause_at_vblank {
+            if self.ppu.frame_count() >= n {
+                println!("Paused at VBlank {}", n);
+                should_break = true;
+                self.pause_at_vblank = None;
+            }
+        }
         if self.stepover_break == Some(self.pc) || self.stepinto {
             should_break = true;
             self.stepinto = false;
@@ -1082,8 +1805,15 @@ impl CPU {
         }
 
         if should_break {
-            self.print_instruction_info(self.verbose, true);
-            self.get_breakpoint_input();
+            self.broke = true;
+            if let Some(mut callback) = self.break_callback.take() {
+                callback(self);
+                self.break_callback.get_or_insert(callback);
+            } else {
+                self.print_instruction_info(self.verbose, true);
+                #[cfg(feature = "cli")]
+                self.get_breakpoint_input();
+            }
         } else if self.verbose {
             self.print_instruction_info(true, false);
         }
@@ -1098,6 +1828,48 @@ impl CPU {
         }
     }
 
+    // Count a hit if there's a breakpoint at the current PC, and return true once it's been hit
+    // more times than its ignore count.
+    fn hit_breakpoint(&mut self) -> bool {
+        if !self.breaks.contains(&self.pc) { return false; }
+
+        let hits = self.break_hits.entry(self.pc).or_insert(0);
+        *hits += 1;
+        *hits > self.break_ignores.get(&self.pc).copied().unwrap_or(0)
+    }
+
+    // Describe each breakpoint in address order with its hit and ignore counts, then each
+    // interrupt break.
+    pub fn breakpoint_list(&self) -> Vec<String> {
+        let mut addrs: Vec<u16> = self.breaks.iter().copied().collect();
+        addrs.sort_unstable();
+        let mut bits: Vec<u8> = self.int_breaks.iter().copied().collect();
+        bits.sort_unstable();
+
+        addrs.iter().map(|addr| {
+            let hits = self.break_hits.get(addr).copied().unwrap_or(0);
+            match self.break_ignores.get(addr) {
+                Some(ignore) => format!("0x{:04x}: {} hits, ignoring the first {}", addr, hits, ignore),
+                None => format!("0x{:04x}: {} hits", addr, hits),
+            }
+        }).chain(bits.iter().map(|&bit| format!("{} interrupt", INTERRUPT_NAMES[bit as usize])))
+          .collect()
+    }
+
+    // If an interrupt we break on was just dispatched, return its IF bit and the return address
+    // it pushed to the stack.
+    fn take_interrupt_break(&mut self) -> Option<(u8, u16)> {
+        let bit = self.int_break.take()?;
+        Some((bit, self.parse_u16(self.regs.get(Reg16::SP))))
+    }
+
+    // Handle breaks with the given callback instead of the interactive prompt. The callback can
+    // inspect the CPU, and use debug_command to step or set up the next break.
+    pub fn set_break_callback<F: FnMut(&mut CPU) + 'static>(&mut self, callback: F) {
+        self.break_callback = Some(Box::new(callback));
+    }
+
+    #[cfg(feature = "cli")]
     fn print_instruction_info(&self, detailed: bool, is_break: bool) {
         let pstr = self.get_instruction_info_str(detailed);
         let mut stdout = StandardStream::stdout(ColorChoice::Always);
@@ -1108,17 +1880,54 @@ impl CPU {
         stdout.set_color(ColorSpec::new().set_fg(None)).unwrap();
     }
 
+    #[cfg(not(feature = "cli"))]
+    fn print_instruction_info(&self, detailed: bool, _is_break: bool) {
+        println!("{}", self.get_instruction_info_str(detailed));
+    }
+
     fn write_instruction_trace(&mut self) {
-        let mut pstr = self.get_instruction_info_str(true);
+        self.trace_count += 1;
+        if !self.trace_count.is_multiple_of(self.trace_every) { return; }
+
+        let mut pstr = self.get_trace_str();
         pstr.push('\n');
         match &mut self.trace_file {
             None => (),
-            Some(file) => { file.write(pstr.as_bytes()).unwrap(); }
+            Some(file) => { file.write_all(pstr.as_bytes()).unwrap(); }
+        }
+    }
+
+    fn get_trace_str(&self) -> String {
+        let pstr = self.get_instruction_info_str(true);
+        if self.trace_disasm {
+            format!("{} {}", pstr, self.disassemble(self.pc).0)
+        } else {
+            pstr
+        }
+    }
+
+    // Disassemble the instruction at the given address, returning its text and length in bytes.
+    pub fn disassemble(&self, addr: u16) -> (String, u8) {
+        let bytes = [self.mem_get(addr),
+                     self.mem_get(addr.wrapping_add(1)),
+                     self.mem_get(addr.wrapping_add(2))];
+        lookup::disassemble(&bytes, addr)
+    }
+
+    // Disassemble every instruction starting in [start, end], returning (address, text) pairs.
+    pub fn disassemble_range(&self, start: u16, end: u16) -> Vec<(u16, String)> {
+        let mut lines = Vec::new();
+        let mut addr = start as u32;
+        while addr <= end as u32 {
+            let (text, len) = self.disassemble(addr as u16);
+            lines.push((addr as u16, text));
+            addr += len.max(1) as u32;
         }
+        lines
     }
 
     fn get_instruction_info_str(&self, detailed: bool) -> String {
-        // A:01 F:Z-HC BC:0013 DE:00d8 HL:014d SP:fffe PC:0100 0x0100: 00
+        // A:01 F:Z-HC BC:0013 DE:00d8 HL:014d SP:fffe PC:0100 IME:0 0x0100: 00
         let flag_str = format!("{}{}{}{}",
                        if self.regs.get_flag(Flag::Z)  { "Z" } else { "-" },
                        if self.regs.get_flag(Flag::N)  { "N" } else { "-" },
@@ -1128,10 +1937,24 @@ impl CPU {
         let mut inst_str = String::from("");
         if !detailed {
             let argpc = self.pc + 1 as u16;
-            if self.inst.bytes == 3 {
-                inst_str += &format!("0x{:04x}", self.parse_u16(argpc));
-            } else {
-                inst_str += &format!("0x{:02x}", self.mem_get(argpc));
+            let opcode = self.inst.opcode;
+            if !self.inst.prefix_cb && (opcode == 0x18 || (opcode & 0xe7) == 0x20) {
+                // JR and JR cc: show the signed offset and the address we'd jump to.
+                let offset = self.mem_get(argpc) as i8;
+                let target = self.pc.wrapping_add(2).wrapping_add(offset as u16);
+                let mnemonic = self.inst.name.trim_end_matches("r8").trim_end();
+                return format!("0x{:04x}: {} 0x{:04x} ({})", self.pc, mnemonic, target, offset);
+            } else if !self.inst.prefix_cb && opcode == 0x10 {
+                // STOP's second byte is already part of its name.
+                return format!("0x{:04x}: {}", self.pc, self.inst.name);
+            }
+
+            // Only immediates follow the opcode. A CB instruction's second byte is its opcode, and
+            // is already covered by the name.
+            match (self.inst.prefix_cb, self.inst.bytes) {
+                (false, 2) => inst_str += &format!(" 0x{:02x}", self.mem_get(argpc)),
+                (false, 3) => inst_str += &format!(" 0x{:04x}", self.parse_u16(argpc)),
+                _ => (),
             }
         } else {
             for i in 0..self.inst.bytes {
@@ -1140,7 +1963,7 @@ impl CPU {
         }
 
         if detailed {
-            format!("A:{:02X} F:{} BC:{:04X} DE:{:04x} HL:{:04x} SP:{:04x} PC:{:04x} 0x{:04x}:{}",
+            format!("A:{:02X} F:{} BC:{:04X} DE:{:04x} HL:{:04x} SP:{:04x} PC:{:04x} IME:{} 0x{:04x}:{}",
                                self.regs.get(Reg8::A),
                                flag_str,
                                self.regs.get(Reg16::BC),
@@ -1148,11 +1971,12 @@ impl CPU {
                                self.regs.get(Reg16::HL),
                                self.regs.get(Reg16::SP),
                                self.regs.get(Reg16::PC),
+                               self.ime() as u8,
                                self.regs.get(Reg16::PC),
                                inst_str)
 
         } else {
-            format!("0x{:04x}: {} {}", self.regs.get(Reg16::PC), self.inst.name, inst_str)
+            format!("0x{:04x}: {}{}", self.regs.get(Reg16::PC), self.inst.name, inst_str)
         }
     }
 
@@ -1168,10 +1992,11 @@ impl CPU {
                  self.parse_u16(hl));
     }
 
+    #[cfg(feature = "cli")]
     fn get_breakpoint_input(&mut self) {
         let mut done = false;
         while !done {
-            print!("Press \'c\' to continue, \'s\' to step, \'p\' to print regs: ");
+            print!("Press \'c\' to continue, \'s [count]\' to step, \'p\' to print regs: ");
             let mut selection = String::new();
             io::stdout().flush().ok().expect("Problem flushing stdout.");
             io::stdin().read_line(&mut selection).expect("Could not read from stdin!");
@@ -1183,18 +2008,1468 @@ impl CPU {
                 _ => selection,
             };
 
-            // Handle selection
-            match selection.as_str() {
-                "p" => { self.print_register_info(); },
-                "s" => { self.stepinto = true; done = true; }
-                "n" => { self.stepover_break = Some(self.pc + (self.inst.bytes as u16)); done = true; }
-                "d" => {
-                    let fname = util::create_file_name("_mem_runtime");
-                    let mref = self.mem.lock().unwrap(); mref.dump_to_file(fname.as_str()).unwrap(); }
-                _   => { done = true; }
+            done = self.debug_command(&selection);
+            self.last_break_arg = Some(selection);
+        }
+    }
+
+    // Handle a single debugger command, returning true if execution should resume.
+    pub fn debug_command(&mut self, selection: &str) -> bool {
+        let mut args = selection.split_whitespace();
+        let cmd = args.next().unwrap_or("");
+        let arg = args.next();
+        let parse_addr = |a: &str| u16::from_str_radix(a.trim_start_matches("0x"), 16).ok();
+
+        match cmd {
+            "p" => { self.print_register_info(); false },
+            "s" => {
+                match arg.map(|a| a.parse::<u32>()) {
+                    None => { self.step_count = 1; true },
+                    Some(Ok(n)) if n > 0 => { self.step_count = n; true },
+                    Some(_) => { println!("Invalid step count \"{}\"", arg.unwrap()); false },
+                }
+            },
+            "n" => { self.stepover_break = Some(self.pc + (self.inst.bytes as u16)); true }
+            "v" => {
+                self.verbose = !self.verbose;
+                println!("Verbose output {}", if self.verbose { "on" } else { "off" });
+                false
+            },
+            "b" | "bc" => {
+                match (cmd, arg.and_then(parse_addr)) {
+                    ("b", Some(addr)) => {
+                        self.breaks.insert(addr);
+                        println!("Breakpoint at 0x{:04x}", addr);
+                    },
+                    (_, Some(addr)) if self.breaks.remove(&addr) => {
+                        self.break_ignores.remove(&addr);
+                        self.break_hits.remove(&addr);
+                        println!("Cleared breakpoint at 0x{:04x}", addr);
+                    },
+                    (_, Some(addr)) => println!("No breakpoint at 0x{:04x}", addr),
+                    _ => println!("Usage: {} [address]", cmd),
+                }
+                false
+            },
+            "bl" => {
+                let list = self.breakpoint_list();
+                if list.is_empty() {
+                    println!("No breakpoints");
+                }
+                for line in list {
+                    println!("{}", line);
+                }
+                false
+            },
+            "bca" => {
+                self.breaks.clear();
+                self.break_ignores.clear();
+                self.break_hits.clear();
+                self.int_breaks.clear();
+                println!("Cleared all breakpoints");
+                false
+            },
+            "df" => {
+                match (arg.and_then(parse_addr), args.next().and_then(parse_addr)) {
+                    (Some(start), Some(end)) => {
+                        for (addr, text) in self.disassemble_range(start, end) {
+                            println!("0x{:04x}: {}", addr, text);
+                        }
+                    },
+                    _ => println!("Usage: df [start address] [end address]"),
+                }
+                false
+            },
+            "cov" => {
+                match self.write_coverage() {
+                    Ok(Some(path)) => println!("Wrote coverage map to \"{}\"", path.display()),
+                    Ok(None) => println!("Coverage isn't enabled, run with --coverage"),
+                    Err(e) => println!("Error writing coverage map: {}", e),
+                }
+                false
+            },
+            "tmap" => {
+                for (row, line) in self.ppu.tile_map_text().iter().enumerate() {
+                    println!("{:2}: {}", row, line);
+                }
+                false
+            },
+            "snap" => {
+                match arg {
+                    Some(name) => {
+                        self.snapshots.insert(name.to_string(), self.register_snapshot());
+                        println!("Saved registers as \"{}\"", name);
+                    },
+                    None => println!("Usage: snap [name]"),
+                }
+                false
+            },
+            "diff" => {
+                match arg.map(|name| (name, self.snapshots.get(name))) {
+                    Some((_, Some(snap))) => {
+                        let changes = snap.diff(&self.register_snapshot());
+                        if changes.is_empty() {
+                            println!("No registers changed");
+                        }
+                        for line in changes {
+                            println!("{}", line);
+                        }
+                    },
+                    Some((name, None)) => println!("No snapshot named \"{}\"", name),
+                    None => println!("Usage: diff [name]"),
+                }
+                false
+            },
+            "load" => {
+                // Take the rest of the line, so paths can contain spaces.
+                let path = selection.trim_start()["load".len()..].trim();
+                if path.is_empty() {
+                    println!("Usage: load [ROM file]");
+                } else if let Err(e) = self.load_rom(path) {
+                    println!("Error loading ROM \"{}\": {}", path, e);
+                } else {
+                    println!("Loaded ROM \"{}\"", path);
+                }
+                false
+            },
+            "ss" | "ls" => {
+                let path = selection.trim_start()[cmd.len()..].trim();
+                let result = match (cmd, path.is_empty()) {
+                    (_, true) => { println!("Usage: {} [state file]", cmd); return false; },
+                    ("ss", _) => self.save_state(path).map(|_| "Saved"),
+                    _ => self.load_state(path).map(|_| "Loaded"),
+                };
+                match result {
+                    Ok(done) => println!("{} state \"{}\"", done, path),
+                    Err(e) => println!("Error with state \"{}\": {}", path, e),
+                }
+                false
+            },
+            "d" => {
+                let fname = util::create_file_name(self.save_dir.as_deref(), "_mem_runtime");
+                let mref = self.mem.lock().unwrap(); mref.dump_to_file(fname.as_str()).unwrap();
+                false
+            }
+            _   => true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+    use crate::joypad::Button;
+    use crate::memory::{KEY1_ADDR, STAT_ADDR};
+    use std::fs;
+
+    // Build a headless CPU with the given program loaded at the 0x100 entry point.
+    fn cpu_with_program(program: &[u8]) -> CPU {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        cpu_with_rom(rom, &RuntimeConfig::new())
+    }
+
+    fn cpu_with_rom(rom: Vec<u8>, rcfg: &RuntimeConfig) -> CPU {
+        Emulator::headless(rom, rcfg).into_cpu()
+    }
+
+    #[test]
+    fn run_until_wram_flag() {
+        let mut emu = Emulator::new(cpu_with_program(&[
+            0x06, 0x05,       // LD B,5
+            0x05,             // DEC B
+            0x20, 0xfd,       // JR NZ,-3
+            0x3e, 0x01,       // LD A,1
+            0xea, 0x00, 0xc0, // LD (0xc000),A
+            0x18, 0xfe,       // JR -2
+        ]));
+
+        let matched = emu.run_until(|_, mem| mem.get(0xc000, MemClient::CPU) == 0x01, 1000);
+        assert!(matched);
+        assert_eq!(emu.cpu().regs.get(Reg16::PC), 0x10a);
+        assert_eq!(emu.cpu().regs.get(Reg8::B), 0);
+
+        // The program now spins forever, so this should exhaust the cycle budget.
+        assert!(!emu.run_until(|cpu, _| cpu.regs.get(Reg16::PC) == 0x100, 1000));
+    }
+
+    // Run the given instruction with B and (HL) both set to val, returning the resulting
+    // operand value and flags.
+    fn run_on_operand(op: &[u8], val: u8, from_hl: bool) -> (u8, u8, u8) {
+        let mut program = vec![
+            0x21, 0x00, 0xc0, // LD HL,0xc000
+            0x36, val,        // LD (HL),val
+            0x06, val,        // LD B,val
+            0x3e, 0x35,       // LD A,0x35
+            0x37,             // SCF
+        ];
+        program.extend_from_slice(op);
+        let mut cpu = cpu_with_program(&program);
+        for _ in 0..6 {
+            cpu.process();
+        }
+
+        let operand = if from_hl {
+            cpu.mem.lock().unwrap().get(0xc000, MemClient::CPU)
+        } else {
+            cpu.regs.get(Reg8::B)
+        };
+        (operand, cpu.regs.get(Reg8::A), cpu.regs.flags_byte())
+    }
+
+    #[test]
+    fn hl_operand_matches_register() {
+        // Pairs of (register B form, (HL) form) for a sampling of 8-bit instructions.
+        let ops: [(&[u8], &[u8]); 9] = [
+            (&[0x04], &[0x34]),             // INC
+            (&[0x05], &[0x35]),             // DEC
+            (&[0x88], &[0x8e]),             // ADC A
+            (&[0x90], &[0x96]),             // SUB A
+            (&[0xb8], &[0xbe]),             // CP A
+            (&[0xcb, 0x10], &[0xcb, 0x16]), // RL
+            (&[0xcb, 0x30], &[0xcb, 0x36]), // SWAP
+            (&[0xcb, 0x78], &[0xcb, 0x7e]), // BIT 7
+            (&[0xcb, 0xd8], &[0xcb, 0xde]), // SET 3
+        ];
+
+        for &(reg_op, hl_op) in ops.iter() {
+            for &val in [0x00, 0x0f, 0x35, 0x80, 0xff].iter() {
+                assert_eq!(run_on_operand(reg_op, val, false), run_on_operand(hl_op, val, true));
             }
+        }
+    }
 
-            self.last_break_arg = Some(selection);
+    #[test]
+    fn cb_hl_shift_flags() {
+        // (operand, value, expected result, expected flags). Unlike RLCA and friends, Z follows
+        // the result, and CY is the shifted out bit even though SCF set it beforehand.
+        let cases: [(&[u8], u8, u8, u8); 4] = [
+            (&[0xcb, 0x26], 0x80, 0x00, 0x90), // SLA (HL)
+            (&[0xcb, 0x26], 0x41, 0x82, 0x00),
+            (&[0xcb, 0x3e], 0x01, 0x00, 0x90), // SRL (HL)
+            (&[0xcb, 0x3e], 0x82, 0x41, 0x00),
+        ];
+
+        for &(op, val, result, flags) in cases.iter() {
+            let (operand, _, f) = run_on_operand(op, val, true);
+            assert_eq!((operand, f), (result, flags), "op {:02x?} on 0x{:02x}", op, val);
+        }
+
+        // N and H are cleared even if they were set before.
+        let mut cpu = cpu_with_program(&[0xcb, 0x3e]);
+        cpu.regs.set(Reg16::HL, 0xc000);
+        cpu.mem_set(0x82, 0xc000);
+        cpu.regs.set_flags_byte(0x70);
+        cpu.process();
+        assert_eq!(cpu.regs.flags_byte(), 0x00);
+    }
+
+    #[test]
+    fn daa_matches_decimal_arithmetic() {
+        let bcd = |n: u32| (((n / 10) << 4) | (n % 10)) as u8;
+
+        // (opcode with B, carry in, subtract). Every pair of BCD operands should adjust to the
+        // decimal result, with CY set on a carry or borrow out of the two digits.
+        let ops = [(0x80, false, false), (0x88, false, false), (0x88, true, false),
+                   (0x90, false, true), (0x98, false, true), (0x98, true, true)];
+        for &(op, carry_in, sub) in ops.iter() {
+            // SCF, then CCF to clear it again if there's no carry in.
+            let mut cpu = cpu_with_program(&[0x37, if carry_in { 0x00 } else { 0x3f }, op, 0x27]);
+            for x in 0..100 {
+                for y in 0..100 {
+                    cpu.regs.set(Reg16::PC, 0x100);
+                    cpu.regs.set(Reg8::A, bcd(x));
+                    cpu.regs.set(Reg8::B, bcd(y));
+                    for _ in 0..4 {
+                        cpu.process();
+                    }
+
+                    let c = carry_in as u32;
+                    let (result, carry) = if sub {
+                        ((x + 200 - y - c) % 100, x < y + c)
+                    } else {
+                        ((x + y + c) % 100, x + y + c > 99)
+                    };
+                    let flags = (if result == 0 { 0x80 } else { 0 })
+                        | (if sub { 0x40 } else { 0 })
+                        | (if carry { 0x10 } else { 0 });
+                    assert_eq!((cpu.regs.get(Reg8::A), cpu.regs.flags_byte()), (bcd(result), flags),
+                               "op 0x{:02x} carry {} on {} and {}", op, carry_in, x, y);
+                }
+            }
+        }
+
+        // A binary result above 0x99 carries even without CY or H from the addition.
+        let mut cpu = cpu_with_program(&[0x3e, 0x9a, 0xb7, 0x27]); // LD A,0x9a; OR A; DAA
+        for _ in 0..3 {
+            cpu.process();
+        }
+        assert_eq!((cpu.regs.get(Reg8::A), cpu.regs.flags_byte()), (0x00, 0x90));
+    }
+
+    #[test]
+    fn vblank_dispatch_count() {
+        let mut cpu = cpu_with_program(&[0x00]);
+        cpu.mem.lock().unwrap().request_interrupt(0);
+        cpu.dispatch_interrupt(0);
+
+        assert_eq!(cpu.interrupt_counts(), [1, 0, 0, 0, 0]);
+        assert_eq!(cpu.regs.get(Reg16::PC), 0x40);
+        assert_eq!(cpu.mem.lock().unwrap().get(IF_ADDR, MemClient::CPU), 0xe0);
+        assert_eq!(cpu.parse_u16(cpu.regs.get(Reg16::SP)), 0x100);
+    }
+
+    #[test]
+    fn dispatch_highest_priority_interrupt() {
+        let mut cpu = cpu_with_program(&[0xfb, 0x00, 0x00]); // EI, NOP, NOP
+        {
+            let mut mref = cpu.mem.lock().unwrap();
+            mref.set(0x1f, IE_ADDR, MemClient::CPU);
+            mref.request_interrupt(2);
+            mref.request_interrupt(1);
+        }
+        assert!(cpu.process().is_some());
+        assert!(cpu.process().is_some());
+        assert!(cpu.ime());
+        assert!(cpu.get_trace_str().contains("IME:1"));
+
+        // LCD STAT beats Timer. IME is cleared, and the Timer request waits.
+        let start = cpu.clocks();
+        assert!(cpu.tick().is_some());
+        assert_eq!(cpu.clocks() - start, 20 + 4);
+        assert_eq!(cpu.regs.get(Reg16::PC), 0x49);
+        assert_eq!(cpu.parse_u16(cpu.regs.get(Reg16::SP)), 0x102);
+        assert_eq!(cpu.mem_get(IF_ADDR) & 0x1f, 0x04);
+        assert!(!cpu.ime());
+        assert!(cpu.get_trace_str().contains("IME:0"));
+    }
+
+    #[test]
+    fn timer_interrupt_dispatches() {
+        use crate::timer::{TAC_ADDR, TIMA_ADDR};
+
+        let mut cpu = cpu_with_program(&[
+            0x3e, 0xfc,       // LD A,0xfc
+            0xe0, 0x05,       // LDH (TIMA),A
+            0x3e, 0x05,       // LD A,0x05
+            0xe0, 0x07,       // LDH (TAC),A, 262144 Hz
+            0x3e, 0x04,       // LD A,0x04
+            0xe0, 0xff,       // LDH (IE),A
+            0xfb,             // EI
+            0x18, 0xfe,       // JR -2
+        ]);
+        assert!(cpu.run_until(|cpu, _| cpu.regs.get(Reg16::PC) == 0x51, 1000));
+        assert_eq!(cpu.interrupt_counts(), [0, 0, 1, 0, 0]);
+        let mref = cpu.mem.lock().unwrap();
+        assert_eq!(mref.get(TAC_ADDR, MemClient::CPU), 0xfd);
+        assert!(mref.get(TIMA_ADDR, MemClient::CPU) < 0x04);
+    }
+
+    #[test]
+    fn stop_advances_pc_and_waits_for_joypad() {
+        let mut cpu = cpu_with_program(&[0x10, 0x00, 0x04]); // STOP 0, INC B
+        assert!(cpu.process().is_some());
+        assert_eq!(cpu.regs.get(Reg16::PC), 0x102);
+
+        // Nothing executes until a button is pressed.
+        assert!(cpu.process().is_some());
+        assert_eq!(cpu.regs.get(Reg16::PC), 0x102);
+
+        cpu.mem.lock().unwrap().set_button(Button::Start, true);
+        assert!(cpu.process().is_some());
+        assert_eq!(cpu.regs.get(Reg16::PC), 0x103);
+        assert_eq!(cpu.regs.get(Reg8::B), 0x01);
+    }
+
+    #[test]
+    fn speed_switch_stalls_before_next_instruction() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80; // CGB
+        rom[0x100..0x107].copy_from_slice(&[
+            0x3e, 0x01, // LD A,1
+            0xe0, 0x4d, // LDH (KEY1),A
+            0x10, 0x00, // STOP 0
+            0x04,       // INC B
+        ]);
+        let mut cpu = cpu_with_rom(rom, &RuntimeConfig::new());
+
+        cpu.tick();
+        cpu.tick();
+        let (before, ppu_before) = (cpu.clocks(), cpu.ppu.cycles());
+        cpu.tick();
+        assert_eq!(cpu.clocks() - before, 4 + 2050 * 4);
+        assert_eq!(cpu.ppu.cycles() - ppu_before, 1 + 2050);
+        assert_eq!(cpu.regs.get(Reg8::B), 0x00);
+        assert_eq!(cpu.mem.lock().unwrap().get(KEY1_ADDR, MemClient::CPU), 0xfe);
+
+        // The CPU doesn't stop, the next instruction runs straight after the stall.
+        cpu.tick();
+        assert_eq!(cpu.regs.get(Reg8::B), 0x01);
+    }
+
+    #[test]
+    fn halt_wakes_and_services_with_ime() {
+        let mut cpu = cpu_with_program(&[0xfb, 0x76, 0x04]); // EI, HALT, INC B
+        cpu.mem.lock().unwrap().set(0x01, IE_ADDR, MemClient::CPU);
+        assert!(cpu.process().is_some());
+        assert!(cpu.process().is_some());
+        assert!(cpu.process().is_some());
+        assert_eq!(cpu.regs.get(Reg16::PC), 0x102);
+
+        cpu.mem.lock().unwrap().request_interrupt(0);
+        assert!(cpu.process().is_some());
+        assert_eq!(cpu.interrupt_counts(), [1, 0, 0, 0, 0]);
+        assert_eq!(cpu.regs.get(Reg16::PC), 0x41);
+        assert_eq!(cpu.regs.get(Reg8::B), 0x00);
+    }
+
+    #[test]
+    fn halt_with_pending_interrupt_dispatches_immediately() {
+        let mut cpu = cpu_with_program(&[0xfb, 0x76, 0x04]); // EI, HALT, INC B
+        {
+            let mut mref = cpu.mem.lock().unwrap();
+            mref.set(0x01, IE_ADDR, MemClient::CPU);
+            mref.request_interrupt(0);
+        }
+        let start = cpu.clocks();
+
+        // EI and HALT take 4 clocks each, and the HALT never waits.
+        assert!(cpu.tick().is_some());
+        assert!(cpu.tick().is_some());
+        assert!(!cpu.halted());
+        assert_eq!(cpu.clocks() - start, 8);
+
+        // The dispatch takes 20 clocks, then the NOP at the vector runs for 4.
+        assert!(cpu.tick().is_some());
+        assert_eq!(cpu.interrupt_counts(), [1, 0, 0, 0, 0]);
+        assert_eq!(cpu.regs.get(Reg16::PC), 0x41);
+        assert_eq!(cpu.parse_u16(cpu.regs.get(Reg16::SP)), 0x102);
+        assert_eq!(cpu.clocks() - start, 32);
+        assert_eq!(cpu.regs.get(Reg8::B), 0x00);
+    }
+
+    #[test]
+    fn halt_bug_reads_next_byte_twice() {
+        // HALT, INC B: with a request pending and IME clear, INC B runs twice.
+        let mut cpu = cpu_with_program(&[0x76, 0x04, 0x00]);
+        {
+            let mut mref = cpu.mem.lock().unwrap();
+            mref.set(0x01, IE_ADDR, MemClient::CPU);
+            mref.request_interrupt(0);
+        }
+        assert!(cpu.process().is_some());
+        assert!(!cpu.halted());
+        assert!(cpu.process().is_some());
+        assert_eq!(cpu.regs.get(Reg16::PC), 0x101);
+        assert!(cpu.process().is_some());
+        assert_eq!(cpu.regs.get(Reg16::PC), 0x102);
+        assert_eq!(cpu.regs.get(Reg8::B), 0x02);
+
+        // HALT, LD A,0x14: the opcode doubles as the operand, then 0x14 runs as INC D.
+        let mut cpu = cpu_with_program(&[0x76, 0x3e, 0x14, 0x00]);
+        {
+            let mut mref = cpu.mem.lock().unwrap();
+            mref.set(0x01, IE_ADDR, MemClient::CPU);
+            mref.request_interrupt(0);
+        }
+        for _ in 0..3 {
+            assert!(cpu.process().is_some());
+        }
+        assert_eq!(cpu.regs.get(Reg8::A), 0x3e);
+        assert_eq!(cpu.regs.get(Reg8::D), 0x01);
+        assert_eq!(cpu.regs.get(Reg16::PC), 0x103);
+    }
+
+    #[test]
+    fn halt_wakes_without_service_when_ime_clear() {
+        let mut cpu = cpu_with_program(&[0x76, 0x04]); // HALT, INC B
+        cpu.mem.lock().unwrap().set(0x01, IE_ADDR, MemClient::CPU);
+        assert!(cpu.process().is_some());
+        assert!(cpu.process().is_some());
+        assert_eq!(cpu.regs.get(Reg16::PC), 0x101);
+
+        cpu.mem.lock().unwrap().request_interrupt(0);
+        assert!(cpu.process().is_some());
+        assert_eq!(cpu.interrupt_counts(), [0; 5]);
+        assert_eq!(cpu.regs.get(Reg16::PC), 0x102);
+        assert_eq!(cpu.regs.get(Reg8::B), 0x01);
+        assert_eq!(cpu.mem_get(IF_ADDR) & 0x01, 0x01);
+    }
+
+    #[test]
+    fn ld_fast_page_c() {
+        let mut cpu = cpu_with_program(&[
+            0x0e, 0x80, // LD C,0x80
+            0x3e, 0x5a, // LD A,0x5a
+            0xe2,       // LD (C),A
+            0xaf,       // XOR A
+            0xf2,       // LD A,(C)
+        ]);
+        for _ in 0..3 {
+            cpu.process();
+        }
+        assert_eq!(cpu.regs.get(Reg16::PC), 0x105);
+        assert_eq!(cpu.mem.lock().unwrap().get(0xff80, MemClient::CPU), 0x5a);
+
+        cpu.process();
+        cpu.process();
+        assert_eq!(cpu.regs.get(Reg16::PC), 0x107);
+        assert_eq!(cpu.regs.get(Reg8::A), 0x5a);
+    }
+
+    #[test]
+    fn add_sp_zero_result_clears_z() {
+        let cases: [(u16, u8, bool, bool); 3] = [
+            (0xfffe, 0x02, true, true),   // SP+2 wraps to 0x0000.
+            (0x0001, 0xff, true, true),   // SP-1 is 0x0000, flags from 0x01 + 0xff.
+            (0x0100, 0x80, false, false), // SP-128, no carries from the low byte.
+        ];
+        for &(sp, operand, h, cy) in cases.iter() {
+            let mut cpu = cpu_with_program(&[0x31, sp as u8, (sp >> 8) as u8, 0xe8, operand]);
+            cpu.process();
+            cpu.process();
+
+            let expected = sp.wrapping_add(operand as i8 as i16 as u16);
+            assert_eq!(cpu.regs.get(Reg16::SP), expected);
+            assert!(!cpu.regs.get_flag(Flag::Z));
+            assert!(!cpu.regs.get_flag(Flag::N));
+            assert_eq!(cpu.regs.get_flag(Flag::H), h);
+            assert_eq!(cpu.regs.get_flag(Flag::CY), cy);
+        }
+    }
+
+    #[test]
+    fn sp_offset_flags_from_low_byte() {
+        // (SP, operand, H, CY). The flags come from adding the unsigned operand to SP's low byte,
+        // even for negative offsets.
+        let cases: [(u16, u8, bool, bool); 8] = [
+            (0x0007, 0x01, false, false), // 0x07 + 0x01 stays below bit 3.
+            (0x000f, 0x01, true, false),  // Carry out of bit 3.
+            (0x00f0, 0x10, false, true),  // Carry out of bit 7 only.
+            (0x00ff, 0x01, true, true),   // Carry out of both.
+            (0x0ff8, 0x08, true, true),   // Carries on through bit 11, which has no flag.
+            (0x1000, 0xff, false, false), // SP-1 borrows through bit 12, no carries from 0x00.
+            (0x1008, 0xf8, true, true),   // SP-8, flags from 0x08 + 0xf8.
+            (0xc0f1, 0x8f, true, true),   // SP-113, flags from 0xf1 + 0x8f.
+        ];
+        for &(sp, operand, h, cy) in cases.iter() {
+            let expected = sp.wrapping_add(operand as i8 as i16 as u16);
+
+            // ADD SP,r8, then LD HL,SP+r8 from the original SP.
+            for &(op, dest) in [(0xe8, Reg16::SP), (0xf8, Reg16::HL)].iter() {
+                let mut cpu = cpu_with_program(&[0x31, sp as u8, (sp >> 8) as u8, 0x37, op, operand]);
+                for _ in 0..3 {
+                    cpu.process();
+                }
+                assert_eq!(cpu.regs.get(dest), expected);
+                assert_eq!(cpu.regs.flags_byte(), (h as u8) << 5 | (cy as u8) << 4,
+                           "op 0x{:02x} on SP 0x{:04x} with 0x{:02x}", op, sp, operand);
+                if op == 0xf8 {
+                    assert_eq!(cpu.regs.get(Reg16::SP), sp);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn conditional_branch_cycles() {
+        // (program, machine cycles for the last instruction). XOR A sets Z, and a taken branch
+        // adds the lookup table's extra clocks.
+        let cases: [(&[u8], u32); 10] = [
+            (&[0xaf, 0x28, 0x00], 3),             // JR Z taken
+            (&[0xaf, 0x20, 0x00], 2),             // JR NZ not taken
+            (&[0xaf, 0xca, 0x05, 0x01], 4),       // JP Z taken
+            (&[0xaf, 0xc2, 0x05, 0x01], 3),       // JP NZ not taken
+            (&[0xaf, 0xcc, 0x05, 0x01], 6),       // CALL Z taken
+            (&[0xaf, 0xc4, 0x05, 0x01], 3),       // CALL NZ not taken
+            (&[0xaf, 0xc8], 5),                   // RET Z taken
+            (&[0xaf, 0xc0], 2),                   // RET NZ not taken
+            (&[0xaf, 0x18, 0x00], 3),             // JR
+            (&[0xaf, 0xc9], 4),                   // RET
+        ];
+        for &(program, expected) in cases.iter() {
+            let mut cpu = cpu_with_program(program);
+            assert_eq!(cpu.tick(), Some(1));
+            assert_eq!(cpu.tick(), Some(expected), "{:02x?}", program);
+            assert_eq!(cpu.cycles_elapsed(), 1 + expected as u64);
+        }
+    }
+
+    #[test]
+    fn jp_hl_does_not_dereference() {
+        let mut cpu = cpu_with_program(&[0x21, 0x50, 0xc3, 0xe9]); // LD HL,0xc350, JP HL
+        cpu.mem_set(0x12, 0xc350);
+        cpu.mem_set(0x34, 0xc351);
+        cpu.process();
+        cpu.process();
+
+        assert_eq!(cpu.regs.get(Reg16::PC), 0xc350);
+        assert_eq!(cpu.inst.name, "JP HL");
+        assert_eq!((cpu.inst.bytes, cpu.inst.clocks), (1, 4));
+    }
+
+    #[test]
+    fn ld_a16_sp_little_endian() {
+        let mut cpu = cpu_with_program(&[
+            0x31, 0xfe, 0xff, // LD SP,0xfffe
+            0x08, 0x00, 0xc0, // LD (0xc000),SP
+        ]);
+        cpu.process();
+        cpu.process();
+        let mem = cpu.mem.lock().unwrap();
+        assert_eq!(mem.get(0xc000, MemClient::CPU), 0xfe);
+        assert_eq!(mem.get(0xc001, MemClient::CPU), 0xff);
+    }
+
+    #[test]
+    fn bit_hl_does_not_write() {
+        // A write to DIV would reset it, so it should keep its post-boot value.
+        let mut cpu = cpu_with_program(&[
+            0x21, 0x04, 0xff, // LD HL,0xff04
+            0xcb, 0x56,       // BIT 2,(HL)
+        ]);
+        cpu.process();
+        cpu.process();
+        assert_eq!(cpu.mem.lock().unwrap().get(DIV_ADDR, MemClient::CPU), 0xab);
+        assert!(cpu.regs.get_flag(Flag::Z));
+    }
+
+    #[test]
+    fn vblank_interrupt_break() {
+        let mut cpu = cpu_with_program(&[0x00, 0x00, 0x00]);
+        cpu.int_breaks.insert(0);
+        cpu.process();
+        cpu.dispatch_interrupt(2);
+        assert_eq!(cpu.take_interrupt_break(), None);
+
+        cpu.dispatch_interrupt(0);
+        assert_eq!(cpu.regs.get(Reg16::PC), 0x40);
+        assert_eq!(cpu.take_interrupt_break(), Some((0, 0x50)));
+        assert_eq!(cpu.take_interrupt_break(), None);
+    }
+
+    #[test]
+    fn pause_at_vblank() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut cpu = cpu_with_program(&[
+            0x00,             // NOP
+            0xc3, 0x00, 0x01, // JP 0x100
+        ]);
+        cpu.pause_at_vblank = Some(2);
+        let pauses = Rc::new(RefCell::new(Vec::new()));
+        let pauses_cb = pauses.clone();
+        cpu.set_break_callback(move |cpu| {
+            // The break holds until the callback returns, so sample PC and clocks twice.
+            let held = (cpu.regs.get(Reg16::PC), cpu.clocks());
+            assert_eq!((cpu.regs.get(Reg16::PC), cpu.clocks()), held);
+            pauses_cb.borrow_mut().push((cpu.ppu.frame_count(), held));
+        });
+
+        // Nothing happens before the second VBlank.
+        while cpu.ppu.frame_count() < 2 {
+            assert!(pauses.borrow().is_empty());
+            assert!(cpu.tick().is_some());
+        }
+
+        // The pause comes before the next instruction runs, so PC and the cycle counter are still
+        // where the previous instruction left them.
+        let before = (cpu.regs.get(Reg16::PC), cpu.clocks());
+        assert!(cpu.tick().is_some());
+        assert_eq!(*pauses.borrow(), [(2, before)]);
+        assert!(cpu.ppu.is_alive());
+
+        // Once released, execution continues and it doesn't pause again.
+        cpu.run_clocks(70224);
+        assert!(cpu.clocks() > before.1);
+        assert_eq!(pauses.borrow().len(), 1);
+    }
+
+    #[test]
+    fn first_sram_write_breaks_once() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03; // MBC1+RAM+BATTERY
+        rom[0x149] = 0x02; // 8 KiB
+        rom[0x100..0x10f].copy_from_slice(&[
+            0xea, 0x00, 0xa0, // 0x100: LD (0xA000),A, dropped while RAM is disabled
+            0x3e, 0x0a,       // 0x103: LD A,0x0A
+            0xea, 0x00, 0x00, // 0x105: LD (0x0000),A
+            0xea, 0x10, 0xa0, // 0x108: LD (0xA010),A
+            0xea, 0x11, 0xa0, // 0x10b: LD (0xA011),A
+            0x76,             // 0x10e: HALT
+        ]);
+        let mut cpu = cpu_with_rom(rom, &RuntimeConfig::new());
+        cpu.mem.lock().unwrap().watch_first_sram_write();
+
+        let breaks = Rc::new(RefCell::new(Vec::new()));
+        let breaks_cb = breaks.clone();
+        cpu.set_break_callback(move |cpu| breaks_cb.borrow_mut().push(cpu.pc));
+        while !cpu.halted() {
+            assert!(cpu.tick().is_some());
         }
+
+        // The break lands on the instruction after the write.
+        assert_eq!(*breaks.borrow(), [0x10b]);
+    }
+
+    #[test]
+    fn list_and_clear_breakpoints() {
+        let mut cpu = cpu_with_program(&[0x00]);
+        assert!(cpu.breakpoint_list().is_empty());
+
+        assert!(!cpu.debug_command("b 0x150"));
+        assert!(!cpu.debug_command("b 120"));
+        cpu.break_ignores.insert(0x150, 2);
+        cpu.break_hits.insert(0x150, 1);
+        cpu.int_breaks.insert(0);
+        assert_eq!(cpu.breakpoint_list(), [
+            "0x0120: 0 hits",
+            "0x0150: 1 hits, ignoring the first 2",
+            "VBlank interrupt",
+        ]);
+
+        assert!(!cpu.debug_command("bc 150"));
+        assert!(!cpu.debug_command("bc 150"));
+        assert_eq!(cpu.breakpoint_list(), ["0x0120: 0 hits", "VBlank interrupt"]);
+        assert!(cpu.break_ignores.is_empty() && cpu.break_hits.is_empty());
+
+        assert!(!cpu.debug_command("bca"));
+        assert!(cpu.breakpoint_list().is_empty());
+        assert!(cpu.breaks.is_empty());
+    }
+
+    #[test]
+    fn breakpoint_ignore_count() {
+        let mut cpu = cpu_with_program(&[0x00]);
+        cpu.breaks.insert(0x150);
+        cpu.break_ignores.insert(0x150, 3);
+
+        cpu.pc = 0x150;
+        let hits: Vec<bool> = (0..5).map(|_| cpu.hit_breakpoint()).collect();
+        assert_eq!(hits, [false, false, false, true, true]);
+
+        cpu.pc = 0x151;
+        assert!(!cpu.hit_breakpoint());
+    }
+
+    #[test]
+    fn load_rom_resets_machine() {
+        let dir = std::env::temp_dir().join(format!("gblite_load_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("second.gb");
+        let mut rom = vec![0; 0x8000];
+        rom[0x100] = 0x3c; // INC A
+        rom[0x4000] = 0x5a;
+        fs::write(&rom_path, &rom).unwrap();
+
+        let mut emu = Emulator::new(cpu_with_program(&[
+            0x06, 0x42,       // LD B,0x42
+            0xc3, 0x00, 0x02, // JP 0x200
+        ]));
+        emu.cpu_mut().process();
+        emu.cpu_mut().process();
+        assert_eq!(emu.cpu().regs.get(Reg16::PC), 0x200);
+
+        emu.load_rom(rom_path.to_str().unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let cpu = emu.cpu_mut();
+        assert_eq!(cpu.regs.get(Reg16::PC), 0x100);
+        assert_eq!(cpu.regs.get(Reg8::B), 0x00);
+        assert_eq!(cpu.mem_get(0x4000), 0x5a);
+
+        cpu.process();
+        assert_eq!(cpu.regs.get(Reg8::A), 0x02);
+    }
+
+    #[test]
+    fn load_rom_keeps_memory_settings() {
+        use crate::memory::Model;
+        use crate::serial::{ChannelTransport, Transport};
+
+        let dir = std::env::temp_dir().join(format!("gblite_load_settings_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("second.gb");
+        let mut rom = crate::testrom::build(&[
+            0x3e, 0x42, // LD A,0x42
+            0xe0, 0x01, // LDH (SB),A
+            0x3e, 0x81, // LD A,0x81
+            0xe0, 0x02, // LDH (SC),A
+            0x18, 0xfe, // JR -2
+        ]);
+        rom[0x143] = 0x80; // CGB
+        fs::write(&rom_path, &rom).unwrap();
+
+        let mut rcfg = RuntimeConfig::new();
+        rcfg.model = Model::Dmg;
+        let mut cpu = cpu_with_rom(vec![0; 0x8000], &rcfg);
+        let (link, mut peer) = ChannelTransport::pair();
+        {
+            let mut mref = cpu.mem.lock().unwrap();
+            mref.enable_access_stats();
+            mref.connect_serial(Box::new(link));
+        }
+
+        cpu.load_rom(rom_path.to_str().unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        for _ in 0..8 {
+            cpu.tick().unwrap();
+        }
+
+        // Still a DMG, so the CGB game runs without CGB features, and the link still works.
+        let mref = cpu.mem.lock().unwrap();
+        assert!(!mref.cgb_mode());
+        assert!(mref.access_stats().is_some());
+        assert_eq!(peer.recv(), Some(0x42));
+    }
+
+    #[test]
+    fn slow_frame_warning_after_load_rom() {
+        let dir = std::env::temp_dir().join(format!("gblite_load_slow_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("second.gb");
+        let rom = crate::testrom::build(&[0x18, 0xfe]); // JR -2
+        fs::write(&rom_path, &rom).unwrap();
+
+        // The new ROM's machine clock starts again from 0, behind the last frame's time.
+        let mut rcfg = RuntimeConfig::new();
+        rcfg.deterministic = true;
+        rcfg.warn_slow_frames = true;
+        let mut emu = crate::emulator::Emulator::headless(rom, &rcfg);
+        assert!(emu.cpu_mut().run_frames(3));
+        emu.load_rom(rom_path.to_str().unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(emu.step_frame().is_some());
+    }
+
+    #[test]
+    fn deterministic_runs_match() {
+        let run = || {
+            let mut rom = vec![0; 0x8000];
+            rom[0x147] = 0x10; // MBC3 with RTC
+            rom[0x149] = 0x03;
+            let program = [
+                0x3e, 0x0a,       // LD A,0x0a
+                0xea, 0x00, 0x00, // LD (0x0000),A
+                0x3e, 0x08,       // LD A,0x08
+                0xea, 0x00, 0x40, // LD (0x4000),A
+                0xaf,             // XOR A
+                0xea, 0x00, 0x60, // LD (0x6000),A
+                0x3c,             // INC A
+                0xea, 0x00, 0x60, // LD (0x6000),A
+                0xfa, 0x00, 0xa0, // LD A,(0xa000)
+                0x47,             // LD B,A
+                0xc3, 0x0a, 0x01, // JP 0x10a
+            ];
+            rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+
+            let mut rcfg = RuntimeConfig::new();
+            rcfg.deterministic = true;
+            let mut cpu = cpu_with_rom(rom, &rcfg);
+
+            let ran = cpu.run_clocks(1_048_576);
+            let elapsed = cpu.mem.lock().unwrap().elapsed();
+            (ran, cpu.regs.get(Reg16::PC), cpu.regs.get(Reg8::B), elapsed)
+        };
+
+        // Emulated time follows the clocks run exactly, not the host's clock.
+        let first = run();
+        assert_eq!(first.3, std::time::Duration::from_nanos(first.0 * 1_000_000_000 / 4_194_304));
+        assert_eq!(run(), first);
+    }
+
+    #[test]
+    fn autosave_after_interval() {
+        let dir = std::env::temp_dir().join(format!("gblite_autosave_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03; // MBC1 with RAM and battery
+        rom[0x149] = 0x02;
+        rom[0x100..0x10a].copy_from_slice(&[
+            0x3e, 0x0a,       // LD A,0x0a
+            0xea, 0x00, 0x00, // LD (0x0000),A
+            0xea, 0x00, 0xa0, // LD (0xa000),A
+            0x18, 0xfe,       // JR -2
+        ]);
+        let mut rcfg = RuntimeConfig::new();
+        rcfg.rom_file = Some(String::from("autosave.gb"));
+        rcfg.save_dir = Some(dir.to_string_lossy().into_owned());
+        rcfg.autosave_secs = Some(1);
+        rcfg.deterministic = true;
+        let mut cpu = cpu_with_rom(rom, &rcfg);
+
+        let save_path = dir.join("autosave.sav");
+        cpu.run_clocks(1_048_576);
+        assert!(!save_path.exists());
+
+        cpu.run_clocks(4_194_304);
+        let save = fs::read(&save_path);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(save.unwrap()[0], 0x0a);
+    }
+
+    #[test]
+    fn coverage_marks_fetched_addresses() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x107].copy_from_slice(&[
+            0x06, 0x03, // LD B,3
+            0x05,       // DEC B
+            0x20, 0xfd, // JR NZ,-3
+            0x18, 0xfe, // JR -2
+        ]);
+        let mut rcfg = RuntimeConfig::new();
+        rcfg.coverage = true;
+        let mut cpu = cpu_with_rom(rom, &rcfg);
+
+        for _ in 0..10 {
+            cpu.process();
+        }
+        let executed: Vec<usize> = (0..0x8000).filter(|&a| cpu.executed(a)).collect();
+        assert_eq!(executed, [0x100, 0x102, 0x103, 0x105]);
+    }
+
+    #[test]
+    fn check_sync_through_long_instructions() {
+        let mut cpu = cpu_with_program(&[
+            0xcd, 0x08, 0x01, // CALL 0x108
+            0xaf,             // XOR A
+            0xcc, 0x08, 0x01, // CALL Z,0x108
+            0x76,             // HALT
+            0xc5,             // PUSH BC
+            0xc1,             // POP BC
+            0xc9,             // RET
+        ]);
+        cpu.check_sync = true;
+
+        // The PPU runs a machine cycle for each one an instruction takes, not one per instruction.
+        while cpu.ppu.frame_count() < 2 {
+            assert!(cpu.tick().is_some());
+        }
+        assert!(cpu.halted());
+        assert_eq!(cpu.ppu.cycles() * 4, cpu.clocks());
+    }
+
+    #[test]
+    #[should_panic(expected = "CPU and PPU out of step")]
+    fn check_sync_catches_extra_ppu_step() {
+        let mut cpu = cpu_with_program(&[0x18, 0xfe]); // JR -2
+        cpu.check_sync = true;
+        for _ in 0..10 {
+            cpu.tick();
+        }
+
+        // Stepping the PPU outside of CPU::tick puts it a machine cycle ahead.
+        cpu.ppu.tick();
+        cpu.tick();
+    }
+
+    #[test]
+    fn verbose_toggle() {
+        let mut cpu = cpu_with_program(&[]);
+        assert!(!cpu.verbose);
+        assert!(!cpu.debug_command("v"));
+        assert!(cpu.verbose);
+        cpu.debug_command("v");
+        assert!(!cpu.verbose);
+    }
+
+    #[test]
+    fn snapshot_diff_lists_changed_registers() {
+        let mut cpu = cpu_with_program(&[0x06, 0x05, 0xaf]); // LD B,5, XOR A
+        assert!(!cpu.debug_command("snap start"));
+        cpu.process();
+        cpu.process();
+
+        let changes = cpu.snapshots["start"].diff(&cpu.register_snapshot());
+        assert_eq!(changes, [
+            "A: 0x01 -> 0x00",
+            "B: 0x00 -> 0x05",
+            "PC: 0x0100 -> 0x0103",
+            "H flag: 1 -> 0",
+            "CY: 1 -> 0",
+        ]);
+    }
+
+    #[test]
+    fn trace_and_save_written_after_panic() {
+        let dir = std::env::temp_dir().join(format!("gblite_trace_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03; // MBC1+RAM+BATTERY
+        rom[0x149] = 0x02; // 8 KiB
+        let mut rcfg = RuntimeConfig::new();
+        rcfg.dump_trace = true;
+        rcfg.save_dir = Some(dir.to_string_lossy().into_owned());
+        rcfg.rom_file = Some(String::from("crash.gb"));
+        let mut cpu = cpu_with_rom(rom, &rcfg);
+        {
+            let mut mref = cpu.mem.lock().unwrap();
+            mref.set(0x0a, 0x0000, MemClient::CPU);
+            mref.set(0x42, 0xa000, MemClient::CPU);
+        }
+
+        let result = cpu.run_guarded(|cpu| {
+            cpu.process();
+            cpu.process();
+            panic!("simulated emulator error");
+        });
+        assert!(result.is_err());
+
+        let save = fs::read(dir.join("crash.sav")).unwrap();
+        let trace_path = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path())
+            .find(|p| p.to_string_lossy().ends_with("_trace.log")).unwrap();
+        let trace = fs::read_to_string(&trace_path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(trace.lines().count(), 2);
+        assert_eq!(save[0], 0x42);
+    }
+
+    #[test]
+    fn trace_every_tenth_instruction() {
+        let dir = std::env::temp_dir().join(format!("gblite_trace_every_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut rcfg = RuntimeConfig::new();
+        rcfg.dump_trace = true;
+        rcfg.trace_every = 10;
+        rcfg.save_dir = Some(dir.to_string_lossy().into_owned());
+        let mut cpu = cpu_with_rom(vec![0; 0x8000], &rcfg);
+
+        for _ in 0..35 {
+            cpu.process();
+        }
+        cpu.flush_trace().unwrap();
+
+        let trace_path = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+        let trace = fs::read_to_string(&trace_path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let pcs: Vec<&str> = trace.lines().map(|l| &l[l.find("PC:").unwrap()..][..7]).collect();
+        assert_eq!(pcs, ["PC:0109", "PC:0113", "PC:011d"]);
+    }
+
+    #[test]
+    fn clocks_count_instructions_and_dispatch() {
+        let mut cpu = cpu_with_program(&[
+            0x21, 0x00, 0xc0, // LD HL,0xc000
+            0xfb,             // EI
+            0x00,             // NOP
+        ]);
+        {
+            let mut mem = cpu.mem.lock().unwrap();
+            mem.set(0x01, IE_ADDR, MemClient::CPU);
+            mem.request_interrupt(0);
+        }
+
+        for _ in 0..3 {
+            cpu.tick();
+        }
+        assert_eq!(cpu.clocks(), 12 + 4 + 4);
+
+        // The VBlank dispatch, then the NOP at its vector.
+        cpu.tick();
+        assert_eq!(cpu.regs.get(Reg16::PC), 0x41);
+        assert_eq!(cpu.clocks(), 12 + 4 + 4 + 20 + 4);
+    }
+
+    #[test]
+    fn ei_then_di_services_nothing() {
+        let mut cpu = cpu_with_program(&[0xfb, 0xf3, 0x00, 0x00]); // EI, DI, NOP, NOP
+        {
+            let mut mem = cpu.mem.lock().unwrap();
+            mem.set(0x01, IE_ADDR, MemClient::CPU);
+            mem.request_interrupt(0);
+        }
+
+        for _ in 0..4 {
+            cpu.process();
+        }
+        assert_eq!(cpu.regs.get(Reg16::PC), 0x104);
+        assert_eq!(cpu.interrupt_counts(), [0; 5]);
+        assert!(!cpu.ir_enabled && !cpu.ei_pending);
+    }
+
+    #[test]
+    fn reti_enables_interrupts_immediately() {
+        let mut program = vec![0; 0x11];
+        program[0x00..0x04].copy_from_slice(&[0xcd, 0x10, 0x01, 0x00]); // 0x100: CALL 0x110, NOP
+        program[0x10] = 0xd9;                                            // 0x110: RETI
+        let mut cpu = cpu_with_program(&program);
+        {
+            let mut mem = cpu.mem.lock().unwrap();
+            mem.set(0x01, IE_ADDR, MemClient::CPU);
+            mem.request_interrupt(0);
+        }
+
+        // Unlike EI, there's no delay: the interrupt is taken before the NOP after the return.
+        assert!(cpu.process().is_some());
+        assert!(cpu.process().is_some());
+        assert!(cpu.ime());
+        assert!(cpu.process().is_some());
+        assert_eq!(cpu.interrupt_counts(), [1, 0, 0, 0, 0]);
+        assert_eq!(cpu.regs.get(Reg16::PC), 0x41);
+        assert_eq!(cpu.parse_u16(cpu.regs.get(Reg16::SP)), 0x103);
+    }
+
+    #[test]
+    fn disassemble_range_counts() {
+        let cpu = cpu_with_program(&[
+            0x21, 0x00, 0xc0, // LD HL,0xc000
+            0xcb, 0x7c,       // BIT 7,H
+            0x3e, 0x01,       // LD A,0x01
+            0x77,             // LD (HL),A
+            0x20, 0xf6,       // JR NZ,-10
+            0xc9,             // RET
+        ]);
+
+        let lines = cpu.disassemble_range(0x100, 0x10a);
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[1], (0x103, String::from("BIT 7,H")));
+        assert_eq!(lines[5], (0x10a, String::from("RET")));
+    }
+
+    #[test]
+    fn run_clocks_one_frame() {
+        const FRAME_CLOCKS: u64 = 70224;
+        let mut cpu = cpu_with_program(&[
+            0x00,             // NOP
+            0x21, 0x00, 0xc0, // LD HL,0xc000
+            0xc3, 0x00, 0x01, // JP 0x100
+        ]);
+
+        let ran = cpu.run_clocks(FRAME_CLOCKS);
+        let overshoot = ran - FRAME_CLOCKS;
+        assert!(overshoot < 24);
+
+        // Carrying the overshoot keeps the total in step with the requested clocks.
+        let ran = cpu.run_clocks(FRAME_CLOCKS - overshoot);
+        assert!(ran >= FRAME_CLOCKS - overshoot);
+    }
+
+    #[test]
+    fn break_callback_replaces_prompt() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut cpu = cpu_with_program(&[0x00, 0x00, 0x00, 0x00]);
+        cpu.breaks.insert(0x101);
+        let hits = Rc::new(RefCell::new(Vec::new()));
+        let hits_cb = hits.clone();
+        cpu.set_break_callback(move |cpu| {
+            hits_cb.borrow_mut().push(cpu.regs.get(Reg16::PC));
+            cpu.debug_command("s 2");
+        });
+
+        for _ in 0..4 {
+            cpu.process();
+        }
+        assert!(*hits.borrow() == [0x101, 0x103]);
+    }
+
+    #[test]
+    fn trace_with_disassembly() {
+        let mut cpu = cpu_with_program(&[0xc3, 0x50, 0xc3]); // JP 0xc350
+        cpu.trace_disasm = true;
+        cpu.pc = 0x100;
+        cpu.inst = lookup::get_instruction(0xc3);
+        let line = cpu.get_trace_str();
+        assert!(line.contains("PC:0100"));
+        assert!(line.ends_with("JP 0xc350"));
+    }
+
+    #[test]
+    fn step_count() {
+        // Ten instructions, alternating between one and two bytes long.
+        let mut cpu = cpu_with_program(&[0x00, 0x06, 0x01, 0x00, 0x06, 0x01, 0x00, 0x06, 0x01,
+                                         0x00, 0x06, 0x01, 0x00, 0x06, 0x01]);
+        assert!(cpu.debug_command("s 10"));
+        for _ in 0..9 {
+            cpu.process();
+            assert!(!cpu.stepinto);
+        }
+
+        cpu.process();
+        assert!(cpu.stepinto);
+        assert_eq!(cpu.regs.get(Reg16::PC), 0x10f);
+
+        assert!(!cpu.debug_command("s 0"));
+        assert!(!cpu.debug_command("s x"));
+    }
+
+    #[test]
+    fn display_operands_by_length() {
+        let cases: [(&[u8], &str); 4] = [
+            (&[0x00], "0x0100: NOP"),
+            (&[0x06, 0x05], "0x0100: LD B,d8 0x05"),
+            (&[0x21, 0x00, 0xc0], "0x0100: LD HL,d16 0xc000"),
+            (&[0xcb, 0x7c], "0x0100: BIT 7,H"),
+        ];
+        for (program, expected) in cases.iter() {
+            let mut cpu = cpu_with_program(program);
+            let opcode = if program[0] == 0xcb { 0xcb00 | program[1] as u16 } else { program[0] as u16 };
+            cpu.pc = 0x100;
+            cpu.inst = lookup::get_instruction(opcode);
+            assert_eq!(cpu.get_instruction_info_str(false), *expected);
+        }
+    }
+
+    #[test]
+    fn jr_display_backward() {
+        let mut cpu = cpu_with_program(&[0x20, 0xfa]); // JR NZ,-6
+        cpu.pc = 0x100;
+        cpu.inst = lookup::get_instruction(0x20);
+        assert_eq!(cpu.get_instruction_info_str(false), "0x0100: JR NZ, 0x00fc (-6)");
+
+        cpu.inst = lookup::get_instruction(0x18);
+        assert_eq!(cpu.get_instruction_info_str(false), "0x0100: JR 0x00fc (-6)");
+    }
+
+    #[test]
+    fn load_state_rejects_other_rom() {
+        let cpu_for = |program: &[u8]| cpu_with_rom(crate::testrom::build(program), &RuntimeConfig::new());
+        let path = std::env::temp_dir().join(format!("gblite_state_test_{}.state", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut cpu = cpu_for(&[0x06, 0x42, 0x76]); // LD B,0x42; HALT
+        assert!(cpu.run_until(|cpu, _| cpu.halted(), 1000));
+        cpu.save_state(path).unwrap();
+
+        // The same ROM takes the state back.
+        let mut same = cpu_for(&[0x06, 0x42, 0x76]);
+        same.load_state(path).unwrap();
+        assert_eq!(same.regs.get(Reg8::B), 0x42);
+        assert_eq!(same.regs.get(Reg16::PC), cpu.regs.get(Reg16::PC));
+        assert!(same.halted());
+
+        let mut other = cpu_for(&[0x06, 0x43, 0x76]); // LD B,0x43; HALT
+        let err = other.load_state(path).unwrap_err();
+        fs::remove_file(path).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("different ROM"));
+        assert_eq!(other.regs.get(Reg8::B), 0);
+    }
+
+    #[test]
+    fn load_state_continues_frame() {
+        let rom = crate::testrom::build(&[0x3c, 0x18, 0xfd]); // INC A; JR -3
+        let cpu_for = || {
+            let mut cpu = cpu_with_rom(rom.clone(), &RuntimeConfig::new());
+            cpu.mem.lock().unwrap().set_dma_bus_conflicts(false);
+            cpu.check_sync = true;
+            cpu
+        };
+        let path = std::env::temp_dir().join(format!("gblite_state_frame_test_{}.state", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        // Save partway down the second frame with an OAM DMA running.
+        let mut cpu = cpu_for();
+        assert!(cpu.run_until(|cpu, _| cpu.clocks() >= 70224 + 30000, 200000));
+        cpu.mem.lock().unwrap().set(0xc0, crate::memory::DMA_ADDR, MemClient::CPU);
+        cpu.tick().unwrap();
+        cpu.save_state(path).unwrap();
+
+        let mut restored = cpu_for();
+        restored.load_state(path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(restored.ppu.framebuffer(), cpu.ppu.framebuffer());
+        assert!(restored.mem.lock().unwrap().dma_active());
+
+        // Both finish the frame and the next in step, with check_sync holding throughout.
+        for cpu in [&mut cpu, &mut restored] {
+            assert!(cpu.run_until(|cpu, _| cpu.ppu.frame_count() == 3, 200000));
+        }
+        assert_eq!(restored.clocks(), cpu.clocks());
+        assert_eq!(restored.ppu.cycles(), cpu.ppu.cycles());
+        assert_eq!(restored.regs.get(Reg8::A), cpu.regs.get(Reg8::A));
+        assert_eq!(restored.ppu.framebuffer(), cpu.ppu.framebuffer());
+        let oam = |cpu: &CPU| cpu.mem.lock().unwrap().read_range(0xfe00, 0xa0);
+        assert_eq!(oam(&restored), oam(&cpu));
+    }
+
+    #[test]
+    fn load_state_at_break_takes_no_time() {
+        let path = std::env::temp_dir().join(format!("gblite_state_break_test_{}.state", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let mut cpu = cpu_with_rom(crate::testrom::build(&[0x3c, 0x18, 0xfd]), &RuntimeConfig::new());
+        cpu.check_sync = true;
+        for _ in 0..100 {
+            cpu.tick().unwrap();
+        }
+        cpu.save_state(&path).unwrap();
+        let saved = (cpu.clocks(), cpu.ppu.cycles());
+        cpu.tick().unwrap();
+
+        // The instruction fetched before the load is dropped, so the saved times hold.
+        let ls = format!("ls {}", path);
+        cpu.set_break_callback(move |cpu| { cpu.debug_command(&ls); });
+        cpu.stepinto = true;
+        cpu.tick().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!((cpu.clocks(), cpu.ppu.cycles()), saved);
+    }
+
+    #[test]
+    fn game_genie_patches_rom_reads() {
+        let mut emu = Emulator::new(cpu_with_program(&[0x00]));
+        let read = |cpu: &CPU| cpu.mem.lock().unwrap().get(0x0150, MemClient::CPU);
+
+        // The compare byte doesn't match what's in ROM, so this one does nothing.
+        emu.add_cheat("3E1-50F-F6A").unwrap();
+        assert_eq!(read(emu.cpu()), 0x00);
+
+        emu.add_cheat("3E1-50F-E6A").unwrap();
+        assert_eq!(read(emu.cpu()), 0x3e);
+        assert!(emu.add_cheat("3E1-50").is_err());
+    }
+
+    #[test]
+    fn gameshark_holds_ram_each_frame() {
+        let mut emu = Emulator::new(cpu_with_program(&[
+            0x3e, 0x10,       // LD A,0x10
+            0xea, 0x00, 0xc0, // LD (0xc000),A
+            0x18, 0xfe,       // JR -2
+        ]));
+        let read = |cpu: &CPU| cpu.mem.lock().unwrap().get(0xc000, MemClient::CPU);
+        emu.add_cheat("016300C0").unwrap();
+        assert_eq!(read(emu.cpu()), 0x63);
+
+        // The program overwrites it, and the cheat puts it back at the next VBlank.
+        emu.cpu_mut().tick();
+        emu.cpu_mut().tick();
+        emu.cpu_mut().tick();
+        assert_eq!(read(emu.cpu()), 0x10);
+        assert!(emu.cpu_mut().run_frames(1));
+        assert_eq!(read(emu.cpu()), 0x63);
+
+        emu.cpu().mem.lock().unwrap().set(0x20, 0xc000, MemClient::CPU);
+        assert!(emu.cpu_mut().run_frames(1));
+        assert_eq!(read(emu.cpu()), 0x63);
+    }
+
+    #[test]
+    fn runs_from_hram_during_dma() {
+        let mut cpu = cpu_with_program(&[
+            0x3e, 0xc0,       // LD A,0xc0
+            0xc3, 0x80, 0xff, // JP 0xff80
+        ]);
+        {
+            let mut mref = cpu.mem.lock().unwrap();
+            for i in 0..0xa0u16 {
+                mref.set(i as u8 + 1, 0xc000 + i, MemClient::CPU);
+            }
+            let routine = [
+                0xe0, 0x46,       // LDH (DMA),A
+                0xfa, 0x05, 0xc0, // LD A,(0xc005)
+                0x47,             // LD B,A
+                0x3e, 0x40,       // LD A,64, to wait out the copy
+                0x3d,             // DEC A
+                0x20, 0xfd,       // JR NZ,-3
+                0x76,             // HALT
+            ];
+            for (i, byte) in routine.iter().enumerate() {
+                mref.set(*byte, 0xff80 + i as u16, MemClient::CPU);
+            }
+        }
+
+        assert!(cpu.run_until(|cpu, _| cpu.halted(), 2000));
+        assert_eq!(cpu.regs.get(Reg16::PC), 0xff8c);
+        // WRAM was off the bus during the copy, but HRAM wasn't.
+        assert_eq!(cpu.regs.get(Reg8::B), 0xff);
+
+        let mref = cpu.mem.lock().unwrap();
+        assert!(!mref.dma_active());
+        assert_eq!(mref.read_range(0xfe00, 0xa0), mref.read_range(0xc000, 0xa0));
+    }
+
+    #[test]
+    fn step_frame_stops_at_vblank_or_break() {
+        let mut emu = Emulator::new(cpu_with_program(&[
+            0x04,       // INC B
+            0x18, 0xfd, // JR -3
+        ]));
+        for n in 1..=2 {
+            let frame = emu.step_frame().unwrap();
+            assert_eq!(frame.len(), 160 * 144 * 3);
+            let cpu = emu.cpu();
+            assert_eq!(cpu.ppu.frame_count(), n);
+            assert_eq!(cpu.mem.lock().unwrap().get(STAT_ADDR, MemClient::CPU) & 0x03, 1);
+        }
+
+        let cpu = emu.cpu_mut();
+        cpu.breaks.insert(0x101);
+        cpu.set_break_callback(|_| ());
+        assert!(emu.step_frame().is_none());
+        assert_eq!(emu.cpu().ppu.frame_count(), 2);
+        assert_eq!(emu.cpu().pc, 0x101);
+    }
+
+    #[test]
+    fn call_depth_follows_calls_and_returns() {
+        let mut program = vec![0; 0x31];
+        program[0x00..0x04].copy_from_slice(&[0xcd, 0x10, 0x01, 0x76]); // 0x100: CALL 0x110, HALT
+        program[0x10..0x14].copy_from_slice(&[0xcd, 0x20, 0x01, 0xc9]); // 0x110: CALL 0x120, RET
+        program[0x20..0x24].copy_from_slice(&[0xcd, 0x30, 0x01, 0xc9]); // 0x120: CALL 0x130, RET
+        program[0x30] = 0xc9;                                            // 0x130: RET
+        let mut cpu = cpu_with_program(&program);
+
+        // Record (PC of the instruction, depth after it) each time the depth changes.
+        let mut trace = Vec::new();
+        while !cpu.halted() {
+            let depth = cpu.call_depth();
+            cpu.process();
+            if cpu.call_depth() != depth {
+                trace.push((cpu.pc, cpu.call_depth()));
+            }
+        }
+        assert_eq!(trace, [(0x100, 1), (0x110, 2), (0x120, 3), (0x130, 2), (0x123, 1), (0x113, 0)]);
+    }
+
+    #[test]
+    fn step_described_reports_each_instruction() {
+        let mut emu = Emulator::new(cpu_with_program(&[
+            0xfb,             // 0x100: EI
+            0x06, 0x05,       // 0x101: LD B,5
+            0xc3, 0x00, 0x01, // 0x103: JP 0x100
+        ]));
+        {
+            let mut mref = emu.cpu().mem.lock().unwrap();
+            mref.set(0x01, IE_ADDR, MemClient::CPU);
+        }
+
+        let step = emu.step_described().unwrap();
+        assert_eq!(step, StepInfo { mnemonic: String::from("EI"), pc_before: 0x100, pc_after: 0x101, clocks: 4, interrupt: None });
+        let step = emu.step_described().unwrap();
+        assert_eq!((step.mnemonic.as_str(), step.pc_before, step.pc_after, step.clocks), ("LD B,0x05", 0x101, 0x103, 8));
+
+        // A VBlank request is dispatched first, then the NOP at its vector runs.
+        emu.cpu().mem.lock().unwrap().set(0x01, IF_ADDR, MemClient::CPU);
+        let step = emu.step_described().unwrap();
+        assert_eq!(step, StepInfo { mnemonic: String::from("NOP"), pc_before: 0x103, pc_after: 0x41, clocks: 24, interrupt: Some(0) });
+    }
+
+    #[test]
+    fn identical_cpus_run_in_lockstep() {
+        // Count into WRAM and the tile map, so both memory and the screen change every frame.
+        let program = [
+            0x21, 0x00, 0x98, // 0x100: LD HL,0x9800
+            0x34,             // 0x103: INC (HL)
+            0xfa, 0x00, 0xc0, // 0x104: LD A,(0xC000)
+            0x3c,             // 0x107: INC A
+            0xea, 0x00, 0xc0, // 0x108: LD (0xC000),A
+            0xc3, 0x03, 0x01, // 0x10b: JP 0x103
+        ];
+        let mut a = Emulator::new(cpu_with_program(&program));
+        let mut b = Emulator::new(cpu_with_program(&program));
+        assert_eq!(a.run_lockstep(&mut b, 5), Ok(()));
+
+        b.cpu().mem.lock().unwrap().set(0x00, 0xc100, MemClient::CPU);
+        a.cpu().mem.lock().unwrap().set(0x01, 0xc100, MemClient::CPU);
+        let err = a.run_lockstep(&mut b, 1).unwrap_err();
+        assert!(err.starts_with("Frame 1: memory differs at 0xc100"), "{}", err);
     }
 }
@@ -0,0 +1,72 @@
+// The whole machine, for front ends and tools built on the library. The CPU already drives
+// everything, owning the PPU and sharing memory with it, so this just forwards to it. Anything not
+// covered here is reachable through cpu() and cpu_mut().
+
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::cpu::{CPU, StepInfo};
+use crate::memory::Memory;
+use crate::ppu::PPU;
+use crate::RuntimeConfig;
+
+pub struct Emulator {
+    cpu: CPU,
+}
+
+impl Emulator {
+    pub fn new(cpu: CPU) -> Emulator {
+        Emulator { cpu }
+    }
+
+    // Build a machine without a window that runs the given ROM image, with memory set up from the
+    // config's RAM init, model, and determinism settings.
+    pub fn headless(rom: Vec<u8>, rcfg: &RuntimeConfig) -> Emulator {
+        let mut mem = Memory::new(0x10000);
+        mem.init_ram(rcfg.ram_init);
+        mem.set_model(rcfg.model);
+        mem.set_deterministic(rcfg.deterministic);
+        mem.load_rom(rom);
+        let mem = Arc::new(Mutex::new(mem));
+        let ppu = PPU::new_headless(mem.clone(), rcfg);
+        Emulator::new(CPU::new(mem, ppu, rcfg))
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    pub fn into_cpu(self) -> CPU {
+        self.cpu
+    }
+
+    // See CPU::load_rom.
+    pub fn load_rom(&mut self, path: &str) -> io::Result<()> {
+        self.cpu.load_rom(path)
+    }
+
+    pub fn add_cheat(&mut self, code: &str) -> Result<(), String> {
+        self.cpu.add_cheat(code)
+    }
+
+    pub fn run_until<F>(&mut self, pred: F, max_clocks: u64) -> bool
+        where F: FnMut(&CPU, &Memory) -> bool {
+        self.cpu.run_until(pred, max_clocks)
+    }
+
+    pub fn step_frame(&mut self) -> Option<Vec<u8>> {
+        self.cpu.step_frame()
+    }
+
+    pub fn step_described(&mut self) -> Option<StepInfo> {
+        self.cpu.step_described()
+    }
+
+    pub fn run_lockstep(&mut self, other: &mut Emulator, frames: u64) -> Result<(), String> {
+        self.cpu.run_lockstep(&mut other.cpu, frames)
+    }
+}
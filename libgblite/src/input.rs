@@ -0,0 +1,104 @@
+// Scripted input, for driving the emulator without a window. A script has one command per line:
+//
+//   press [button]    Hold a button down.
+//   release [button]  Let a button go.
+//   frame [N]         Run until N more frames (default 1) have finished.
+//
+// Buttons are a, b, select, start, up, down, left, and right. Blank lines and lines starting
+// with '#' are ignored.
+
+use std::io::BufRead;
+
+use crate::cpu::CPU;
+use crate::joypad::Button;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Command {
+    Press(Button),
+    Release(Button),
+    Frame(u64),
+}
+
+fn parse_button(name: &str) -> Option<Button> {
+    match name.to_lowercase().as_str() {
+        "a"      => Some(Button::A),
+        "b"      => Some(Button::B),
+        "select" => Some(Button::Select),
+        "start"  => Some(Button::Start),
+        "up"     => Some(Button::Up),
+        "down"   => Some(Button::Down),
+        "left"   => Some(Button::Left),
+        "right"  => Some(Button::Right),
+        _ => None,
+    }
+}
+
+// Parse one line of a script. Returns None for lines with no command.
+pub fn parse_command(line: &str) -> Result<Option<Command>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let mut words = line.split_whitespace();
+    let cmd = words.next().unwrap();
+    let arg = words.next();
+    let button = || arg.and_then(parse_button).ok_or_else(|| format!("Invalid button in \"{}\"", line));
+
+    match cmd {
+        "press" => Ok(Some(Command::Press(button()?))),
+        "release" => Ok(Some(Command::Release(button()?))),
+        "frame" => match arg.map(|n| n.parse::<u64>()) {
+            None => Ok(Some(Command::Frame(1))),
+            Some(Ok(n)) => Ok(Some(Command::Frame(n))),
+            Some(Err(e)) => Err(format!("Invalid frame count in \"{}\": {}", line, e)),
+        },
+        _ => Err(format!("Unknown input command \"{}\"", line)),
+    }
+}
+
+// Run the commands read from the given script, such as stdin, until it ends or emulation stops.
+pub fn run_script<R: BufRead>(cpu: &mut CPU, script: R) -> Result<(), String> {
+    for line in script.lines() {
+        let line = line.map_err(|e| format!("Error reading input script: {}", e))?;
+        let running = match parse_command(&line)? {
+            Some(Command::Press(button)) => { cpu.mem.lock().unwrap().set_button(button, true); true },
+            Some(Command::Release(button)) => { cpu.mem.lock().unwrap().set_button(button, false); true },
+            Some(Command::Frame(n)) => cpu.run_frames(n),
+            None => true,
+        };
+        if !running { break; }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+    use crate::memory::{MemClient, P1_ADDR};
+    use crate::RuntimeConfig;
+
+    #[test]
+    fn scripted_session() {
+        let mut cpu = Emulator::headless(vec![0; 0x8000], &RuntimeConfig::new()).into_cpu();
+
+        let script = "press a\npress Start\nframe\n\n# let go of A\nrelease a\nframe 2\n";
+        run_script(&mut cpu, script.as_bytes()).unwrap();
+        assert_eq!(cpu.ppu.frame_count(), 3);
+
+        // Select the action buttons: only Start (bit 3) reads as pressed.
+        let mut mref = cpu.mem.lock().unwrap();
+        mref.set(0x10, P1_ADDR, MemClient::CPU);
+        assert_eq!(mref.get(P1_ADDR, MemClient::CPU), 0xd7);
+    }
+
+    #[test]
+    fn bad_commands() {
+        assert_eq!(parse_command("frame"), Ok(Some(Command::Frame(1))));
+        assert!(parse_command("press x").is_err());
+        assert!(parse_command("release").is_err());
+        assert!(parse_command("frame two").is_err());
+        assert!(parse_command("jump").is_err());
+    }
+}
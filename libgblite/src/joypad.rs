@@ -0,0 +1,108 @@
+// The joypad is read through the P1 register (0xFF00). The game selects the direction keys by
+// clearing bit 4, and/or the action buttons by clearing bit 5, then reads the selected keys from
+// the lower nibble, where a pressed key reads as 0.
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    // Returns (true if this is a direction key, bit in the P1 lower nibble).
+    fn p1_bit(self) -> (bool, u8) {
+        match self {
+            Button::Right  => (true,  0),
+            Button::Left   => (true,  1),
+            Button::Up     => (true,  2),
+            Button::Down   => (true,  3),
+            Button::A      => (false, 0),
+            Button::B      => (false, 1),
+            Button::Select => (false, 2),
+            Button::Start  => (false, 3),
+        }
+    }
+}
+
+pub struct Joypad {
+    select: u8,     // P1 bits 4-5, as last written by the CPU.
+    directions: u8, // Lower nibble, a set bit means the key is held down.
+    actions: u8,    // Lower nibble, a set bit means the button is held down.
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Joypad::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            select: 0x30,
+            directions: 0,
+            actions: 0,
+        }
+    }
+
+    // Update the held state of a button. Returns true if the button was newly pressed.
+    pub fn set_button(&mut self, button: Button, pressed: bool) -> bool {
+        let (is_direction, bit) = button.p1_bit();
+        let keys = if is_direction { &mut self.directions } else { &mut self.actions };
+        let was_pressed = (*keys & (1 << bit)) != 0;
+        if pressed {
+            *keys |= 1 << bit;
+        } else {
+            *keys &= !(1 << bit);
+        }
+
+        pressed && !was_pressed
+    }
+
+    // Read P1. If both groups are selected, a key reads as pressed if it's held in either group.
+    pub fn read(&self) -> u8 {
+        let mut keys = 0x0f;
+        if self.select & 0x10 == 0 {
+            keys &= !self.directions;
+        }
+        if self.select & 0x20 == 0 {
+            keys &= !self.actions;
+        }
+
+        0xc0 | self.select | keys
+    }
+
+    // Only the select bits of P1 are writable.
+    pub fn write(&mut self, val: u8) {
+        self.select = val & 0x30;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_combinations() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(Button::Right, true);
+        joypad.set_button(Button::B, true);
+
+        joypad.write(0x30);
+        assert_eq!(joypad.read(), 0xff);
+        joypad.write(0x20);
+        assert_eq!(joypad.read(), 0xee);
+        joypad.write(0x10);
+        assert_eq!(joypad.read(), 0xdd);
+
+        // With both groups selected, the two nibbles are ANDed together.
+        joypad.write(0x00);
+        assert_eq!(joypad.read(), 0xcc);
+    }
+}
@@ -1,33 +1,110 @@
 pub mod cpu;
+pub mod emulator;
+pub mod input;
+pub mod joypad;
+pub mod lookup;
 pub mod memory;
 pub mod ppu;
+pub mod serial;
+pub mod testrom;
+pub mod timer;
 pub mod util;
 
 mod alu;
+mod cheats;
+mod clock;
 mod registers;
-mod lookup;
+mod mbc;
+mod palette;
+mod patch;
+mod state;
 mod window;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+
+use memory::{Model, RamInit};
+use ppu::PixelFormat;
 
 pub struct RuntimeConfig {
     pub rom_file: Option<String>,
+    pub boot_rom_file: Option<String>,
+    pub patch_file: Option<String>, // IPS patch applied to the ROM once it's loaded.
+    pub cheats: Vec<String>, // Game Genie and GameShark codes to apply.
+    pub save_dir: Option<String>, // Where save files and logs go, instead of the working dir.
     pub breakpoints: HashSet<u16>,
+    pub breakpoint_ignores: HashMap<u16, u32>, // Number of hits to skip before each breakpoint stops.
+    pub interrupt_breaks: HashSet<u8>, // IF bits of interrupts to break on when dispatched.
+    pub break_on_sram_write: bool, // Break once, after the first write to enabled external RAM.
     pub killpoint: Option<u16>,
+    pub pause_at_vblank: Option<u64>,
     pub dump_trace: bool,
+    pub trace_disasm: bool,
+    pub trace_every: usize, // Only trace one in this many instructions.
     pub dump_mem: bool,
+    pub coverage: bool,
+    pub access_stats: bool, // Count CPU reads and writes per memory region, printed on exit.
     pub verbose:  bool,
+    pub log_ppu_modes: bool,
+    pub verbose_ppu: bool,
+    pub log_interrupts: bool,
+    pub call_depth_log: Option<u32>, // Log each change of CALL/RET nesting at or above this depth.
+    pub warn_slow_frames: bool,
+    pub stdin_input: bool,
+    pub link_listen: Option<String>,
+    pub link_connect: Option<String>,
+    pub ram_init: RamInit,
+    pub model: Model,
+    pub deterministic: bool,
+    pub autosave_secs: Option<u64>, // Write changed battery RAM to the save file this often.
+    pub check_sync: bool, // Assert the CPU, PPU, and machine clock agree on elapsed cycles after each instruction.
+    pub pixel_format: PixelFormat,
+    pub palette_file: Option<String>, // Four RGB hex colors to draw DMG shades with.
+    pub vsync: bool, // Sync window presents to the display, trading latency for no tearing.
+    pub unlimited_sprites: bool,
+    pub scanline_filter: bool, // Start with every other line dimmed, like an LCD grid.
+    pub fifo_renderer: bool, // Draw a pixel per dot through the pixel FIFOs instead of a line at a time.
 }
 
 impl RuntimeConfig {
     pub fn new() -> Self {
         RuntimeConfig {
             rom_file: None,
+            boot_rom_file: None,
+            patch_file: None,
+            cheats: Vec::new(),
+            save_dir: None,
             breakpoints: HashSet::new(),
+            breakpoint_ignores: HashMap::new(),
+            interrupt_breaks: HashSet::new(),
+            break_on_sram_write: false,
             killpoint: None,
+            pause_at_vblank: None,
             dump_trace: false,
+            trace_disasm: false,
+            trace_every: 1,
             dump_mem: false,
+            coverage: false,
+            access_stats: false,
             verbose:  false,
+            log_ppu_modes: false,
+            verbose_ppu: false,
+            log_interrupts: false,
+            call_depth_log: None,
+            warn_slow_frames: false,
+            stdin_input: false,
+            link_listen: None,
+            link_connect: None,
+            ram_init: RamInit::Zero,
+            model: Model::Cgb,
+            deterministic: false,
+            autosave_secs: None,
+            check_sync: false,
+            pixel_format: PixelFormat::Rgb24,
+            palette_file: None,
+            vsync: false,
+            unlimited_sprites: false,
+            scanline_filter: false,
+            fifo_renderer: false,
         }
     }
 }
@@ -1,7 +1,7 @@
 #![allow(dead_code)]
 
-use crate::registers::FlagMod;
-use crate::registers::FlagStatus;
+// Part of the public API, so tools can reuse the opcode tables.
+pub use crate::registers::{FlagMod, FlagStatus};
 
 pub struct Instruction {
     pub opcode: u8,           // The byte opcode of this instruction.
@@ -15,6 +15,16 @@ pub struct Instruction {
     pub modifies_flags: bool  // True if any flag could be modified by this instruction
 }
 
+/// Look up an instruction's length, timing, and name without running it. Opcodes after the 0xCB
+/// prefix are looked up as 0xCBxx.
+///
+/// ```
+/// use libgblite::lookup::{self, FlagMod};
+/// let jp = lookup::get_instruction(0xc3);
+/// assert_eq!(jp.name, "JP a16");
+/// assert_eq!((jp.bytes, jp.clocks), (3, 16));
+/// assert!(matches!(lookup::get_flags(0xc3).z, FlagMod::Ignore));
+/// ```
 pub fn get_instruction(opcode: u16) -> Instruction {
     match opcode {
         0x0 => Instruction {
@@ -2055,7 +2065,7 @@ pub fn get_instruction(opcode: u16) -> Instruction {
             opcode: 0xe2,
             prefix_cb: false,
             name: String::from("LD (C),A"),
-            bytes: 2,
+            bytes: 1,
             clocks: 8,
             clocks_extra: 0,
             modifies_flags: false
@@ -2199,7 +2209,7 @@ pub fn get_instruction(opcode: u16) -> Instruction {
             opcode: 0xf2,
             prefix_cb: false,
             name: String::from("LD A,(C)"),
-            bytes: 2,
+            bytes: 1,
             clocks: 8,
             clocks_extra: 0,
             modifies_flags: false
@@ -2956,7 +2966,7 @@ pub fn get_instruction(opcode: u16) -> Instruction {
             prefix_cb: true,
             name: String::from("BIT 0,(HL)"),
             bytes: 2,
-            clocks: 16,
+            clocks: 12,
             clocks_extra: 0,
             modifies_flags: true
         },
@@ -3028,7 +3038,7 @@ pub fn get_instruction(opcode: u16) -> Instruction {
             prefix_cb: true,
             name: String::from("BIT 1,(HL)"),
             bytes: 2,
-            clocks: 16,
+            clocks: 12,
             clocks_extra: 0,
             modifies_flags: true
         },
@@ -3100,7 +3110,7 @@ pub fn get_instruction(opcode: u16) -> Instruction {
             prefix_cb: true,
             name: String::from("BIT 2,(HL)"),
             bytes: 2,
-            clocks: 16,
+            clocks: 12,
             clocks_extra: 0,
             modifies_flags: true
         },
@@ -3172,7 +3182,7 @@ pub fn get_instruction(opcode: u16) -> Instruction {
             prefix_cb: true,
             name: String::from("BIT 3,(HL)"),
             bytes: 2,
-            clocks: 16,
+            clocks: 12,
             clocks_extra: 0,
             modifies_flags: true
         },
@@ -3244,7 +3254,7 @@ pub fn get_instruction(opcode: u16) -> Instruction {
             prefix_cb: true,
             name: String::from("BIT 4,(HL)"),
             bytes: 2,
-            clocks: 16,
+            clocks: 12,
             clocks_extra: 0,
             modifies_flags: true
         },
@@ -3316,7 +3326,7 @@ pub fn get_instruction(opcode: u16) -> Instruction {
             prefix_cb: true,
             name: String::from("BIT 5,(HL)"),
             bytes: 2,
-            clocks: 16,
+            clocks: 12,
             clocks_extra: 0,
             modifies_flags: true
         },
@@ -3388,7 +3398,7 @@ pub fn get_instruction(opcode: u16) -> Instruction {
             prefix_cb: true,
             name: String::from("BIT 6,(HL)"),
             bytes: 2,
-            clocks: 16,
+            clocks: 12,
             clocks_extra: 0,
             modifies_flags: true
         },
@@ -3460,7 +3470,7 @@ pub fn get_instruction(opcode: u16) -> Instruction {
             prefix_cb: true,
             name: String::from("BIT 7,(HL)"),
             bytes: 2,
-            clocks: 16,
+            clocks: 12,
             clocks_extra: 0,
             modifies_flags: true
         },
@@ -4640,8 +4650,40 @@ pub fn get_instruction(opcode: u16) -> Instruction {
     }
 }
 
+// Decode the instruction at the start of bytes, which is located at addr, into its mnemonic with
+// the operand placeholders (d8, d16, a8, a16, r8) replaced by their values. Missing trailing bytes
+// are treated as zero. Returns the text and the length of the instruction in bytes.
+pub fn disassemble(bytes: &[u8], addr: u16) -> (String, u8) {
+    let byte = |i: usize| *bytes.get(i).unwrap_or(&0);
+    let opcode = if byte(0) == 0xcb { 0xcb00 | byte(1) as u16 } else { byte(0) as u16 };
+    let inst = get_instruction(opcode);
+
+    let imm8 = byte(1);
+    let imm16 = (byte(2) as u16) << 8 | byte(1) as u16;
+    let text = if inst.prefix_cb {
+        inst.name.clone()
+    } else if inst.name.contains("d16") || inst.name.contains("a16") {
+        inst.name.replace("d16", &format!("0x{:04x}", imm16))
+                 .replace("a16", &format!("0x{:04x}", imm16))
+    } else if inst.name.contains("d8") {
+        inst.name.replace("d8", &format!("0x{:02x}", imm8))
+    } else if inst.name.contains("a8") {
+        inst.name.replace("a8", &format!("0xff{:02x}", imm8))
+    } else if inst.name.starts_with("JR") {
+        let target = addr.wrapping_add(2).wrapping_add(imm8 as i8 as u16);
+        inst.name.replace("r8", &format!("0x{:04x}", target))
+    } else if inst.name.contains("r8") {
+        let offset = imm8 as i8;
+        inst.name.replace("+r8", &format!("{:+}", offset)).replace("r8", &format!("{}", offset))
+    } else {
+        inst.name.clone()
+    };
+
+    (text, inst.bytes)
+}
 
-pub fn get_flagmod(full_opcode: u16) -> FlagStatus {
+// How the instruction changes each flag, looked up the same way as get_instruction.
+pub fn get_flags(full_opcode: u16) -> FlagStatus {
     match full_opcode {
         0x04 => FlagStatus{ z: FlagMod::Eval, n: FlagMod::Set(false), h: FlagMod::Eval, cy: FlagMod::Ignore },
         0x05 => FlagStatus{ z: FlagMod::Eval, n: FlagMod::Set(true), h: FlagMod::Eval, cy: FlagMod::Ignore },
@@ -4876,3 +4918,94 @@ pub fn get_flagmod(full_opcode: u16) -> FlagStatus {
         _      => FlagStatus{ z: FlagMod::Ignore, n: FlagMod::Ignore, h: FlagMod::Ignore, cy: FlagMod::Ignore }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disassemble_operands() {
+        assert_eq!(disassemble(&[0xc3, 0x50, 0xc3], 0x150), (String::from("JP 0xc350"), 3));
+        assert_eq!(disassemble(&[0x3e, 0x42], 0x150), (String::from("LD A,0x42"), 2));
+        assert_eq!(disassemble(&[0xe0, 0x40], 0x150), (String::from("LDH (0xff40),A"), 2));
+        assert_eq!(disassemble(&[0x20, 0xfa], 0x156), (String::from("JR NZ,0x0152"), 2));
+        assert_eq!(disassemble(&[0xf8, 0xfe], 0x150), (String::from("LD HL,SP-2"), 2));
+        assert_eq!(disassemble(&[0xe8, 0x02], 0x150), (String::from("ADD SP,2"), 2));
+        assert_eq!(disassemble(&[0xcb, 0x7c], 0x150), (String::from("BIT 7,H"), 2));
+        assert_eq!(disassemble(&[0x00], 0x150), (String::from("NOP"), 1));
+    }
+
+    // Reference lengths and base clocks (the untaken path of conditionals) of the unprefixed
+    // opcodes, from the Pan Docs opcode table. Unused opcodes are 0.
+    const REF_BYTES: [u8; 256] = [
+        1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 1, 3, 3, 2, 1,
+        1, 1, 3, 0, 3, 1, 2, 1, 1, 1, 3, 0, 3, 0, 2, 1,
+        2, 1, 1, 0, 0, 1, 2, 1, 2, 1, 3, 0, 0, 0, 2, 1,
+        2, 1, 1, 1, 0, 1, 2, 1, 2, 1, 3, 1, 0, 0, 2, 1,
+    ];
+
+    const REF_CLOCKS: [u8; 256] = [
+         4, 12,  8,  8,  4,  4,  8,  4, 20,  8,  8,  8,  4,  4,  8,  4,
+         4, 12,  8,  8,  4,  4,  8,  4, 12,  8,  8,  8,  4,  4,  8,  4,
+         8, 12,  8,  8,  4,  4,  8,  4,  8,  8,  8,  8,  4,  4,  8,  4,
+         8, 12,  8,  8, 12, 12, 12,  4,  8,  8,  8,  8,  4,  4,  8,  4,
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4,
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4,
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4,
+         8,  8,  8,  8,  8,  8,  4,  8,  4,  4,  4,  4,  4,  4,  8,  4,
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4,
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4,
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4,
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4,
+         8, 12, 12, 16, 12, 16,  8, 16,  8, 16, 12,  4, 12, 24,  8, 16,
+         8, 12, 12,  0, 12, 16,  8, 16,  8, 16, 12,  0, 12,  0,  8, 16,
+        12, 12,  8,  0,  0, 16,  8, 16, 16,  4, 16,  0,  0,  0,  8, 16,
+        12, 12,  8,  4,  0, 16,  8, 16, 12,  8, 16,  4,  0,  0,  8, 16,
+    ];
+
+    // Check every opcode's length and timing against the reference tables, and the CB opcodes
+    // against the rules they all follow: two bytes, 8 clocks, or 16 on (HL) except BIT's 12.
+    // Conditional jumps, calls, and returns take clocks_extra more when the branch is taken.
+    #[test]
+    fn opcode_table_consistency() {
+        let mut mismatches = Vec::new();
+        for op in 0..=0xffu16 {
+            let inst = get_instruction(op);
+            let extra = match op {
+                0x20 | 0x28 | 0x30 | 0x38 | 0xc2 | 0xca | 0xd2 | 0xda => 4,
+                0xc0 | 0xc8 | 0xd0 | 0xd8 | 0xc4 | 0xcc | 0xd4 | 0xdc => 12,
+                _ => 0,
+            };
+            let expected = (REF_BYTES[op as usize], REF_CLOCKS[op as usize], extra);
+            if (inst.bytes, inst.clocks, inst.clocks_extra) != expected || inst.opcode as u16 != op {
+                mismatches.push(format!("0x{:02x} {}: (bytes, clocks, extra) {:?}, expected {:?}",
+                    op, inst.name, (inst.bytes, inst.clocks, inst.clocks_extra), expected));
+            }
+
+            let cb = 0xcb00 | op;
+            let inst = get_instruction(cb);
+            let clocks = match (op & 0x07, op & 0xc0) {
+                (6, 0x40) => 12,
+                (6, _) => 16,
+                _ => 8,
+            };
+            if (inst.bytes, inst.clocks, inst.clocks_extra) != (2, clocks, 0) || !inst.prefix_cb || inst.opcode as u16 != op {
+                mismatches.push(format!("0x{:04x} {}: (bytes, clocks, extra) {:?}, expected {:?}",
+                    cb, inst.name, (inst.bytes, inst.clocks, inst.clocks_extra), (2, clocks, 0)));
+            }
+        }
+        assert!(mismatches.is_empty(), "opcode table mismatches:\n{}", mismatches.join("\n"));
+    }
+}
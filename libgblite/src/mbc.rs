@@ -0,0 +1,239 @@
+// Memory bank controllers. Writes to the ROM region never modify ROM, they set the controller's
+// registers, which select the ROM bank mapped at [0x4000, 0x8000) and the RAM bank mapped at
+// [0xA000, 0xC000). The external RAM enable register is handled by Memory.
+//
+// MBC3 carts may also have a real-time clock. Selecting RAM bank 0x08-0x0C maps one of its
+// registers (seconds, minutes, hours, day low, day high) to [0xA000, 0xC000) instead of RAM, and
+// writing 0x00 then 0x01 to [0x6000, 0x8000) latches the current time into them. Times are given
+// to the MBC in seconds, so the caller decides where time comes from.
+
+// Length of the clock state appended to save files, in the layout other emulators use: the
+// current then latched registers as five 32-bit values each, then a 64-bit Unix timestamp of when
+// it was saved, all little endian.
+pub const RTC_SAVE_LEN: usize = 48;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum MbcKind {
+    RomOnly,
+    Mbc1,
+    Mbc3,
+    Mbc5,
+}
+
+impl MbcKind {
+    // The controller for the cartridge type at 0x0147 of the header. Unsupported controllers
+    // are treated as ROM-only.
+    pub fn from_cart_type(cart_type: u8) -> MbcKind {
+        match cart_type {
+            0x01..=0x03 => MbcKind::Mbc1,
+            0x0f..=0x13 => MbcKind::Mbc3,
+            0x19..=0x1e => MbcKind::Mbc5,
+            _ => MbcKind::RomOnly,
+        }
+    }
+}
+
+// MBC3 real-time clock.
+struct Rtc {
+    base_secs: u64,    // The clock's value in seconds at base_time.
+    base_time: u64,    // The time the clock was last set.
+    halted: bool,      // Day high bit 6: the clock doesn't advance while set.
+    latched: [u8; 5],  // Register values as of the last latch.
+    latch_armed: bool, // Set by a 0x00 write, a following 0x01 write latches.
+}
+
+impl Rtc {
+    fn new() -> Rtc {
+        Rtc {
+            base_secs: 0,
+            base_time: 0,
+            halted: false,
+            latched: [0; 5],
+            latch_armed: false,
+        }
+    }
+
+    fn secs(&self, now: u64) -> u64 {
+        if self.halted { self.base_secs } else { self.base_secs + now.saturating_sub(self.base_time) }
+    }
+
+    // Seconds, minutes, hours, day low, and day high register values at the given time.
+    fn registers(&self, now: u64) -> [u8; 5] {
+        let secs = self.secs(now);
+        let days = secs / 86400;
+        let day_high = ((days >> 8) & 0x01) as u8
+            | if self.halted { 0x40 } else { 0x00 }
+            | if days > 0x1ff { 0x80 } else { 0x00 };
+        [(secs % 60) as u8, (secs / 60 % 60) as u8, (secs / 3600 % 24) as u8, days as u8, day_high]
+    }
+
+    fn write(&mut self, reg: usize, val: u8, now: u64) {
+        let mut regs = self.registers(now);
+        regs[reg] = val;
+        self.set(regs, now);
+    }
+
+    // Set all five registers at the given time. The clock keeps running from the new value.
+    fn set(&mut self, regs: [u8; 5], now: u64) {
+        let days = (regs[4] as u64 & 0x01) << 8 | regs[3] as u64;
+        let days = if regs[4] & 0x80 != 0 { days + 0x200 } else { days };
+        self.base_secs = days * 86400 + (regs[2] as u64 % 24) * 3600 + (regs[1] as u64 % 60) * 60
+            + regs[0] as u64 % 60;
+        self.base_time = now;
+        self.halted = regs[4] & 0x40 != 0;
+    }
+
+    fn save(&self, now: u64, unix_time: u64) -> [u8; RTC_SAVE_LEN] {
+        let mut data = [0; RTC_SAVE_LEN];
+        for (i, &reg) in self.registers(now).iter().chain(self.latched.iter()).enumerate() {
+            data[i * 4] = reg;
+        }
+        data[40..].copy_from_slice(&unix_time.to_le_bytes());
+        data
+    }
+
+    // Restore the clock, then run it forward by the real time since it was saved. A timestamp of
+    // 0, from a deterministic run, or a unix_time of None skips that.
+    fn load(&mut self, data: &[u8], now: u64, unix_time: Option<u64>) {
+        let reg = |i: usize| data[i * 4];
+        self.set([reg(0), reg(1), reg(2), reg(3), reg(4)], now);
+        for i in 0..5 {
+            self.latched[i] = reg(i + 5);
+        }
+
+        let mut saved = [0; 8];
+        saved.copy_from_slice(&data[40..RTC_SAVE_LEN]);
+        let saved = u64::from_le_bytes(saved);
+        if let (false, Some(unix_time), true) = (self.halted, unix_time, saved != 0) {
+            self.base_secs += unix_time.saturating_sub(saved);
+        }
+    }
+}
+
+pub struct Mbc {
+    kind: MbcKind,
+    rom_bank: usize, // Bank register for [0x4000, 0x8000). For MBC1, only the lower 5 bits.
+    ram_bank: usize, // RAM bank register. For MBC1, also the upper 2 ROM bank bits.
+    mode: bool,      // MBC1 banking mode: if set, ram_bank also applies to [0x0000, 0x4000) and RAM.
+    rtc: Option<Rtc>, // MBC3 real-time clock, if the cart has one.
+}
+
+impl Mbc {
+    pub fn new(kind: MbcKind) -> Mbc {
+        Mbc {
+            kind,
+            rom_bank: 1,
+            ram_bank: 0,
+            mode: false,
+            rtc: None,
+        }
+    }
+
+    // Add a real-time clock, for MBC3 carts that have one.
+    pub fn enable_rtc(&mut self) {
+        self.rtc = Some(Rtc::new());
+    }
+
+    // Handle a write to [0x2000, 0x8000) at the given time in seconds. Writes below that are the
+    // RAM enable register.
+    pub fn write(&mut self, addr: u16, val: u8, now: u64) {
+        if let (Some(rtc), 0x6000..=0x7fff) = (&mut self.rtc, addr) {
+            if rtc.latch_armed && val == 0x01 {
+                rtc.latched = rtc.registers(now);
+            }
+            rtc.latch_armed = val == 0x00;
+        }
+
+        let val = val as usize;
+        match (self.kind, addr) {
+            (MbcKind::Mbc1, 0x2000..=0x3fff) => self.rom_bank = (val & 0x1f).max(1),
+            (MbcKind::Mbc1, 0x4000..=0x5fff) => self.ram_bank = val & 0x03,
+            (MbcKind::Mbc1, 0x6000..=0x7fff) => self.mode = (val & 0x01) != 0,
+            (MbcKind::Mbc3, 0x2000..=0x3fff) => self.rom_bank = (val & 0x7f).max(1),
+            (MbcKind::Mbc3, 0x4000..=0x5fff) => self.ram_bank = val & 0x0f,
+            (MbcKind::Mbc5, 0x2000..=0x2fff) => self.rom_bank = (self.rom_bank & 0x100) | val,
+            (MbcKind::Mbc5, 0x3000..=0x3fff) => self.rom_bank = (self.rom_bank & 0xff) | ((val & 0x01) << 8),
+            (MbcKind::Mbc5, 0x4000..=0x5fff) => self.ram_bank = val & 0x0f,
+            _ => (),
+        }
+    }
+
+    // The bank registers and MBC1 mode, for save states.
+    pub fn banks(&self) -> (usize, usize, bool) {
+        (self.rom_bank, self.ram_bank, self.mode)
+    }
+
+    pub fn set_banks(&mut self, rom_bank: usize, ram_bank: usize, mode: bool) {
+        self.rom_bank = rom_bank;
+        self.ram_bank = ram_bank;
+        self.mode = mode;
+    }
+
+    // The ROM bank currently mapped at [0x4000, 0x8000).
+    pub fn rom_bank(&self) -> usize {
+        match self.kind {
+            MbcKind::RomOnly => 1,
+            MbcKind::Mbc1 => (self.ram_bank << 5) | self.rom_bank,
+            _ => self.rom_bank,
+        }
+    }
+
+    // Offset into the ROM for an address in [0x0000, 0x8000).
+    pub fn rom_offset(&self, addr: u16) -> usize {
+        let a = addr as usize;
+        if a < 0x4000 {
+            let bank = if self.kind == MbcKind::Mbc1 && self.mode { self.ram_bank << 5 } else { 0 };
+            bank * 0x4000 + a
+        } else {
+            self.rom_bank() * 0x4000 + (a - 0x4000)
+        }
+    }
+
+    // Offset into the external RAM for an address in [0xA000, 0xC000).
+    pub fn ram_offset(&self, addr: u16) -> usize {
+        let bank = match self.kind {
+            MbcKind::Mbc1 if !self.mode => 0,
+            MbcKind::RomOnly => 0,
+            MbcKind::Mbc3 => self.ram_bank & 0x03,
+            _ => self.ram_bank,
+        };
+        bank * 0x2000 + (addr as usize - 0xa000)
+    }
+
+    // The RTC register [0, 4] mapped to [0xA000, 0xC000), if one is selected instead of RAM.
+    pub fn rtc_register(&self) -> Option<usize> {
+        match (&self.rtc, self.ram_bank) {
+            (Some(_), 0x08..=0x0c) => Some(self.ram_bank - 0x08),
+            _ => None,
+        }
+    }
+
+    // Read an RTC register, as of the last latch.
+    pub fn read_rtc(&self, reg: usize) -> u8 {
+        self.rtc.as_ref().map_or(0xff, |rtc| rtc.latched[reg])
+    }
+
+    // Set an RTC register at the given time in seconds. The clock keeps running from the new value.
+    pub fn write_rtc(&mut self, reg: usize, val: u8, now: u64) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.write(reg, val, now);
+        }
+    }
+
+    pub fn has_rtc(&self) -> bool {
+        self.rtc.is_some()
+    }
+
+    // The clock's state at the given time, for the end of a save file, if there's a clock.
+    pub fn save_rtc(&self, now: u64, unix_time: u64) -> Option<[u8; RTC_SAVE_LEN]> {
+        self.rtc.as_ref().map(|rtc| rtc.save(now, unix_time))
+    }
+
+    // Restore the clock from RTC_SAVE_LEN bytes written by save_rtc, catching up on the time since
+    // if unix_time is given.
+    pub fn load_rtc(&mut self, data: &[u8], now: u64, unix_time: Option<u64>) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load(data, now, unix_time);
+        }
+    }
+}
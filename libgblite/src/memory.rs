@@ -1,13 +1,116 @@
 #![allow(dead_code)]
 
+use std::cell::Cell;
 use std::fs;
 use std::io;
+use std::time::Duration;
+
+use crate::util;
+use crate::clock::Clock;
+use crate::joypad::{Button, Joypad};
+use crate::mbc::{Mbc, MbcKind, RTC_SAVE_LEN};
+use crate::palette::PaletteRam;
+use crate::patch;
+use crate::serial::{Serial, Transport, SB_ADDR, SC_ADDR};
+use crate::state::StateReader;
+use crate::timer::{Timer, DIV_ADDR, TAC_ADDR};
 
 pub struct Memory {
     mem:  Vec<u8>,
-    rom:  Vec<u8>
+    rom:  Vec<u8>,
+    boot_rom: Vec<u8>,     // Mapped over the start of the ROM until the game writes to 0xFF50.
+    boot_rom_mapped: bool,
+    mbc:  Mbc,
+    ext_ram: Vec<u8>,      // Cartridge RAM, sized by the ROM header. Empty for RAM-less carts.
+    ext_ram_gated:  bool, // If true, external RAM is only accessible after the enable write.
+    ext_ram_enabled: bool, // Set by writing 0x0A to [0x0000, 0x2000).
+    ext_ram_dirty: bool,   // External RAM was written since the last save.
+    joypad: Joypad,
+    serial: Serial,
+    obj_palettes: PaletteRam, // CGB sprite palettes, accessed through OCPS/OCPD.
+    timer: Timer,          // DIV, TIMA, TMA, and TAC.
+    model: Model,          // The hardware. CGB features also need a game that supports them.
+    double_speed: bool,    // CGB speed, KEY1 bit 7. Only recorded, all timing is still single speed.
+    rom_cheats: Vec<(u16, u8, Option<u8>)>, // Game Genie patches: address, value, and the byte replaced.
+    dma: Option<(u16, u64)>, // OAM DMA in progress: source address and clock cycles run so far.
+    dma_bus_conflicts: bool, // If true, the CPU only sees HRAM and I/O while DMA runs.
+    access_counts: Option<[(Cell<u64>, u64); 7]>, // CPU reads and writes per region, if enabled.
+    sram_watch: bool,      // Record the next write to external RAM, then stop watching.
+    sram_write: Option<u16>, // Address of the watched write, until it's taken.
+    clock: Clock,          // Time source for the cartridge RTC.
+}
+
+// Interrupt flag (IF) and interrupt enable (IE) registers. Bits 0-4 are, in priority order:
+// VBlank, LCD STAT, Timer, Serial, and Joypad.
+pub const P1_ADDR: u16 = 0xff00;
+pub const LCDC_ADDR: u16 = 0xff40;
+pub const STAT_ADDR: u16 = 0xff41;
+pub const BOOT_ADDR: u16 = 0xff50;
+pub const DMA_ADDR: u16 = 0xff46;
+pub const KEY1_ADDR: u16 = 0xff4d; // CGB speed switch: bit 7 is the current speed, bit 0 arms a switch.
+pub const RP_ADDR: u16 = 0xff56; // CGB infrared port: bit 0 drives the LED, bits 6-7 enable reading.
+pub const OCPS_ADDR: u16 = 0xff6a;
+pub const OCPD_ADDR: u16 = 0xff6b;
+pub const IF_ADDR: u16 = 0xff0f;
+pub const IE_ADDR: u16 = 0xffff;
+
+// I/O registers that only exist on CGB: KEY1, VBK, HDMA1-5, RP, BCPS/BCPD, OCPS/OCPD, OPRI, and
+// SVBK. On DMG they read as 0xFF and ignore writes.
+const CGB_REGS: [u16; 14] = [KEY1_ADDR, 0xff4f, 0xff51, 0xff52, 0xff53, 0xff54, 0xff55, RP_ADDR,
+                             0xff68, 0xff69, OCPS_ADDR, OCPD_ADDR, 0xff6c, 0xff70];
+
+// How a save file's length matched the cartridge RAM it was loaded into.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SaveFit {
+    Exact,
+    Padded,    // The file was short, the rest of RAM is zeroed.
+    Truncated, // The file was long, the extra bytes were ignored.
+}
+
+// The hardware being emulated. A CGB runs DMG games in compatibility mode, without its CGB-only
+// registers and palettes, so CGB features need both a CGB and a CGB game.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Model {
+    Dmg,
+    Cgb,
+}
+
+// Power-on contents of the internal RAM regions (VRAM, WRAM, and HRAM).
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum RamInit {
+    Zero,
+    Random(u64), // Pseudo-random contents from the given seed, so runs are reproducible.
+    Pattern(u8),
+}
+
+// Bytes in a memory save state before the address space: the bank registers and flags, then the
+// timer, serial port, joypad select, OAM DMA, sprite palettes, and the clock's cycle count.
+const STATE_HEADER_LEN: usize = 12 + 11 + 1 + 11 + 65 + 8;
+
+// OAM DMA copies 160 bytes, one per machine cycle.
+const DMA_LEN: u64 = 0xa0;
+const OAM_ADDR: u16 = 0xfe00;
+
+// Regions for access statistics, indexed by access_region.
+pub const REGION_NAMES: [&str; 7] = ["ROM", "VRAM", "SRAM", "WRAM", "OAM", "I/O", "HRAM"];
+
+// Echo RAM counts as WRAM and the unusable area as OAM, since that's what they sit beside.
+fn access_region(addr: u16) -> usize {
+    match addr {
+        0x0000..=0x7fff => 0,
+        0x8000..=0x9fff => 1,
+        0xa000..=0xbfff => 2,
+        0xc000..=0xfdff => 3,
+        0xfe00..=0xfeff => 4,
+        0xff80..=0xfffe => 6,
+        _ => 5,
+    }
 }
 
+// Internal divider counter value on a DMG after the boot ROM hands off, so DIV reads 0xAB. CGB
+// isn't handled: its boot ROM runs for a different time, but every model starts from this value.
+const DMG_DIV_POST_BOOT: u16 = 0xabcc;
+
 pub enum MemClient {
     CPU,
     PPU
@@ -16,40 +119,591 @@ pub enum MemClient {
 impl Memory {
     pub fn new(size: usize) -> Memory {
 
-        let mut v = vec![0; size];
-        v[0xff50] = 1;
-
         Memory {
-            mem:  v,
-            rom:  Vec::new()
+            mem:  vec![0; size],
+            rom:  Vec::new(),
+            boot_rom: Vec::new(),
+            boot_rom_mapped: false,
+            mbc:  Mbc::new(MbcKind::RomOnly),
+            ext_ram: Vec::new(),
+            ext_ram_gated: true,
+            ext_ram_enabled: false,
+            ext_ram_dirty: false,
+            joypad: Joypad::new(),
+            serial: Serial::new(),
+            obj_palettes: PaletteRam::new(),
+            timer: Timer::new(DMG_DIV_POST_BOOT),
+            model: Model::Cgb,
+            double_speed: false,
+            rom_cheats: Vec::new(),
+            dma: None,
+            dma_bus_conflicts: true,
+            access_counts: None,
+            sram_watch: false,
+            sram_write: None,
+            clock: Clock::new(false),
+        }
+    }
+
+    // Return to the power-on state with RAM filled from init, ready for a new ROM to be loaded.
+    // The settings carry over: the model, serial link, access stats, SRAM write watch, DMA bus
+    // conflicts, external RAM gating, and clock source. So does the boot ROM, but unmapped, since
+    // it isn't rerun.
+    pub fn reset(&mut self, init: RamInit) {
+        let mut fresh = Memory::new(self.mem.len());
+        fresh.model = self.model;
+        fresh.boot_rom = std::mem::take(&mut self.boot_rom);
+        fresh.ext_ram_gated = self.ext_ram_gated;
+        fresh.dma_bus_conflicts = self.dma_bus_conflicts;
+        fresh.access_counts = self.access_counts.take();
+        fresh.sram_watch = self.sram_watch;
+        fresh.serial = std::mem::take(&mut self.serial);
+        fresh.serial.reset();
+        std::mem::swap(&mut fresh.clock, &mut self.clock);
+        fresh.clock.restart();
+        fresh.init_ram(init);
+        *self = fresh;
+    }
+
+    // Fill VRAM, WRAM, and HRAM with their power-on contents.
+    pub fn init_ram(&mut self, init: RamInit) {
+        let mut rng_state = match init {
+            RamInit::Random(seed) => seed,
+            _ => 0,
+        };
+
+        for range in [0x8000..0xa000, 0xc000..0xe000, 0xff80..0xffff].iter().cloned() {
+            for a in range {
+                self.mem[a] = match init {
+                    RamInit::Zero => 0,
+                    RamInit::Random(_) => util::xorshift64(&mut rng_state) as u8,
+                    RamInit::Pattern(val) => val,
+                };
+            }
+        }
+    }
+
+    // Enable or disable DMA bus conflicts. With them off, the CPU can still read and write all of
+    // memory while OAM DMA runs, which is more forgiving than hardware.
+    pub fn set_dma_bus_conflicts(&mut self, conflicts: bool) {
+        self.dma_bus_conflicts = conflicts;
+    }
+
+    // Start counting CPU reads and writes per region. The PPU's accesses aren't counted.
+    pub fn enable_access_stats(&mut self) {
+        self.access_counts = Some(Default::default());
+    }
+
+    // Reads and writes per region, named by REGION_NAMES, or None if stats aren't enabled.
+    pub fn access_stats(&self) -> Option<[(u64, u64); 7]> {
+        self.access_counts.as_ref().map(|counts| counts.each_ref().map(|(r, w)| (r.get(), *w)))
+    }
+
+    // Watch for the first write to enabled external RAM, which is usually the game's save routine.
+    pub fn watch_first_sram_write(&mut self) {
+        self.sram_watch = true;
+    }
+
+    // The address of the first external RAM write, once, after it happens.
+    pub fn take_first_sram_write(&mut self) -> Option<u16> {
+        self.sram_write.take()
+    }
+
+    // Enable or disable the external RAM enable gate. With gating off, external RAM is always
+    // accessible, which some homebrew (and broken emulators) assume.
+    pub fn set_ext_ram_gating(&mut self, gated: bool) {
+        self.ext_ram_gated = gated;
+    }
+
+    fn ext_ram_accessible(&self) -> bool {
+        !self.ext_ram.is_empty() && (!self.ext_ram_gated || self.ext_ram_enabled)
+    }
+
+    // The size of the cartridge RAM in bytes, from the RAM size code at 0x0149 of the header.
+    fn header_ram_size(rom: &[u8]) -> usize {
+        match rom.get(0x149) {
+            Some(0x01) => 0x800,
+            Some(0x02) => 0x2000,
+            Some(0x03) => 0x8000,
+            Some(0x04) => 0x20000,
+            Some(0x05) => 0x10000,
+            _ => 0,
+        }
+    }
+
+    // True if the cartridge type in the header at 0x0147 has a battery backing its RAM.
+    pub fn has_battery(&self) -> bool {
+        matches!(self.rom.get(0x147),
+                 Some(0x03) | Some(0x06) | Some(0x09) | Some(0x0d) | Some(0x0f) | Some(0x10) |
+                 Some(0x13) | Some(0x1b) | Some(0x1e) | Some(0x22) | Some(0xff))
+    }
+
+    // Contents of the cartridge RAM.
+    pub fn ext_ram(&self) -> &[u8] {
+        &self.ext_ram
+    }
+
+    // The battery-backed data for a save file: cartridge RAM, then the MBC3 clock's state if the
+    // cart has one.
+    pub fn save_data(&self) -> Vec<u8> {
+        let mut data = self.ext_ram.clone();
+        let now = self.clock.elapsed().as_secs();
+        if let Some(rtc) = self.mbc.save_rtc(now, self.clock.unix_time().unwrap_or(0)) {
+            data.extend_from_slice(&rtc);
+        }
+        data
+    }
+
+    // True if external RAM was written since the last call.
+    pub fn take_ext_ram_dirty(&mut self) -> bool {
+        std::mem::replace(&mut self.ext_ram_dirty, false)
+    }
+
+    // Restore cartridge RAM from a save file. A file a little off the header's RAM size is
+    // padded with zeroes or truncated, as other emulators append extra data like RTC state. An
+    // empty file, or one more than twice the RAM size, is rejected without touching RAM.
+    pub fn load_ext_ram(&mut self, data: &[u8]) -> Result<SaveFit, String> {
+        let size = self.ext_ram.len();
+
+        // An MBC3 clock's state follows the RAM, see save_data.
+        if self.mbc.has_rtc() && data.len() == size + RTC_SAVE_LEN {
+            let now = self.clock.elapsed().as_secs();
+            self.mbc.load_rtc(&data[size..], now, self.clock.unix_time());
+            self.ext_ram.copy_from_slice(&data[..size]);
+            return Ok(SaveFit::Exact);
+        }
+        if data.is_empty() || data.len() > size * 2 {
+            return Err(format!("save file is {} bytes, but the cartridge has {} bytes of RAM",
+                               data.len(), size));
+        }
+
+        let len = data.len().min(size);
+        self.ext_ram[..len].copy_from_slice(&data[..len]);
+        self.ext_ram[len..].iter_mut().for_each(|b| *b = 0);
+        Ok(match data.len() {
+            n if n < size => SaveFit::Padded,
+            n if n > size => SaveFit::Truncated,
+            _ => SaveFit::Exact,
+        })
+    }
+
+    // The ROM byte visible at the given address in [0x0000, 0x8000), through the MBC.
+    fn rom_byte(&self, addr: u16) -> u8 {
+        if self.rom.is_empty() {
+            0xff
+        } else {
+            self.rom[self.mbc.rom_offset(addr) % self.rom.len()]
+        }
+    }
+
+    // Offset into the cartridge ROM of the byte visible at the given address, or None if the
+    // address isn't mapped to cartridge ROM.
+    pub fn rom_offset(&self, addr: u16) -> Option<usize> {
+        let boot = self.boot_rom_mapped && (addr as usize) < self.boot_rom.len();
+        if addr >= 0x8000 || boot || self.rom.is_empty() {
+            None
+        } else {
+            Some(self.mbc.rom_offset(addr) % self.rom.len())
+        }
+    }
+
+    pub fn rom_len(&self) -> usize {
+        self.rom.len()
+    }
+
+    // The header checksum at 0x14D and the global checksum at 0x14E of the loaded ROM, used to
+    // tell whether a save state belongs to it.
+    pub fn rom_checksums(&self) -> (u8, u16) {
+        let byte = |addr: usize| self.rom.get(addr).copied().unwrap_or(0);
+        (byte(0x14d), ((byte(0x14e) as u16) << 8) | byte(0x14f) as u16)
+    }
+
+    // The address space, cartridge RAM and clock, bank registers, and the state of the timer,
+    // serial port, joypad select, OAM DMA, and CGB sprite palettes, for save states. The ROM
+    // itself and settings like the model aren't included.
+    pub fn save_state(&self) -> Vec<u8> {
+        let (rom_bank, ram_bank, mode) = self.mbc.banks();
+        let (dma_source, dma_ran) = self.dma.unwrap_or((0, 0));
+        let mut state = Vec::with_capacity(self.state_len());
+        state.extend_from_slice(&(rom_bank as u16).to_le_bytes());
+        state.push(ram_bank as u8);
+        state.push(mode as u8);
+        state.push(self.boot_rom_mapped as u8);
+        state.push(self.ext_ram_enabled as u8);
+        state.push(self.double_speed as u8);
+        state.extend_from_slice(&self.timer.state());
+        state.extend_from_slice(&self.serial.state());
+        state.push(self.joypad.read() & 0x30);
+        state.push(self.dma.is_some() as u8);
+        state.extend_from_slice(&dma_source.to_le_bytes());
+        state.extend_from_slice(&dma_ran.to_le_bytes());
+        state.extend_from_slice(&self.obj_palettes.state());
+        state.extend_from_slice(&self.clock.cycles().to_le_bytes());
+        state.extend_from_slice(&self.mem);
+        state.extend_from_slice(&self.ext_ram);
+
+        // A timestamp of 0 restores the clock as it was, without catching up on real time.
+        let now = self.clock.elapsed().as_secs();
+        if let Some(rtc) = self.mbc.save_rtc(now, 0) {
+            state.extend_from_slice(&rtc);
+        }
+        state
+    }
+
+    // The length of a state from save_state for the current memory and cartridge.
+    pub fn state_len(&self) -> usize {
+        let rtc_len = if self.mbc.has_rtc() { RTC_SAVE_LEN } else { 0 };
+        STATE_HEADER_LEN + self.mem.len() + self.ext_ram.len() + rtc_len
+    }
+
+    // Restore a state from save_state. Fails without changing anything if the sizes don't match
+    // the current memory and cartridge.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let expected = self.state_len();
+        if state.len() != expected {
+            return Err(format!("memory state is {} bytes, expected {}", state.len(), expected));
+        }
+
+        let mut r = StateReader::new(state);
+        let rom_bank = r.u16()? as usize;
+        let (ram_bank, mode) = (r.u8()? as usize, r.bool()?);
+        self.mbc.set_banks(rom_bank, ram_bank, mode);
+        self.boot_rom_mapped = r.bool()?;
+        self.ext_ram_enabled = r.bool()?;
+        self.double_speed = r.bool()?;
+        let mut timer = [0; 5];
+        timer.copy_from_slice(r.take(5)?);
+        self.timer.set_state(timer);
+        let mut serial = [0; 11];
+        serial.copy_from_slice(r.take(11)?);
+        self.serial.set_state(serial);
+        self.joypad.write(r.u8()?);
+        let dma_active = r.bool()?;
+        let dma = (r.u16()?, r.u64()?);
+        self.dma = if dma_active { Some(dma) } else { None };
+        let mut palettes = [0; 65];
+        palettes.copy_from_slice(r.take(65)?);
+        self.obj_palettes.set_state(palettes);
+        self.clock.set_cycles(r.u64()?);
+        let (mem_len, ext_ram_len) = (self.mem.len(), self.ext_ram.len());
+        self.mem.copy_from_slice(r.take(mem_len)?);
+        self.ext_ram.copy_from_slice(r.take(ext_ram_len)?);
+
+        // The clock's time has to be restored first, since the RTC counts from it.
+        let now = self.clock.elapsed().as_secs();
+        self.mbc.load_rtc(r.rest(), now, None);
+        Ok(())
+    }
+
+    // The ROM bank currently mapped at [0x4000, 0x8000).
+    pub fn rom_bank(&self) -> usize {
+        self.mbc.rom_bank()
+    }
+
+    // Read len bytes starting at start as the CPU would see them, through banking and access
+    // rules, wrapping past 0xFFFF. Lets tools refresh a whole view under a single lock.
+    pub fn read_range(&self, start: u16, len: usize) -> Vec<u8> {
+        (0..len).map(|i| self.get(start.wrapping_add(i as u16), MemClient::CPU)).collect()
+    }
+
+    // True if OAM DMA keeps the CPU off the bus for this address. HRAM is on its own bus, so DMA
+    // routines run from there, and the I/O registers stay reachable to start and time the copy.
+    fn dma_blocks(&self, addr: u16, client: &MemClient) -> bool {
+        self.dma.is_some() && self.dma_bus_conflicts && matches!(client, MemClient::CPU) && addr < 0xff00
+    }
+
+    pub fn get(&self, addr: u16, client: MemClient) -> u8 {
+        if let (Some(counts), MemClient::CPU) = (&self.access_counts, &client) {
+            let reads = &counts[access_region(addr)].0;
+            reads.set(reads.get() + 1);
         }
+        if self.dma_blocks(addr, &client) {
+            return 0xff;
+        }
+        self.read(addr)
     }
 
-    // TODO: Implement ROM switching and interfaces for different memory bank controllers.
-    pub fn get(&self, addr: u16, _client: MemClient) -> u8 {
+    // Read memory without any DMA bus conflict.
+    fn read(&self, addr: u16) -> u8 {
         let a = addr as usize;
-        if a < 0x4000 {
-            self.rom[a]
+        if self.boot_rom_mapped && a < self.boot_rom.len() {
+            self.boot_rom[a]
         } else if a < 0x8000 {
-            self.rom[a]
+            let byte = self.rom_byte(addr);
+            self.rom_cheats.iter()
+                .find(|(at, _, compare)| *at == addr && (compare.is_none() || *compare == Some(byte)))
+                .map_or(byte, |(_, value, _)| *value)
+        } else if (0xa000..0xc000).contains(&a) {
+            if let Some(reg) = self.mbc.rtc_register() {
+                if self.ext_ram_enabled { self.mbc.read_rtc(reg) } else { 0xff }
+            } else if self.ext_ram_accessible() {
+                self.ext_ram[self.mbc.ram_offset(addr) % self.ext_ram.len()]
+            } else {
+                0xff
+            }
+        } else if (0xfea0..0xff00).contains(&a) {
+            // Unusable region between OAM and I/O, reads back as 0x00 on DMG. What a CGB returns
+            // depends on its revision and isn't modelled, so it reads 0x00 there too.
+            0x00
+        } else if addr == P1_ADDR {
+            self.joypad.read()
+        } else if addr == SB_ADDR || addr == SC_ADDR {
+            self.serial.read(addr)
+        } else if (DIV_ADDR..=TAC_ADDR).contains(&addr) {
+            self.timer.read(addr)
+        } else if addr == BOOT_ADDR {
+            // Only bit 0 exists, and it's set once the boot ROM is unmapped.
+            if self.boot_rom_mapped { 0xfe } else { 0xff }
+        } else if CGB_REGS.contains(&addr) && !self.cgb_mode() {
+            0xff
+        } else if addr == KEY1_ADDR {
+            ((self.double_speed as u8) << 7) | 0x7e | (self.mem[a] & 0x01)
+        } else if addr == RP_ADDR {
+            // No IR device is connected, so bit 1 always reads 1 for "no light received".
+            (self.mem[a] & 0xc1) | 0x3e
+        } else if addr == OCPS_ADDR {
+            self.obj_palettes.read_index()
+        } else if addr == OCPD_ADDR {
+            self.obj_palettes.read_data()
+        } else if addr == IF_ADDR {
+            // Only the lower 5 bits of IF exist, the rest always read as 1.
+            self.mem[a] | 0xe0
         } else {
             self.mem[a]
         }
     }
 
-    pub fn set(&mut self, val: u8, addr: u16, _client: MemClient) {
+    pub fn set(&mut self, val: u8, addr: u16, client: MemClient) {
         let a = addr as usize;
-        if a < 0x4000 {
-            self.rom[a] = val;
+        if let (Some(counts), MemClient::CPU) = (&mut self.access_counts, &client) {
+            counts[access_region(addr)].1 += 1;
+        }
+        if self.dma_blocks(addr, &client) {
+            // Writes are lost while DMA holds the bus.
+        } else if a < 0x2000 {
+            // RAM enable register: only a lower nibble of 0xA enables external RAM.
+            self.ext_ram_enabled = (val & 0x0f) == 0x0a;
         } else if a < 0x8000 {
-            self.rom[a] = val;
+            // ROM is read-only, writes only drive the MBC registers.
+            self.mbc.write(addr, val, self.clock.elapsed().as_secs());
+        } else if (0xa000..0xc000).contains(&a) {
+            // Writes to disabled or missing external RAM are dropped.
+            if let Some(reg) = self.mbc.rtc_register() {
+                if self.ext_ram_enabled {
+                    self.mbc.write_rtc(reg, val, self.clock.elapsed().as_secs());
+                }
+            } else if self.ext_ram_accessible() {
+                let len = self.ext_ram.len();
+                self.ext_ram[self.mbc.ram_offset(addr) % len] = val;
+                self.ext_ram_dirty = true;
+                if self.sram_watch {
+                    self.sram_watch = false;
+                    self.sram_write = Some(addr);
+                }
+            }
+        } else if (0xfea0..0xff00).contains(&a) {
+            // Writes to the unusable region are ignored.
+        } else if addr == P1_ADDR {
+            self.joypad.write(val);
+        } else if addr == SB_ADDR || addr == SC_ADDR {
+            self.serial.write(addr, val);
+        } else if (DIV_ADDR..=TAC_ADDR).contains(&addr) {
+            self.timer.write(addr, val);
+        } else if addr == BOOT_ADDR {
+            // Any nonzero write unmaps the boot ROM for good, nothing can map it back.
+            if val != 0 {
+                self.boot_rom_mapped = false;
+            }
+        } else if CGB_REGS.contains(&addr) && !self.cgb_mode() {
+            // Writes to CGB-only registers are ignored on DMG.
+        } else if addr == KEY1_ADDR {
+            self.mem[a] = val & 0x01;
+        } else if addr == RP_ADDR {
+            self.mem[a] = val & 0xc1;
+        } else if addr == OCPS_ADDR {
+            self.obj_palettes.write_index(val);
+        } else if addr == OCPD_ADDR {
+            self.obj_palettes.write_data(val);
+        } else if addr == IF_ADDR {
+            self.mem[a] = val & 0x1f;
+        } else if addr == DMA_ADDR {
+            // The register keeps the source page, and a CPU write restarts any copy in progress.
+            // The PPU writes it back unchanged every tick, which mustn't start a copy.
+            self.mem[a] = val;
+            if matches!(client, MemClient::CPU) {
+                self.dma = Some(((val as u16) << 8, 0));
+            }
+        } else if addr == STAT_ADDR && matches!(client, MemClient::CPU) {
+            // The CPU can only write the interrupt enable bits 3-6. Bit 7 and the coincidence and
+            // mode bits are owned by the PPU.
+            self.mem[a] = (val & 0x78) | (self.mem[a] & 0x87);
         } else {
             self.mem[a] = val;
         }
     }
 
+    // Press or release a joypad button, requesting the joypad interrupt on a new press.
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if self.joypad.set_button(button, pressed) {
+            self.request_interrupt(4);
+        }
+    }
+
+    // Plug a link cable into the serial port.
+    pub fn connect_serial(&mut self, transport: Box<dyn Transport>) {
+        self.serial.connect(transport);
+    }
+
+    // Advance a serial transfer in progress, requesting the Serial interrupt when it completes.
+    pub fn step_serial(&mut self, cycles: u64) {
+        if self.serial.step(cycles) {
+            self.request_interrupt(3);
+        }
+    }
+
+    // Run the timer for the given clock cycles, requesting the Timer interrupt on overflow.
+    pub fn step_timer(&mut self, cycles: u64) {
+        self.timer.step(cycles);
+        if self.timer.take_interrupt() {
+            self.request_interrupt(2);
+        }
+    }
+
+    // Set the given bit [0, 4] in the IF register.
+    pub fn request_interrupt(&mut self, bit: u8) {
+        self.mem[IF_ADDR as usize] |= (1 << bit) & 0x1f;
+    }
+
+    // Clear the given bit [0, 4] in the IF register.
+    pub fn clear_interrupt(&mut self, bit: u8) {
+        self.mem[IF_ADDR as usize] &= !(1 << bit) & 0x1f;
+    }
+
+    // Map a boot ROM over the start of the cartridge ROM. The CPU should start at 0x0000.
+    pub fn load_boot_rom(&mut self, boot_rom: Vec<u8>) {
+        self.boot_rom_mapped = !boot_rom.is_empty();
+        self.boot_rom = boot_rom;
+    }
+
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom_mapped
+    }
+
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+    }
+
+    // True if CGB features are on: the hardware is a CGB, and the cartridge header (0x0143 bit 7)
+    // says the game supports them. Otherwise everything renders through the DMG palettes.
+    pub fn cgb_mode(&self) -> bool {
+        self.model == Model::Cgb && matches!(self.rom.get(0x143), Some(flag) if (flag & 0x80) != 0)
+    }
+
+    // Called by STOP: if a CGB speed switch is armed in KEY1, flip the speed and disarm it.
+    // Returns true if the speed changed.
+    pub fn speed_switch(&mut self) -> bool {
+        let key1 = KEY1_ADDR as usize;
+        if !self.cgb_mode() || self.mem[key1] & 0x01 == 0 {
+            return false;
+        }
+        self.mem[key1] &= !0x01;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    pub(crate) fn obj_palettes(&self) -> &PaletteRam {
+        &self.obj_palettes
+    }
+
     pub fn load_rom_file(&mut self, file_name : &str) {
-        self.rom = fs::read(file_name).unwrap_or(vec![])
+        self.load_rom(fs::read(file_name).unwrap_or(vec![]))
+    }
+
+    // Make reads of the ROM address return value instead, but only where the ROM holds compare,
+    // if one is given.
+    pub fn add_rom_cheat(&mut self, addr: u16, value: u8, compare: Option<u8>) {
+        self.rom_cheats.push((addr, value, compare));
+    }
+
+    pub fn load_rom(&mut self, rom: Vec<u8>) {
+        let cart_type = rom.get(0x147).copied().unwrap_or(0);
+        self.ext_ram = vec![0; Memory::header_ram_size(&rom)];
+        self.mbc = Mbc::new(MbcKind::from_cart_type(cart_type));
+        if cart_type == 0x0f || cart_type == 0x10 {
+            self.mbc.enable_rtc();
+        }
+        self.rom = rom;
+    }
+
+    // Apply an IPS patch file to the loaded ROM. Fails without changing the ROM if the patch
+    // can't be read or is malformed.
+    pub fn apply_ips(&mut self, path: &str) -> io::Result<()> {
+        let ips = fs::read(path)?;
+        let mut rom = self.rom.clone();
+        patch::apply_ips(&mut rom, &ips)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        // The patch may change the header, so reload the cartridge settings too.
+        self.load_rom(rom);
+        Ok(())
+    }
+
+    // Drive time from emulated cycles instead of the system clock, so runs are reproducible.
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.clock = Clock::new(deterministic);
+    }
+
+    // Advance emulated time by the given number of clock cycles.
+    pub fn advance_clock(&mut self, cycles: u64) {
+        self.clock.advance(cycles);
+        self.step_dma(cycles);
+    }
+
+    // Copy the OAM DMA bytes due in the given clock cycles, ending the transfer after the last.
+    fn step_dma(&mut self, cycles: u64) {
+        let (source, ran) = match self.dma {
+            Some(dma) => dma,
+            None => return,
+        };
+        let done = ((ran + cycles) / 4).min(DMA_LEN);
+        for i in (ran / 4)..done {
+            // Sources above WRAM read its echo, as the upper pages aren't on the DMA bus.
+            let mut src = source + i as u16;
+            if src >= 0xe000 { src -= 0x2000; }
+            self.mem[(OAM_ADDR + i as u16) as usize] = self.dma_read(src);
+        }
+        self.dma = if done < DMA_LEN { Some((source, ran + cycles)) } else { None };
+    }
+
+    // Read a DMA source byte. While the PPU draws it owns VRAM, so a copy from VRAM gets 0xFF,
+    // the same as the CPU would. ROM, cartridge RAM, and WRAM are always readable.
+    fn dma_read(&self, addr: u16) -> u8 {
+        let lcd_on = self.mem[LCDC_ADDR as usize] & 0x80 != 0;
+        let drawing = self.mem[STAT_ADDR as usize] & 0x03 == 3;
+        if (0x8000..0xa000).contains(&addr) && lcd_on && drawing {
+            0xff
+        } else {
+            self.read(addr)
+        }
+    }
+
+    // True while OAM DMA is copying.
+    pub fn dma_active(&self) -> bool {
+        self.dma.is_some()
+    }
+
+    // Emulated clock cycles elapsed since the machine started.
+    pub fn clock_cycles(&self) -> u64 {
+        self.clock.cycles()
+    }
+
+    // Time elapsed since the machine started, real or emulated.
+    pub fn elapsed(&self) -> Duration {
+        self.clock.elapsed()
     }
 
     // For debug use only: do a hex dump of the contents of our ROM cartridge.
@@ -99,3 +753,356 @@ impl Memory {
         print!("{}", mem_dump);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Build a blank 32 KiB ROM with the given cartridge type and RAM size code in its header.
+    fn rom_with_header(cart_type: u8, ram_size: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = cart_type;
+        rom[0x149] = ram_size;
+        rom
+    }
+
+    #[test]
+    fn access_stats_count_cpu_accesses() {
+        let mut mem = Memory::new(0x10000);
+        mem.load_rom(vec![0; 0x8000]);
+        assert_eq!(mem.access_stats(), None);
+        mem.enable_access_stats();
+
+        mem.get(0x0150, MemClient::CPU);
+        mem.get(0x4000, MemClient::CPU);
+        mem.set(0x12, 0x8000, MemClient::CPU);
+        mem.set(0x12, 0x9fff, MemClient::CPU);
+        mem.get(0x9fff, MemClient::CPU);
+        mem.set(0x34, 0xc000, MemClient::CPU);
+        mem.get(0xe000, MemClient::CPU);
+        mem.set(0x00, 0xfe00, MemClient::CPU);
+        mem.get(0xff44, MemClient::CPU);
+        mem.set(0x00, 0xffff, MemClient::CPU);
+        mem.set(0x56, 0xff80, MemClient::CPU);
+        mem.get(0xfffe, MemClient::CPU);
+        mem.get(0x8000, MemClient::PPU);
+        mem.set(0x00, 0xff41, MemClient::PPU);
+
+        assert_eq!(mem.access_stats(), Some([(2, 0), (1, 2), (0, 0), (1, 1), (0, 1), (1, 1), (1, 1)]));
+    }
+
+    #[test]
+    fn read_range_matches_get() {
+        let mut mem = Memory::new(0x10000);
+        mem.load_rom(rom_with_header(0x03, 0x02)); // MBC1 with RAM
+        mem.set(0x0a, 0x0000, MemClient::CPU);
+        for addr in 0..0x10000 {
+            mem.set(addr as u8 ^ 0x5a, addr as u16, MemClient::CPU);
+        }
+
+        for &(start, len) in &[(0x0000u16, 0x100), (0x9ff0, 0x20), (0xfe90, 0x80), (0xfff0, 0x20)] {
+            let expected: Vec<u8> = (0..len)
+                .map(|i| mem.get(start.wrapping_add(i as u16), MemClient::CPU))
+                .collect();
+            assert_eq!(mem.read_range(start, len), expected);
+        }
+    }
+
+    // Run a whole DMA from the given page, with the PPU in the given STAT mode.
+    fn dma_with_mode(mem: &mut Memory, page: u8, mode: u8) -> Vec<u8> {
+        mem.set(0x80 | mode, STAT_ADDR, MemClient::PPU);
+        mem.set(page, DMA_ADDR, MemClient::CPU);
+        mem.advance_clock(DMA_LEN * 4);
+        assert!(!mem.dma_active());
+        mem.read_range(OAM_ADDR, DMA_LEN as usize)
+    }
+
+    #[test]
+    fn dma_source_access() {
+        let mut mem = Memory::new(0x10000);
+        mem.set(0x91, LCDC_ADDR, MemClient::CPU);
+        for i in 0..DMA_LEN as u16 {
+            mem.set(i as u8, 0xc000 + i, MemClient::CPU);
+            mem.set(!i as u8, 0x8000 + i, MemClient::CPU);
+        }
+
+        // WRAM copies in every mode.
+        for mode in 0..4 {
+            assert_eq!(dma_with_mode(&mut mem, 0xc0, mode), mem.read_range(0xc000, DMA_LEN as usize));
+        }
+
+        // VRAM is only readable while the PPU isn't drawing.
+        assert_eq!(dma_with_mode(&mut mem, 0x80, 0), mem.read_range(0x8000, DMA_LEN as usize));
+        assert!(dma_with_mode(&mut mem, 0x80, 3).iter().all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn save_file_sizes() {
+        let mut mem = Memory::new(0x10000);
+        mem.load_rom(rom_with_header(0x03, 0x02)); // MBC1 with 8 KiB of RAM and a battery
+
+        assert_eq!(mem.load_ext_ram(&[0x5a; 0x2000]), Ok(SaveFit::Exact));
+        assert_eq!(mem.load_ext_ram(&[0x11; 0x1000]), Ok(SaveFit::Padded));
+        assert_eq!(mem.ext_ram()[0xfff], 0x11);
+        assert_eq!(mem.ext_ram()[0x1000], 0x00);
+
+        // An RTC footer on the end is just dropped.
+        assert_eq!(mem.load_ext_ram(&[0x22; 0x2030]), Ok(SaveFit::Truncated));
+        assert!(mem.ext_ram().iter().all(|b| *b == 0x22));
+
+        assert!(mem.load_ext_ram(&[0x33; 0x8000]).is_err());
+        assert!(mem.load_ext_ram(&[]).is_err());
+        assert!(mem.ext_ram().iter().all(|b| *b == 0x22));
+    }
+
+    #[test]
+    fn ips_patch_applied() {
+        let mut mem = Memory::new(0x10000);
+        mem.load_rom(rom_with_header(0x00, 0x00));
+        assert_eq!(mem.get(0x1234, MemClient::CPU), 0x00);
+
+        let path = std::env::temp_dir().join(format!("gblite_ips_test_{}.ips", std::process::id()));
+        fs::write(&path, b"PATCH\x00\x12\x34\x00\x01\x42EOF").unwrap();
+        mem.apply_ips(path.to_str().unwrap()).unwrap();
+        assert_eq!(mem.get(0x1234, MemClient::CPU), 0x42);
+
+        fs::write(&path, b"PATCH\x00\x12\x34\x00\x02\x99").unwrap();
+        assert!(mem.apply_ips(path.to_str().unwrap()).is_err());
+        assert_eq!(mem.get(0x1234, MemClient::CPU), 0x42);
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn cgb_registers_absent_on_dmg() {
+        let mut mem = Memory::new(0x10000);
+        mem.load_rom(rom_with_header(0x00, 0x00));
+        for &addr in CGB_REGS.iter() {
+            mem.set(0x01, addr, MemClient::CPU);
+            assert_eq!(mem.get(addr, MemClient::CPU), 0xff);
+        }
+
+        let mut rom = rom_with_header(0x00, 0x00);
+        rom[0x143] = 0x80;
+        mem.load_rom(rom);
+        mem.set(0x01, 0xff4f, MemClient::CPU);
+        assert_eq!(mem.get(0xff4f, MemClient::CPU), 0x01);
+    }
+
+    #[test]
+    fn infrared_port_without_device() {
+        let mut rom = rom_with_header(0x00, 0x00);
+        rom[0x143] = 0x80;
+        let mut mem = Memory::new(0x10000);
+        mem.load_rom(rom);
+        assert_eq!(mem.get(RP_ADDR, MemClient::CPU), 0x3e);
+
+        // Turning on the LED and enabling reads still receives nothing.
+        mem.set(0xff, RP_ADDR, MemClient::CPU);
+        assert_eq!(mem.get(RP_ADDR, MemClient::CPU), 0xff);
+        mem.set(0xc0, RP_ADDR, MemClient::CPU);
+        assert_eq!(mem.get(RP_ADDR, MemClient::CPU), 0xfe);
+    }
+
+    #[test]
+    fn ext_ram_enable_gate() {
+        let mut mem = Memory::new(0x10000);
+        mem.load_rom(rom_with_header(0x03, 0x02));
+
+        // Before enabling, writes are dropped and reads return 0xFF.
+        mem.set(0x42, 0xa000, MemClient::CPU);
+        assert_eq!(mem.get(0xa000, MemClient::CPU), 0xff);
+
+        mem.set(0x0a, 0x0000, MemClient::CPU);
+        mem.set(0x42, 0xa000, MemClient::CPU);
+        assert_eq!(mem.get(0xa000, MemClient::CPU), 0x42);
+
+        // Any other value disables RAM again, but the contents are retained.
+        mem.set(0x00, 0x1fff, MemClient::CPU);
+        assert_eq!(mem.get(0xa000, MemClient::CPU), 0xff);
+        mem.set(0x0a, 0x1000, MemClient::CPU);
+        assert_eq!(mem.get(0xa000, MemClient::CPU), 0x42);
+    }
+
+    #[test]
+    fn rom_writes_only_switch_banks() {
+        // 64 KiB MBC1 ROM, with each bank's first byte set to its bank number.
+        let mut rom = rom_with_header(0x01, 0x00);
+        rom.resize(0x10000, 0);
+        for bank in 0..4 {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        rom[0x100] = 0x00;
+        let mut mem = Memory::new(0x10000);
+        mem.load_rom(rom);
+
+        mem.set(0xc3, 0x0100, MemClient::CPU);
+        assert_eq!(mem.get(0x0100, MemClient::CPU), 0x00);
+        assert_eq!(mem.get(0x4000, MemClient::CPU), 1);
+
+        mem.set(0x02, 0x2000, MemClient::CPU);
+        assert_eq!(mem.rom_bank(), 2);
+        assert_eq!(mem.get(0x4000, MemClient::CPU), 2);
+
+        // Bank 0 can't be mapped to the upper region, it selects bank 1 instead.
+        mem.set(0x00, 0x3fff, MemClient::CPU);
+        assert_eq!(mem.get(0x4000, MemClient::CPU), 1);
+    }
+
+    #[test]
+    fn mbc3_rtc_latch() {
+        let mut mem = Memory::new(0x10000);
+        mem.set_deterministic(true);
+        mem.load_rom(rom_with_header(0x10, 0x03));
+        mem.set(0x0a, 0x0000, MemClient::CPU);
+
+        // 1 day, 1 hour, 1 minute, and 5 seconds.
+        mem.advance_clock(90065 * 4_194_304);
+        mem.set(0x08, 0x4000, MemClient::CPU);
+        assert_eq!(mem.get(0xa000, MemClient::CPU), 0x00);
+        mem.set(0x00, 0x6000, MemClient::CPU);
+        mem.set(0x01, 0x6000, MemClient::CPU);
+
+        let mut regs = Vec::new();
+        for reg in 0x08..=0x0c {
+            mem.set(reg, 0x4000, MemClient::CPU);
+            regs.push(mem.get(0xa000, MemClient::CPU));
+        }
+        assert_eq!(regs, [5, 1, 1, 1, 0]);
+
+        // Latched values hold until the next latch, and RAM bank 0 is still plain RAM.
+        mem.advance_clock(4_194_304);
+        mem.set(0x08, 0x4000, MemClient::CPU);
+        assert_eq!(mem.get(0xa000, MemClient::CPU), 5);
+        mem.set(0x00, 0x4000, MemClient::CPU);
+        mem.set(0x42, 0xa000, MemClient::CPU);
+        assert_eq!(mem.get(0xa000, MemClient::CPU), 0x42);
+    }
+
+    #[test]
+    fn rtc_saved_with_ext_ram() {
+        let mut mem = Memory::new(0x10000);
+        mem.set_deterministic(true);
+        mem.load_rom(rom_with_header(0x10, 0x03));
+        mem.set(0x0a, 0x0000, MemClient::CPU);
+        mem.set(0x5a, 0xa000, MemClient::CPU);
+        mem.advance_clock(3725 * 4_194_304); // 1 hour, 2 minutes, and 5 seconds.
+        let data = mem.save_data();
+        assert_eq!(data.len(), 0x8000 + RTC_SAVE_LEN);
+
+        let latch = |mem: &mut Memory| {
+            mem.set(0x00, 0x6000, MemClient::CPU);
+            mem.set(0x01, 0x6000, MemClient::CPU);
+            (0x08..=0x0c).map(|reg| {
+                mem.set(reg, 0x4000, MemClient::CPU);
+                mem.get(0xa000, MemClient::CPU)
+            }).collect::<Vec<u8>>()
+        };
+
+        // A deterministic clock picks up where it was saved.
+        let mut restored = Memory::new(0x10000);
+        restored.set_deterministic(true);
+        restored.load_rom(rom_with_header(0x10, 0x03));
+        assert_eq!(restored.load_ext_ram(&data), Ok(SaveFit::Exact));
+        assert_eq!(restored.ext_ram()[0], 0x5a);
+        restored.set(0x0a, 0x0000, MemClient::CPU);
+        assert_eq!(latch(&mut restored), [5, 2, 1, 0, 0]);
+
+        // A real one also counts the time since, from the save's timestamp.
+        let mut data = data;
+        let unix_time = Clock::new(false).unix_time().unwrap();
+        data[0x8000 + 40..].copy_from_slice(&(unix_time - 7200).to_le_bytes());
+        let mut restored = Memory::new(0x10000);
+        restored.load_rom(rom_with_header(0x10, 0x03));
+        assert_eq!(restored.load_ext_ram(&data), Ok(SaveFit::Exact));
+        restored.set(0x0a, 0x0000, MemClient::CPU);
+        assert_eq!(latch(&mut restored)[1..], [2, 3, 0, 0]);
+    }
+
+    #[test]
+    fn rom_only_cart_has_no_ext_ram() {
+        let mut mem = Memory::new(0x10000);
+        mem.load_rom(rom_with_header(0x00, 0x00));
+        mem.set(0x0a, 0x0000, MemClient::CPU);
+        mem.set(0x42, 0xa000, MemClient::CPU);
+        assert_eq!(mem.get(0xa000, MemClient::CPU), 0xff);
+        assert_eq!(mem.get(0xbfff, MemClient::CPU), 0xff);
+    }
+
+    #[test]
+    fn ram_init_random_is_deterministic() {
+        let mut a = Memory::new(0x10000);
+        let mut b = Memory::new(0x10000);
+        a.init_ram(RamInit::Random(1234));
+        b.init_ram(RamInit::Random(1234));
+        assert!(a.mem == b.mem);
+        assert!(a.mem[0xc000..0xe000].iter().any(|&x| x != 0));
+
+        b.init_ram(RamInit::Random(4321));
+        assert!(a.mem != b.mem);
+
+        a.init_ram(RamInit::Pattern(0xa5));
+        assert_eq!(a.get(0x8000, MemClient::CPU), 0xa5);
+        assert_eq!(a.get(0xdfff, MemClient::CPU), 0xa5);
+        assert_eq!(a.get(0xfffe, MemClient::CPU), 0xa5);
+    }
+
+    #[test]
+    fn if_upper_bits() {
+        let mut mem = Memory::new(0x10000);
+        assert_eq!(mem.get(IF_ADDR, MemClient::CPU), 0xe0);
+
+        mem.request_interrupt(0);
+        assert_eq!(mem.get(IF_ADDR, MemClient::CPU), 0xe1);
+
+        // Writes only keep the lower 5 bits, and clearing one interrupt leaves the others.
+        mem.set(0xff, IF_ADDR, MemClient::CPU);
+        assert_eq!(mem.get(IF_ADDR, MemClient::CPU), 0xff);
+        mem.clear_interrupt(0);
+        assert_eq!(mem.get(IF_ADDR, MemClient::CPU), 0xfe);
+        mem.set(0x00, IF_ADDR, MemClient::CPU);
+        assert_eq!(mem.get(IF_ADDR, MemClient::CPU), 0xe0);
+    }
+
+    #[test]
+    fn unusable_region_reads_zero() {
+        let mut mem = Memory::new(0x10000);
+        mem.set_model(Model::Dmg);
+        mem.set(0x55, 0xfeb0, MemClient::CPU);
+        assert_eq!(mem.get(0xfeb0, MemClient::CPU), 0x00);
+        assert_eq!(mem.mem[0xfeb0], 0x00);
+    }
+
+    #[test]
+    fn boot_rom_unmap_is_permanent() {
+        let mut rom = rom_with_header(0x00, 0x00);
+        rom[0x0000] = 0x55;
+        let mut mem = Memory::new(0x10000);
+        mem.load_rom(rom);
+        mem.load_boot_rom(vec![0xaa; 0x100]);
+        assert_eq!(mem.get(0x0000, MemClient::CPU), 0xaa);
+        assert_eq!(mem.get(0x0100, MemClient::CPU), 0x00);
+        assert_eq!(mem.get(BOOT_ADDR, MemClient::CPU), 0xfe);
+
+        mem.set(0x01, BOOT_ADDR, MemClient::CPU);
+        assert_eq!(mem.get(0x0000, MemClient::CPU), 0x55);
+        assert_eq!(mem.get(BOOT_ADDR, MemClient::CPU), 0xff);
+
+        // Later writes can't map the boot ROM back in.
+        mem.set(0x00, BOOT_ADDR, MemClient::CPU);
+        mem.set(0x01, BOOT_ADDR, MemClient::CPU);
+        assert_eq!(mem.get(0x0000, MemClient::CPU), 0x55);
+        assert_eq!(mem.get(BOOT_ADDR, MemClient::CPU), 0xff);
+    }
+
+    #[test]
+    fn div_post_boot_and_reset() {
+        let mut mem = Memory::new(0x10000);
+        mem.set_model(Model::Dmg);
+        assert_eq!(mem.get(DIV_ADDR, MemClient::CPU), 0xab);
+
+        mem.set(0x12, DIV_ADDR, MemClient::CPU);
+        assert_eq!(mem.get(DIV_ADDR, MemClient::CPU), 0x00);
+        mem.step_timer(255);
+        assert_eq!(mem.get(DIV_ADDR, MemClient::CPU), 0x00);
+    }
+}
@@ -0,0 +1,63 @@
+// CGB color palette RAM. Each of the eight palettes holds four 15-bit colors, stored little
+// endian as xBBBBBGGGGGRRRRR. The game selects a byte with the index register (BCPS/OCPS), where
+// bit 7 makes the index advance after each write, then reads and writes it through the data
+// register (BCPD/OCPD).
+
+#[derive(Clone)]
+pub struct PaletteRam {
+    data: [u8; 64],
+    index: u8,         // Byte index [0, 63] selected by the index register.
+    auto_inc: bool,    // Index register bit 7: advance the index after each data write.
+}
+
+impl PaletteRam {
+    pub fn new() -> PaletteRam {
+        PaletteRam {
+            data: [0; 64],
+            index: 0,
+            auto_inc: false,
+        }
+    }
+
+    // Bit 6 of the index register is unused and always reads as 1.
+    pub fn read_index(&self) -> u8 {
+        (if self.auto_inc { 0x80 } else { 0x00 }) | 0x40 | self.index
+    }
+
+    pub fn write_index(&mut self, val: u8) {
+        self.auto_inc = (val & 0x80) != 0;
+        self.index = val & 0x3f;
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    pub fn write_data(&mut self, val: u8) {
+        self.data[self.index as usize] = val;
+        if self.auto_inc {
+            self.index = (self.index + 1) & 0x3f;
+        }
+    }
+
+    // The palette bytes followed by the index register, for save states.
+    pub fn state(&self) -> [u8; 65] {
+        let mut state = [0; 65];
+        state[..64].copy_from_slice(&self.data);
+        state[64] = self.read_index();
+        state
+    }
+
+    pub fn set_state(&mut self, state: [u8; 65]) {
+        self.data.copy_from_slice(&state[..64]);
+        self.write_index(state[64]);
+    }
+
+    // The given color [0, 3] of the given palette [0, 7], scaled to 8 bits per channel.
+    pub fn rgb(&self, palette: u8, color: u8) -> (u8, u8, u8) {
+        let i = (palette as usize & 0x7) * 8 + (color as usize & 0x3) * 2;
+        let val = self.data[i] as u16 | (self.data[i+1] as u16) << 8;
+        let scale = |c: u16| ((c << 3) | (c >> 2)) as u8;
+        (scale(val & 0x1f), scale((val >> 5) & 0x1f), scale((val >> 10) & 0x1f))
+    }
+}
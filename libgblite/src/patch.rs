@@ -0,0 +1,78 @@
+// ROM patches in the IPS format. A patch is "PATCH" followed by records, then "EOF". Each record
+// is a 3 byte offset and a 2 byte size, both big endian, then that many bytes to write. A size of
+// zero instead marks a run: a 2 byte count and the single byte to repeat. An optional 3 byte
+// length after "EOF" truncates the result. Writes past the end of the ROM extend it with zeroes.
+
+const IPS_HEADER: &[u8] = b"PATCH";
+const IPS_EOF: u32 = 0x454f46; // "EOF" read as an offset.
+
+// Apply the IPS patch to the ROM. On error the ROM may be partially patched.
+pub fn apply_ips(rom: &mut Vec<u8>, patch: &[u8]) -> Result<(), String> {
+    if !patch.starts_with(IPS_HEADER) {
+        return Err(String::from("not an IPS patch, missing the PATCH header"));
+    }
+
+    let mut pos = IPS_HEADER.len();
+    let mut take = |len: usize| -> Result<&[u8], String> {
+        let bytes = patch.get(pos..pos + len)
+            .ok_or_else(|| format!("IPS patch is truncated at byte {}", pos))?;
+        pos += len;
+        Ok(bytes)
+    };
+    let be = |bytes: &[u8]| bytes.iter().fold(0u32, |val, b| (val << 8) | *b as u32);
+
+    loop {
+        let offset = be(take(3)?);
+        if offset == IPS_EOF { break; }
+        let offset = offset as usize;
+
+        let size = be(take(2)?) as usize;
+        let data = if size == 0 {
+            let count = be(take(2)?) as usize;
+            vec![take(1)?[0]; count]
+        } else {
+            take(size)?.to_vec()
+        };
+
+        if rom.len() < offset + data.len() {
+            rom.resize(offset + data.len(), 0);
+        }
+        rom[offset..offset + data.len()].copy_from_slice(&data);
+    }
+
+    // Anything after EOF must be a truncation length, and nothing else.
+    match take(3) {
+        Ok(len) => {
+            let len = be(len) as usize;
+            if take(1).is_ok() {
+                return Err(String::from("IPS patch has trailing data after EOF"));
+            }
+            rom.truncate(len);
+        },
+        Err(_) if patch.len() == pos => (),
+        Err(_) => return Err(String::from("IPS patch has trailing data after EOF")),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_and_truncate() {
+        let mut rom = vec![0; 8];
+        let patch = b"PATCH\x00\x00\x02\x00\x00\x00\x03\xaaEOF\x00\x00\x06";
+        apply_ips(&mut rom, patch).unwrap();
+        assert_eq!(rom, [0x00, 0x00, 0xaa, 0xaa, 0xaa, 0x00]);
+    }
+
+    #[test]
+    fn malformed_patches() {
+        let mut rom = vec![0; 8];
+        assert!(apply_ips(&mut rom, b"PACTH\x00\x00\x00\x00\x01\xffEOF").is_err());
+        assert!(apply_ips(&mut rom, b"PATCH\x00\x00\x00\x00\x04\xff").is_err());
+        assert!(apply_ips(&mut rom, b"PATCHEOF\x00\x00\x08\x00").is_err());
+        assert!(apply_ips(&mut rom, b"PATCH\x00\x00\x00").is_err());
+    }
+}
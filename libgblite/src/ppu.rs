@@ -3,13 +3,16 @@
 
 use crate::util;
 use crate::memory::Memory;
+use crate::state::StateReader;
 use crate::memory::MemClient;
-use crate::window::Window;
+use crate::window::{Window, WindowConfig};
+use crate::RuntimeConfig;
 
+use std::collections::VecDeque;
 use std::fmt::{Display, Formatter, Result};
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::time::Instant;
+use std::time::Duration;
 
 #[derive(Copy, Clone, PartialEq)]
 enum PPUState {
@@ -19,6 +22,29 @@ enum PPUState {
     Draw      = 3, // Draw is the lookup and transfer period of pixels to the LCD.
 }
 
+impl PPUState {
+    fn from_u8(mode: u8) -> Option<PPUState> {
+        match mode {
+            0 => Some(PPUState::HBlank),
+            1 => Some(PPUState::VBlank),
+            2 => Some(PPUState::OAMSearch),
+            3 => Some(PPUState::Draw),
+            _ => None,
+        }
+    }
+}
+
+impl Display for PPUState {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match *self {
+            PPUState::HBlank    => write!(f, "HBlank"),
+            PPUState::VBlank    => write!(f, "VBlank"),
+            PPUState::OAMSearch => write!(f, "OAMSearch"),
+            PPUState::Draw      => write!(f, "Draw"),
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub enum PPUReg {
     Lcdc = 0xFF40,
@@ -33,7 +59,15 @@ pub enum PPUReg {
     Obp1 = 0xFF49,
     Wy   = 0xFF4A,
     Wx   = 0xFF4B,
-    Vbk  = 0xFF4F
+    Vbk  = 0xFF4F,
+    Opri = 0xFF6C
+}
+
+impl PPUReg {
+    // Registers that only exist on CGB, and keep their power-on settings on DMG.
+    fn cgb_only(self) -> bool {
+        matches!(self, PPUReg::Vbk | PPUReg::Opri)
+    }
 }
 
 impl Display for PPUReg {
@@ -52,6 +86,7 @@ impl Display for PPUReg {
             PPUReg::Wy   => write!(f, "WY"),
             PPUReg::Wx   => write!(f, "WX"),
             PPUReg::Vbk  => write!(f, "VBK"),
+            PPUReg::Opri => write!(f, "OPRI"),
         }
     }
 }
@@ -77,39 +112,19 @@ struct PPUConfig {
     ly:  u8,                 // LY register - the current Y line we're rendering.
     lx:  u8,                 // The X pixel we're rendering - this doesn't map to a hardware register.
     lyc: u8,                 // LYC - line Y compare value, used for the LYC interrupt.
-    dma: u8,                 // DMA - function to DMA from generic memory point to OAM RAM.
+    dma: u8,                 // DMA - source page of the last OAM DMA, reads back as written.
     bgp: u8,                 // BGP - background palette
     obp0: u8,                // OBP0 - object palette 0
     obp1: u8,                // OBP1 - object palette 1
     wy: u8,                  // WY - the window Y offset
     wx: u8,                  // WX - the window X offset
     vbk_enable: bool,        // VBK bit 0 - enable VRAM bank 1, CGB only
+    obj_prio_by_x: bool,     // OPRI bit 0 - DMG-style sprite priority by X if set, else by OAM index. CGB only
 }
 
-#[derive(Copy, Clone, PartialEq)]
-struct PPUDebug {
-    enabled:    bool,        // True if debug logging is enabled
-    last_frame: Instant,     // Timestamp of last frame rendered, to calculate framerate.
-}
-
-pub struct PPU {
-    lcd: Window,             // The actual graphics window, not to be confused with a Game Boy window map/tile.
-    mem: Arc<Mutex<Memory>>, // Reference to our Memory object.
-    pixels: Vec<u8>,         // Vector containing pixel data. Currently UINT RGB8 format.
-    cfg: PPUConfig,          // Struct containing all PPU register config values
-    dbg: PPUDebug,           // Struct containing debug information and statistics
-    lclk: u32,               // The machine cycle for this line, from [0, 113].
-    alive: bool,             // Whether or not the application should continue running. This is != LCD disabled.
-}
-
-impl PPU {
-
-    const WIDTH:  usize = 160;
-    const HEIGHT: usize = 144;
-
-    pub fn new(mem: Arc<Mutex<Memory>>) -> Self {
-        let lcd = Window::new(PPU::WIDTH, PPU::HEIGHT);
-
+impl PPUConfig {
+    // Register values after the boot ROM hands over to the cartridge.
+    fn new() -> PPUConfig {
         let regs: Vec<PPUReg> = [
             PPUReg::Lcdc,
             PPUReg::Stat,
@@ -124,9 +139,10 @@ impl PPU {
             PPUReg::Wy,
             PPUReg::Wx,
             PPUReg::Vbk,
+            PPUReg::Opri,
         ].iter().cloned().collect();
 
-        let cfg = PPUConfig {
+        PPUConfig {
             regs: regs,
             lcd_enabled: true,
             win_map_high_bank: false,
@@ -154,20 +170,257 @@ impl PPU {
             wy: 0,
             wx: 0,
             vbk_enable: false,
+            obj_prio_by_x: true,
+        }
+    }
+}
+
+// Byte layout of the pixel buffer handed to the window. The names give the byte order in memory.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PixelFormat {
+    Rgb24,
+    Bgra32,
+    Argb32,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgb24 => 3,
+            PixelFormat::Bgra32 | PixelFormat::Argb32 => 4,
+        }
+    }
+}
+
+// A single OAM entry that was selected for rendering on the current line.
+#[derive(Copy, Clone, PartialEq, Debug)]
+struct Sprite {
+    y: u8,        // Y position on screen, plus 16.
+    x: u8,        // X position on screen, plus 8.
+    tile: u8,     // Tile index, always from the 0x8000 bank.
+    attrs: u8,    // Bit 7: behind BG, bit 6: Y flip, bit 5: X flip, bit 4: use OBP1.
+    index: u8,    // Index in OAM, used to break priority ties.
+}
+
+// Steps of the pixel FIFO renderer's BG fetcher. Each step but Push takes 2 dots, and Push waits
+// for the BG FIFO to empty.
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum FetchStep {
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+// A sprite pixel waiting in the sprite FIFO. Color 0 is transparent.
+#[derive(Copy, Clone, Default)]
+struct ObjPixel {
+    color: u8,
+    attrs: u8,
+    index: u8,
+}
+
+// State of the pixel FIFO renderer for the line being drawn. The BG fetcher fills the BG FIFO a
+// tile row at a time, and a pixel is shifted out each dot while it has any. Sprites are fetched
+// when the line reaches them, stalling the shifter, and mixed into the sprite FIFO, which lines
+// up with the front of the BG FIFO. Like the line renderer, the window isn't drawn.
+struct PixelFifo {
+    bg: VecDeque<u8>,        // BG color indices [0, 3], leftmost first.
+    obj: VecDeque<ObjPixel>, // Sprite pixels for the next pixels shifted out.
+    step: FetchStep,
+    step_dots: u8,           // Dots spent in the current fetcher step.
+    fetch_x: u8,             // Tiles fetched so far on this line.
+    tile_ptr: u16,           // Address of the fetched tile's row.
+    lo: u8,                  // The fetched row's low bit plane.
+    hi: u8,                  // The fetched row's high bit plane.
+    stall: u8,               // Dots left before the fetcher and shifter run again.
+    discard: u8,             // Pixels left to drop from the start of the line for SCX.
+    sprites_fetched: u64,    // Bit N set once line_sprites[N] is in the sprite FIFO.
+}
+
+impl PixelFifo {
+    // Dots taken by the first BG fetch of a line, which hardware throws away, and by each sprite.
+    const FIRST_FETCH_DOTS: u8 = 6;
+    const SPRITE_FETCH_DOTS: u8 = 6;
+
+    fn new() -> PixelFifo {
+        PixelFifo {
+            bg: VecDeque::with_capacity(16),
+            obj: VecDeque::with_capacity(16),
+            step: FetchStep::Tile,
+            step_dots: 0,
+            fetch_x: 0,
+            tile_ptr: 0,
+            lo: 0,
+            hi: 0,
+            stall: 0,
+            discard: 0,
+            sprites_fetched: 0,
+        }
+    }
+
+    // Reset for a new line, scrolled right by the given number of pixels into the first tile.
+    fn start_line(&mut self, fine_x: u8) {
+        *self = PixelFifo::new();
+        self.stall = PixelFifo::FIRST_FETCH_DOTS;
+        self.discard = fine_x;
+    }
+
+    fn save_state(&self, state: &mut Vec<u8>) {
+        state.push(self.bg.len() as u8);
+        state.extend(self.bg.iter());
+        state.push(self.obj.len() as u8);
+        for pixel in &self.obj {
+            state.extend_from_slice(&[pixel.color, pixel.attrs, pixel.index]);
+        }
+        state.extend_from_slice(&[self.step as u8, self.step_dots, self.fetch_x]);
+        state.extend_from_slice(&self.tile_ptr.to_le_bytes());
+        state.extend_from_slice(&[self.lo, self.hi, self.stall, self.discard]);
+        state.extend_from_slice(&self.sprites_fetched.to_le_bytes());
+    }
+
+    fn load_state(r: &mut StateReader) -> std::result::Result<PixelFifo, String> {
+        let bg_len = r.u8()? as usize;
+        let bg = r.take(bg_len)?.iter().copied().collect();
+        let obj_len = r.u8()? as usize;
+        let obj = r.take(obj_len * 3)?.chunks_exact(3)
+            .map(|p| ObjPixel { color: p[0], attrs: p[1], index: p[2] })
+            .collect();
+        let step = match r.u8()? {
+            0 => FetchStep::Tile,
+            1 => FetchStep::DataLow,
+            2 => FetchStep::DataHigh,
+            3 => FetchStep::Push,
+            step => return Err(format!("invalid fetcher step {}", step)),
         };
+        Ok(PixelFifo {
+            bg,
+            obj,
+            step,
+            step_dots: r.u8()?,
+            fetch_x: r.u8()?,
+            tile_ptr: r.u16()?,
+            lo: r.u8()?,
+            hi: r.u8()?,
+            stall: r.u8()?,
+            discard: r.u8()?,
+            sprites_fetched: r.u64()?,
+        })
+    }
+}
+
+// An 8-bit per channel color, before conversion to the output pixel format.
+type Rgb = (u8, u8, u8);
+
+// The default DMG colors, evenly spaced grays.
+const DMG_GRAYS: [Rgb; 4] = [(0xff, 0xff, 0xff), (0xaa, 0xaa, 0xaa), (0x55, 0x55, 0x55), (0x00, 0x00, 0x00)];
+
+// Parse a DMG palette from text holding four colors as RGB hex triples, like "e0f8d0", lightest
+// first. Colors may start with '#', and are separated by whitespace or commas.
+pub fn parse_dmg_palette(text: &str) -> std::result::Result<[Rgb; 4], String> {
+    let colors = text.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            let hex = s.trim_start_matches('#');
+            match u32::from_str_radix(hex, 16) {
+                Ok(val) if hex.len() == 6 => Ok(((val >> 16) as u8, (val >> 8) as u8, val as u8)),
+                _ => Err(format!("\"{}\" isn't an RGB hex color", s)),
+            }
+        })
+        .collect::<std::result::Result<Vec<Rgb>, String>>()?;
+
+    match colors[..] {
+        [a, b, c, d] => Ok([a, b, c, d]),
+        _ => Err(format!("expected 4 colors, found {}", colors.len())),
+    }
+}
 
+// Called with (LY, that line's pixels) after each line is rendered.
+type LineCallback = Box<dyn FnMut(u8, &mut [u8])>;
+
+#[derive(Copy, Clone, PartialEq)]
+struct PPUDebug {
+    enabled:    bool,        // True if debug logging is enabled
+    log_modes:  bool,        // True if every PPU mode transition should be logged
+    log_regs:   bool,        // True if writes to rendering registers should be logged
+    warn_slow:  bool,        // True if frames that take longer than the real frame period should be logged
+    last_frame: Duration,    // Machine time of last frame rendered, to calculate framerate.
+}
+
+pub struct PPU {
+    lcd: Option<Window>,     // The actual graphics window, not to be confused with a Game Boy window map/tile.
+                             // This is None when running headless.
+    mem: Arc<Mutex<Memory>>, // Reference to our Memory object.
+    pixels: Vec<u8>,         // Vector containing pixel data, packed according to pixel_format.
+    pixel_format: PixelFormat,
+    dmg_palette: [Rgb; 4],   // Colors for DMG shades [0, 3], lightest first.
+    bg_line: Vec<u8>,        // BG color indices [0, 3] for the current line, for sprite priority.
+    line_sprites: Vec<Sprite>, // Sprites selected by OAM search for the current line.
+    unlimited_sprites: bool, // Draw every sprite on a line, ignoring the hardware limit of 10.
+    line_callback: Option<LineCallback>, // Called after each line is rendered.
+    scanline_filter: bool,   // Dim odd lines to mimic the gaps in an LCD grid. Toggled with F.
+    fifo: Option<PixelFifo>, // Pixel FIFO renderer state, or None to draw a line at a time.
+    cfg: PPUConfig,          // Struct containing all PPU register config values
+    dbg: PPUDebug,           // Struct containing debug information and statistics
+    lclk: u32,               // The machine cycle for this line, from [0, 113].
+    line_chunks: usize,      // Number of 8 pixel BG chunks drawn so far on this line.
+    frames: u64,             // Number of frames drawn, counted as each VBlank starts.
+    cycles: u64,             // Machine cycles run, one per tick.
+    cpu_pc: u16,             // PC of the last instruction the CPU ran, for logging.
+    stat_line: bool,         // The STAT interrupt line, high while any enabled STAT condition holds.
+    alive: bool,             // Whether or not the application should continue running. This is != LCD disabled.
+}
+
+impl PPU {
+
+    const WIDTH:  usize = 160;
+    const HEIGHT: usize = 144;
+    const OAM_ADDR: u16 = 0xfe00;
+    const OAM_ENTRIES: u16 = 40;
+    const MAX_LINE_SPRITES: usize = 10;
+    // Real frame period at 59.73 Hz, 70224 clocks at 4.194304 MHz.
+    const FRAME_PERIOD: Duration = Duration::from_nanos(16_742_706);
+
+    pub fn new(mem: Arc<Mutex<Memory>>, rcfg: &RuntimeConfig) -> Self {
+        let lcd = Window::new(PPU::WIDTH, PPU::HEIGHT, WindowConfig::new(rcfg));
+        PPU::with_lcd(mem, Some(lcd), rcfg)
+    }
+
+    // Create a PPU that renders to its pixel buffer only, without opening a window. Useful for
+    // tests and other tooling that drives the emulator without a display.
+    pub fn new_headless(mem: Arc<Mutex<Memory>>, rcfg: &RuntimeConfig) -> Self {
+        PPU::with_lcd(mem, None, rcfg)
+    }
+
+    fn with_lcd(mem: Arc<Mutex<Memory>>, lcd: Option<Window>, rcfg: &RuntimeConfig) -> Self {
         let dbg = PPUDebug {
             enabled: false,
-            last_frame: Instant::now(),
+            log_modes: rcfg.log_ppu_modes,
+            log_regs: rcfg.verbose_ppu,
+            warn_slow: rcfg.warn_slow_frames,
+            last_frame: Duration::default(),
         };
 
         let mut ppu = PPU {
             lcd: lcd,
             mem: mem,
-            pixels: vec![0; PPU::WIDTH*PPU::HEIGHT*3],
-            cfg: cfg,
+            pixels: vec![0; PPU::WIDTH*PPU::HEIGHT*rcfg.pixel_format.bytes_per_pixel()],
+            pixel_format: rcfg.pixel_format,
+            dmg_palette: DMG_GRAYS,
+            bg_line: vec![0; PPU::WIDTH],
+            line_sprites: Vec::new(),
+            unlimited_sprites: rcfg.unlimited_sprites,
+            line_callback: None,
+            scanline_filter: rcfg.scanline_filter,
+            fifo: if rcfg.fifo_renderer { Some(PixelFifo::new()) } else { None },
+            cfg: PPUConfig::new(),
             dbg: dbg,
             lclk: 0,
+            line_chunks: 0,
+            frames: 0,
+            cycles: 0,
+            cpu_pc: 0,
+            stat_line: false,
             alive: true,
         };
 
@@ -177,6 +430,20 @@ impl PPU {
         ppu
     }
 
+    // Register a callback that runs after each line is rendered, with the line number and that
+    // line's pixels in the output format. Front-ends can use it for scanline effects.
+    pub fn set_line_callback<F: FnMut(u8, &mut [u8]) + 'static>(&mut self, callback: F) {
+        self.line_callback = Some(Box::new(callback));
+    }
+
+    pub fn clear_line_callback(&mut self) {
+        self.line_callback = None;
+    }
+
+    pub fn set_scanline_filter(&mut self, enabled: bool) {
+        self.scanline_filter = enabled;
+    }
+
     // Tick performs the appropriate PPU action for this machine cycle.
     // TODO: Adjust cycle accuracy of Draw state, timings can vary slightly.
     pub fn tick(&mut self) {
@@ -189,6 +456,7 @@ impl PPU {
          * 4. Do the appropriate work for this state
          * 5. Flush register changes
          */
+        self.cycles += 1;
 
         // Check window events and for register changes
         self.pull_registers();
@@ -199,19 +467,24 @@ impl PPU {
         if self.cfg.lcd_enabled {
             match self.cfg.state {
                 PPUState::HBlank => {
-                    if self.lclk == 63 {
+                    // The FIFO renderer finishes its lines as Draw ends instead.
+                    if self.lclk == 63 && self.fifo.is_none() {
                         self.render_line();
                         if self.cfg.ly == 143 {
                             self.present();
                         }
                     }
                     if self.lclk == 113 {
-                        if self.cfg.ly == 143 {
-                            self.cfg.state = PPUState::VBlank;
+                        // Move to the next line first, so the mode change is logged with its LY.
+                        self.cfg.ly += 1;
+                        if self.cfg.ly == 144 {
+                            // Request the VBlank interrupt once, as the mode changes.
+                            self.set_state(PPUState::VBlank);
+                            self.request_interrupt(0);
+                            self.frames += 1;
                         } else {
-                            self.cfg.state = PPUState::Draw;
+                            self.set_state(PPUState::OAMSearch);
                         }
-                        self.cfg.ly += 1;
                         self.lclk = 0;
                     } else {
                         self.lclk += 1;
@@ -220,8 +493,8 @@ impl PPU {
                 PPUState::VBlank => {
                     if self.lclk == 113 {
                         if self.cfg.ly == 153 {
-                            self.cfg.state = PPUState::OAMSearch;
                             self.cfg.ly = 0;
+                            self.set_state(PPUState::OAMSearch);
                         } else {
                             self.cfg.ly += 1;
                         }
@@ -231,31 +504,419 @@ impl PPU {
                     }
                 },
                 PPUState::OAMSearch => {
+                    if self.lclk == 0 {
+                        self.oam_search();
+                    }
                     if self.lclk == 19 {
-                        self.cfg.state = PPUState::Draw;
+                        self.set_state(PPUState::Draw);
+                        self.cfg.lx = 0;
+                        let fine_x = self.cfg.scx % 8;
+                        if let Some(fifo) = &mut self.fifo {
+                            fifo.start_line(fine_x);
+                        }
+                    }
+                    self.lclk += 1;
+                },
+                PPUState::Draw if self.fifo.is_some() => {
+                    // Draw takes as long as the FIFOs need, but always leaves some HBlank. The
+                    // deadline only matters with unlimited sprites, where fetches can overrun.
+                    let deadline = self.lclk >= 110;
+                    let mut done = false;
+                    let mut dots = 0;
+                    while !done && (dots < 4 || deadline) {
+                        done = self.fifo_dot(deadline);
+                        dots += 1;
+                    }
+                    if done {
+                        self.finish_line();
+                        if self.cfg.ly == 143 {
+                            self.present();
+                        }
+                        self.set_state(PPUState::HBlank);
                     }
                     self.lclk += 1;
                 },
                 PPUState::Draw => {
+                    // Draw a chunk every other cycle, so register writes made partway through
+                    // the line apply to the rest of it.
+                    self.render_chunks_until(((self.lclk - 20) / 2 + 1) as usize);
                     if self.lclk == 62 {
-                        self.cfg.state = PPUState::HBlank;
+                        self.set_state(PPUState::HBlank);
                     }
                     self.lclk += 1;
                 }
             }
         }
 
+        self.update_stat_line();
         self.push_registers();
     }
 
-    fn render_line(&mut self) {
-        // For each scanline...
-        let wt = PPU::WIDTH / 8;
-        for _w in 0..wt {
+    // The enabled STAT conditions share one interrupt line, and only its rising edge requests the
+    // interrupt. A condition that starts while another is still holding the line high is missed.
+    fn update_stat_line(&mut self) {
+        // Compare after this cycle's LY change, so the coincidence starts with the line.
+        self.cfg.ly_eq_lyc = self.cfg.ly == self.cfg.lyc;
+        let cfg = &self.cfg;
+        let line = cfg.lcd_enabled && (
+            (cfg.ly_eq_lyc_intr && cfg.ly_eq_lyc) ||
+            (cfg.oam_intr && cfg.state == PPUState::OAMSearch) ||
+            (cfg.vblank_intr && cfg.state == PPUState::VBlank) ||
+            (cfg.hblank_intr && cfg.state == PPUState::HBlank));
+
+        if line && !self.stat_line {
+            self.request_interrupt(1);
+        }
+        self.stat_line = line;
+    }
+
+    // Set the given bit [0, 4] in the IF register.
+    fn request_interrupt(&self, bit: u8) {
+        self.mem.lock().unwrap().request_interrupt(bit);
+    }
+
+    // Switch to the given mode, logging the transition if requested.
+    fn set_state(&mut self, state: PPUState) {
+        if self.dbg.log_modes {
+            println!("LY={:02x} lclk={:02} {}", self.cfg.ly, self.lclk, state);
+        }
+        self.cfg.state = state;
+    }
+
+    // Draw BG chunks for the current line until the given number are done.
+    fn render_chunks_until(&mut self, chunks: usize) {
+        let chunks = chunks.min(PPU::WIDTH / 8);
+        while self.line_chunks < chunks {
             self.get_chunk();
+            self.line_chunks += 1;
+        }
+    }
+
+    // Finish the current line: draw any BG chunks not drawn during Draw, then the sprites.
+    fn render_line(&mut self) {
+        self.render_chunks_until(PPU::WIDTH / 8);
+        self.line_chunks = 0;
+        self.cfg.lx = 0;
+
+        if self.cfg.obj_en {
+            self.render_sprites();
+        }
+        self.finish_line();
+    }
+
+    // Apply the scanline filter to the finished line, then hand it to the line callback.
+    fn finish_line(&mut self) {
+        let bpp = self.pixel_format.bytes_per_pixel();
+        let line_len = PPU::WIDTH * bpp;
+        let start = self.cfg.ly as usize * line_len;
+        let line = &mut self.pixels[start..start + line_len];
+
+        if self.scanline_filter && self.cfg.ly % 2 == 1 {
+            // Darken the color bytes by a quarter, leaving alpha alone.
+            let alpha = match self.pixel_format {
+                PixelFormat::Rgb24  => None,
+                PixelFormat::Bgra32 => Some(3),
+                PixelFormat::Argb32 => Some(0),
+            };
+            for pixel in line.chunks_exact_mut(bpp) {
+                for (i, c) in pixel.iter_mut().enumerate() {
+                    if Some(i) != alpha { *c -= *c / 4; }
+                }
+            }
+        }
+
+        if let Some(callback) = &mut self.line_callback {
+            callback(self.cfg.ly, line);
+        }
+    }
+
+    // Run the pixel FIFO renderer for one dot, returning true once the line's last pixel is out.
+    // With no_stall set, fetches take no time, to finish a line that's out of time.
+    fn fifo_dot(&mut self, no_stall: bool) -> bool {
+        let mut fifo = match self.fifo.take() {
+            Some(fifo) => fifo,
+            None => return true,
+        };
+        if no_stall { fifo.stall = 0; }
+
+        let done = if fifo.stall > 0 {
+            fifo.stall -= 1;
+            false
+        } else if let Some(i) = self.next_sprite(&fifo) {
+            self.fetch_sprite(&mut fifo, i);
+            if !no_stall { fifo.stall = PixelFifo::SPRITE_FETCH_DOTS - 1; }
+            false
+        } else {
+            self.fetcher_dot(&mut fifo);
+            self.shift_out(&mut fifo)
+        };
+
+        self.fifo = Some(fifo);
+        done
+    }
+
+    // The index in line_sprites of the next sprite to fetch, out of those not fetched yet that
+    // start at or before the next pixel out. Sprites due together at the left edge go in priority
+    // order, so the winner is in the FIFO first.
+    fn next_sprite(&self, fifo: &PixelFifo) -> Option<usize> {
+        if !self.cfg.obj_en || fifo.discard > 0 {
+            return None;
+        }
+        let due = self.line_sprites.iter().enumerate()
+            .filter(|(i, s)| fifo.sprites_fetched & (1 << i) == 0 && s.x > 0 && s.x <= self.cfg.lx + 8);
+        if self.cfg.obj_prio_by_x {
+            due.min_by_key(|(_, s)| (s.x, s.index)).map(|(i, _)| i)
+        } else {
+            due.min_by_key(|(_, s)| s.index).map(|(i, _)| i)
+        }
+    }
+
+    // Fetch a sprite's row and mix it into the sprite FIFO. Opaque pixels already there win, so
+    // lower X wins, then lower OAM index. With CGB priority by OAM index alone, a lower index
+    // takes over instead.
+    fn fetch_sprite(&self, fifo: &mut PixelFifo, i: usize) {
+        fifo.sprites_fetched |= 1 << i;
+        let sprite = self.line_sprites[i];
+        let height = if self.cfg.tall_objs { 16 } else { 8 };
+        let mut row = self.cfg.ly as u16 + 16 - sprite.y as u16;
+        if util::is_bit_set(sprite.attrs, 6) {
+            row = height - 1 - row;
+        }
+        let tile = if self.cfg.tall_objs { sprite.tile & 0xfe } else { sprite.tile };
+        let data_ptr = 0x8000 + tile as u16 * 16 + row * 2;
+        let lo = self.mem_get(data_ptr);
+        let hi = self.mem_get(data_ptr+1);
+
+        // Sprites partly off the left edge, or reached late, skip their first pixels.
+        let skip = (self.cfg.lx + 8 - sprite.x) as usize;
+        for px in skip..8 {
+            let bit = if util::is_bit_set(sprite.attrs, 5) { px } else { 7 - px };
+            let color = ((hi >> bit) & 0x1) << 1 | ((lo >> bit) & 0x1);
+            let pixel = ObjPixel { color, attrs: sprite.attrs, index: sprite.index };
+
+            let pos = px - skip;
+            if fifo.obj.len() <= pos {
+                fifo.obj.resize(pos + 1, ObjPixel::default());
+            }
+            let old = fifo.obj[pos];
+            let replace = old.color == 0 || (!self.cfg.obj_prio_by_x && color != 0 && sprite.index < old.index);
+            if replace {
+                fifo.obj[pos] = pixel;
+            }
+        }
+    }
+
+    // Advance the BG fetcher by a dot.
+    fn fetcher_dot(&self, fifo: &mut PixelFifo) {
+        if fifo.step != FetchStep::Push {
+            fifo.step_dots += 1;
+            if fifo.step_dots < 2 { return; }
+            fifo.step_dots = 0;
+        }
+
+        match fifo.step {
+            FetchStep::Tile => {
+                let y = self.cfg.ly.wrapping_add(self.cfg.scy);
+                let tile_x = (self.cfg.scx / 8).wrapping_add(fifo.fetch_x) % 32;
+                fifo.tile_ptr = self.get_bg_data_ptr(tile_x, y / 8) + (y % 8) as u16 * 2;
+                fifo.step = FetchStep::DataLow;
+            },
+            FetchStep::DataLow => {
+                fifo.lo = self.mem_get(fifo.tile_ptr);
+                fifo.step = FetchStep::DataHigh;
+            },
+            FetchStep::DataHigh => {
+                fifo.hi = self.mem_get(fifo.tile_ptr + 1);
+                fifo.step = FetchStep::Push;
+            },
+            FetchStep::Push => {
+                if fifo.bg.is_empty() {
+                    for bit in (0..8).rev() {
+                        fifo.bg.push_back(((fifo.hi >> bit) & 0x1) << 1 | ((fifo.lo >> bit) & 0x1));
+                    }
+                    fifo.fetch_x = fifo.fetch_x.wrapping_add(1);
+                    fifo.step = FetchStep::Tile;
+                }
+            },
+        }
+    }
+
+    // Shift a pixel out of the FIFOs onto the screen, mixing in any sprite pixel over it. Returns
+    // true once the line is full.
+    fn shift_out(&mut self, fifo: &mut PixelFifo) -> bool {
+        let bg = match fifo.bg.pop_front() {
+            Some(bg) => bg,
+            None => return false,
+        };
+        if fifo.discard > 0 {
+            fifo.discard -= 1;
+            return false;
+        }
+
+        let x = self.cfg.lx as usize;
+        let obj = fifo.obj.pop_front().unwrap_or_default();
+        let rgb = if self.cfg.obj_en && obj.color != 0 && !(util::is_bit_set(obj.attrs, 7) && bg != 0) {
+            self.obj_rgb(obj)
+        } else {
+            self.shade_to_rgb((self.cfg.bgp >> (bg * 2)) & 0x3)
+        };
+        self.bg_line[x] = bg;
+        self.put_pixel(x, self.cfg.ly as usize, rgb);
+        self.cfg.lx += 1;
+        self.cfg.lx as usize == PPU::WIDTH
+    }
+
+    // The color of a sprite pixel, from the CGB palette RAM or OBP0/OBP1.
+    fn obj_rgb(&self, obj: ObjPixel) -> Rgb {
+        let mref = self.mem.lock().unwrap();
+        if mref.cgb_mode() {
+            mref.obj_palettes().rgb(obj.attrs & 0x07, obj.color)
+        } else {
+            let palette = if util::is_bit_set(obj.attrs, 4) { self.cfg.obp1 } else { self.cfg.obp0 };
+            self.shade_to_rgb((palette >> (obj.color * 2)) & 0x3)
         }
     }
 
+    // Scan OAM in order and select the sprites that intersect the current line. Hardware stops
+    // after the first 10 matches, so any further sprites on this line are never drawn, unless
+    // the limit is turned off to avoid flicker.
+    fn oam_search(&mut self) {
+        let height = if self.cfg.tall_objs { 16 } else { 8 };
+        let line = self.cfg.ly as u16 + 16;
+        self.line_sprites.clear();
+
+        for i in 0..PPU::OAM_ENTRIES {
+            let addr = PPU::OAM_ADDR + i*4;
+            let y = self.mem_get(addr);
+            if line < y as u16 || line >= y as u16 + height {
+                continue;
+            }
+
+            self.line_sprites.push(Sprite {
+                y,
+                x: self.mem_get(addr+1),
+                tile: self.mem_get(addr+2),
+                attrs: self.mem_get(addr+3),
+                index: i as u8,
+            });
+
+            if !self.unlimited_sprites && self.line_sprites.len() == PPU::MAX_LINE_SPRITES {
+                break;
+            }
+        }
+    }
+
+    // Draw the sprites selected by OAM search over the BG pixels of the current line.
+    fn render_sprites(&mut self) {
+        let height = if self.cfg.tall_objs { 16 } else { 8 };
+
+        // On DMG the sprite with the lowest X wins, with ties going to the lowest OAM index. CGB
+        // can instead pick the lowest OAM index regardless of X, selected by OPRI.
+        let mut sprites = self.line_sprites.clone();
+        if self.cfg.obj_prio_by_x {
+            sprites.sort_by_key(|s| (s.x, s.index));
+        } else {
+            sprites.sort_by_key(|s| s.index);
+        }
+
+        // CGB games color sprites from the palette RAM instead of OBP0/OBP1.
+        let cgb_palettes = {
+            let mref = self.mem.lock().unwrap();
+            if mref.cgb_mode() { Some(mref.obj_palettes().clone()) } else { None }
+        };
+
+        // For each screen pixel, the (RGB color, behind BG) of the winning opaque sprite pixel.
+        let mut obj_line: Vec<Option<(Rgb, bool)>> = vec![None; PPU::WIDTH];
+        for sprite in sprites.iter() {
+            let mut row = self.cfg.ly as u16 + 16 - sprite.y as u16;
+            if util::is_bit_set(sprite.attrs, 6) {
+                row = height - 1 - row;
+            }
+            let tile = if self.cfg.tall_objs { sprite.tile & 0xfe } else { sprite.tile };
+            let data_ptr = 0x8000 + tile as u16 * 16 + row * 2;
+            let lo = self.mem_get(data_ptr);
+            let hi = self.mem_get(data_ptr+1);
+
+            for px in 0..8 {
+                let x = sprite.x as i16 - 8 + px as i16;
+                if x < 0 || x >= PPU::WIDTH as i16 || obj_line[x as usize].is_some() {
+                    continue;
+                }
+
+                let bit = if util::is_bit_set(sprite.attrs, 5) { px } else { 7 - px };
+                let color = ((hi >> bit) & 0x1) << 1 | ((lo >> bit) & 0x1);
+                if color != 0 {
+                    let rgb = match &cgb_palettes {
+                        Some(palettes) => palettes.rgb(sprite.attrs & 0x07, color),
+                        None => {
+                            let palette = if util::is_bit_set(sprite.attrs, 4) { self.cfg.obp1 } else { self.cfg.obp0 };
+                            self.shade_to_rgb((palette >> (color * 2)) & 0x3)
+                        },
+                    };
+                    obj_line[x as usize] = Some((rgb, util::is_bit_set(sprite.attrs, 7)));
+                }
+            }
+        }
+
+        for (x, obj) in obj_line.iter().enumerate() {
+            if let Some((rgb, behind_bg)) = *obj {
+                if behind_bg && self.bg_line[x] != 0 {
+                    continue;
+                }
+
+                self.put_pixel(x, self.cfg.ly as usize, rgb);
+            }
+        }
+    }
+
+    // The last rendered screen at its native 160x144 resolution, as 3 bytes per pixel in R, G, B
+    // order, row by row from the top left. The same for every pixel format and window scale, so
+    // tests can compare it byte for byte.
+    pub fn framebuffer(&self) -> Vec<u8> {
+        let bpp = self.pixel_format.bytes_per_pixel();
+        self.pixels.chunks_exact(bpp).flat_map(|p| {
+            match self.pixel_format {
+                PixelFormat::Rgb24  => [p[0], p[1], p[2]],
+                PixelFormat::Bgra32 => [p[2], p[1], p[0]],
+                PixelFormat::Argb32 => [p[1], p[2], p[3]],
+            }
+        }).collect()
+    }
+
+    // Write an RGB color to the given screen coordinate, in the output format.
+    fn put_pixel(&mut self, x: usize, y: usize, (r, g, b): (u8, u8, u8)) {
+        let bpp = self.pixel_format.bytes_per_pixel();
+        let write_addr = ((y * PPU::WIDTH) + x) * bpp;
+        let packed = match self.pixel_format {
+            PixelFormat::Rgb24  => [r, g, b, 0],
+            PixelFormat::Bgra32 => [b, g, r, 0xff],
+            PixelFormat::Argb32 => [0xff, r, g, b],
+        };
+        self.pixels[write_addr..write_addr + bpp].copy_from_slice(&packed[..bpp]);
+    }
+
+    // Fill the screen with 8x8 checks that step through all four shades, and present it. This
+    // bypasses tile fetching entirely, to check the display path independently of emulation.
+    pub fn render_test_pattern(&mut self) {
+        for y in 0..PPU::HEIGHT {
+            for x in 0..PPU::WIDTH {
+                let shade = ((x / 8 + y / 8) % 4) as u8;
+                self.put_pixel(x, y, self.shade_to_rgb(shade));
+            }
+        }
+        self.present();
+    }
+
+    // Convert a DMG shade [0, 3] into an RGB8 color.
+    fn shade_to_rgb(&self, shade: u8) -> (u8, u8, u8) {
+        self.dmg_palette[(shade & 0x3) as usize]
+    }
+
+    // Replace the colors DMG shades [0, 3] are drawn with, lightest first.
+    pub fn set_dmg_palette(&mut self, palette: [Rgb; 4]) {
+        self.dmg_palette = palette;
+    }
+
     // A "chunk" is a group of 8 horizontal pixels.
     fn get_chunk(&mut self) {
         let global_pixel_y = self.cfg.ly.wrapping_add(self.cfg.scy);
@@ -288,29 +949,24 @@ impl PPU {
         // We're almost there!
         for _x in 0..8 {
             let val: u8 = ((hi_bits & 0x1) as u8) << 1 | (lo_bits & 0x1) as u8;
-            let write_addr = ((self.cfg.ly as usize * PPU::WIDTH) + self.cfg.lx as usize) * 3;
             hi_bits = hi_bits >> 1;
             lo_bits = lo_bits >> 1;
 
-            // TODO: Map this value to a palette value
-            let (r,g,b) = match val {
-                0 => { (0xFF, 0xFF, 0xFF) },
-                1 => { (0xAA, 0xAA, 0xAA) },
-                2 => { (0x55, 0x55, 0x55) },
-                3 => { (0x00, 0x00, 0x00) },
-                _ => { (0xFF, 0x00, 0x00) },
-            };
-
-            self.pixels[write_addr+0] = r;
-            self.pixels[write_addr+1] = g;
-            self.pixels[write_addr+2] = b;
+            let shade = (self.cfg.bgp >> (val * 2)) & 0x3;
+            self.bg_line[self.cfg.lx as usize] = val;
+            self.put_pixel(self.cfg.lx as usize, self.cfg.ly as usize, self.shade_to_rgb(shade));
             self.cfg.lx = (self.cfg.lx + 1) % PPU::WIDTH as u8;
         }
     }
 
+    // Start of the 32x32 BG tile map selected by LCDC bit 3.
+    fn bg_map_addr(&self) -> u16 {
+        if self.cfg.bg_map_high_bank { 0x9c00 } else { 0x9800 }
+    }
+
     // Given the coordinates of a BG map tile, return the start address of that tile's data.
     fn get_bg_data_ptr(&self, tx: u8, ty: u8) -> u16 {
-        let base_bg_map_addr: u16 = if self.cfg.bg_map_high_bank { 0x9c00 } else { 0x9800 };
+        let base_bg_map_addr: u16 = self.bg_map_addr();
         let base_bg_data_addr: u16 = if self.cfg.bg_data_low_bank { 0x8000 } else { 0x9000 };
         let bg_map_ptr = base_bg_map_addr + (ty as u16)*32 + tx as u16;
         let bg_data_offset = self.mem_get(bg_map_ptr);
@@ -327,17 +983,51 @@ impl PPU {
         (base_bg_data_addr as i16 + bg_data_offset * 16) as u16
     }
 
+    // The BG tile map's tile indices as 32 rows of 32 hex bytes.
+    pub fn tile_map_text(&self) -> Vec<String> {
+        let base = self.bg_map_addr();
+        (0..32).map(|ty| {
+            let row: Vec<String> = (0..32).map(|tx| format!("{:02x}", self.mem_get(base + ty*32 + tx))).collect();
+            row.join(" ")
+        }).collect()
+    }
+
     fn present(&mut self) {
-        self.lcd.draw(self.pixels.as_slice());
+        if let Some(lcd) = &mut self.lcd {
+            lcd.draw(self.pixels.as_slice(), self.pixel_format);
+        }
 
-        if self.dbg.enabled {
-            let now = Instant::now();
-            let frame_time = now.duration_since(self.dbg.last_frame).as_micros();
-            self.dbg.last_frame = now;
-            println!("Render time for this frame: {} us, or {:.2} fps.", frame_time, (1.0 / frame_time as f64) * 1000000.0);
+        if self.dbg.enabled || self.dbg.warn_slow {
+            let now = self.mem.lock().unwrap().elapsed();
+            let frame_time = self.end_frame(now);
+            if self.dbg.enabled {
+                let frame_us = frame_time.as_micros();
+                println!("Render time for this frame: {} us, or {:.2} fps.", frame_us, (1.0 / frame_us as f64) * 1000000.0);
+            }
         }
     }
 
+    // Record the end of a frame at the given time, warning if it took longer than real hardware
+    // would. Returns how long the frame took to emulate and render.
+    fn end_frame(&mut self, now: Duration) -> Duration {
+        let frame_time = now.saturating_sub(self.dbg.last_frame);
+        self.dbg.last_frame = now;
+        if let Some(warning) = PPU::slow_frame_warning(frame_time).filter(|_| self.dbg.warn_slow) {
+            println!("{}", warning);
+        }
+        frame_time
+    }
+
+    fn slow_frame_warning(frame_time: Duration) -> Option<String> {
+        if frame_time <= PPU::FRAME_PERIOD {
+            return None;
+        }
+        Some(format!("Slow frame: took {} us, {} us over the {} us frame period.",
+                     frame_time.as_micros(),
+                     (frame_time - PPU::FRAME_PERIOD).as_micros(),
+                     PPU::FRAME_PERIOD.as_micros()))
+    }
+
     pub fn terminate(&mut self) {
         self.alive = false;
     }
@@ -346,6 +1036,97 @@ impl PPU {
         self.alive
     }
 
+    // Return to the power-on state and write the initial register values back to memory, for
+    // when the machine is reset underneath us.
+    pub fn reset(&mut self) {
+        self.cfg = PPUConfig::new();
+        self.pixels.iter_mut().for_each(|p| *p = 0);
+        self.line_sprites.clear();
+        self.lclk = 0;
+        self.line_chunks = 0;
+        self.frames = 0;
+        self.cycles = 0;
+        self.stat_line = false;
+        self.dbg.last_frame = Duration::default();
+        self.push_registers();
+    }
+
+    // Where the PPU is in the frame, the line in progress, and the screen drawn so far, for save
+    // states. The registers themselves are saved with memory.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![self.cfg.state as u8, self.cfg.lx, self.line_chunks as u8, self.stat_line as u8];
+        state.extend_from_slice(&self.lclk.to_le_bytes());
+        state.extend_from_slice(&self.frames.to_le_bytes());
+        state.extend_from_slice(&self.cycles.to_le_bytes());
+        state.extend_from_slice(&self.bg_line);
+        state.extend_from_slice(&self.framebuffer());
+        state.push(self.line_sprites.len() as u8);
+        for s in &self.line_sprites {
+            state.extend_from_slice(&[s.y, s.x, s.tile, s.attrs, s.index]);
+        }
+        match &self.fifo {
+            Some(fifo) => {
+                state.push(1);
+                fifo.save_state(&mut state);
+            },
+            None => state.push(0),
+        }
+        state
+    }
+
+    // Restore a state from save_state. Fails without changing anything if it's malformed. The
+    // screen is kept in RGB, so a state can be restored with any pixel format, but one from the
+    // other renderer may draw the line in progress wrong.
+    pub fn load_state(&mut self, state: &[u8]) -> std::result::Result<(), String> {
+        let mut r = StateReader::new(state);
+        let mode = r.u8()?;
+        let mode = PPUState::from_u8(mode).ok_or(format!("invalid PPU mode {}", mode))?;
+        let (lx, line_chunks, stat_line) = (r.u8()?, r.u8()? as usize, r.bool()?);
+        let (lclk, frames, cycles) = (r.u32()?, r.u64()?, r.u64()?);
+        let bg_line = r.take(PPU::WIDTH)?;
+        let screen = r.take(PPU::WIDTH * PPU::HEIGHT * 3)?;
+        let sprite_count = r.u8()? as usize;
+        let sprites: Vec<Sprite> = r.take(sprite_count * 5)?.chunks_exact(5)
+            .map(|s| Sprite { y: s[0], x: s[1], tile: s[2], attrs: s[3], index: s[4] })
+            .collect();
+        let fifo = if r.bool()? { Some(PixelFifo::load_state(&mut r)?) } else { None };
+        if !r.is_empty() {
+            return Err(String::from("PPU state is too long"));
+        }
+
+        self.cfg.state = mode;
+        self.cfg.lx = lx;
+        self.line_chunks = line_chunks;
+        self.stat_line = stat_line;
+        self.lclk = lclk;
+        self.frames = frames;
+        self.cycles = cycles;
+        self.bg_line.copy_from_slice(bg_line);
+        for (i, rgb) in screen.chunks_exact(3).enumerate() {
+            self.put_pixel(i % PPU::WIDTH, i / PPU::WIDTH, (rgb[0], rgb[1], rgb[2]));
+        }
+        self.line_sprites = sprites;
+        if self.fifo.is_some() {
+            self.fifo = Some(fifo.unwrap_or_else(PixelFifo::new));
+        }
+        Ok(())
+    }
+
+    // Tell the PPU which instruction ran last, so register changes can be traced back to it.
+    pub fn set_cpu_pc(&mut self, pc: u16) {
+        self.cpu_pc = pc;
+    }
+
+    // The number of VBlanks entered so far.
+    pub fn frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    // Machine cycles run since power on or the last reset.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
     fn check_events(&mut self) {
         // Do nothing if we've terminated the application.
         if !self.is_alive() {
@@ -353,25 +1134,37 @@ impl PPU {
         }
 
         // Check window for termination events
-        if self.cfg.state == PPUState::VBlank {
-            self.lcd.get_events();
-        }
-        if !self.lcd.is_open() {
-            self.terminate();
-            return;
+        if let Some(lcd) = &mut self.lcd {
+            if self.cfg.state == PPUState::VBlank {
+                let buttons = lcd.get_events();
+                if lcd.take_filter_toggle() {
+                    self.scanline_filter = !self.scanline_filter;
+                }
+                let mut mref = self.mem.lock().unwrap();
+                for (button, pressed) in buttons {
+                    mref.set_button(button, pressed);
+                }
+            }
+            if !lcd.is_open() {
+                self.terminate();
+            }
         }
-
-        // Check for LY==LYC
-        // TODO process the LYC interrupt here?
-        self.cfg.ly_eq_lyc = self.cfg.ly == self.cfg.lyc;
     }
 
     // Check for register changes, and apply the corresponding settings differences.
     // TODO: Some registers can't be changed halfway through a scanline, check for those here.
     fn pull_registers(&mut self) {
+        if self.dbg.log_regs {
+            for line in self.register_changes() {
+                println!("{}", line);
+            }
+        }
+
         // Collect the values before writing to prevent borrowing issues.
         // let regs = self.cfg.regs.cloned();
+        let cgb = self.mem.lock().unwrap().cgb_mode();
         for reg in self.cfg.regs.iter() {
+            if reg.cgb_only() && !cgb { continue; }
             let val = self.mem_get(*reg as u16);
 
             match reg {
@@ -404,6 +1197,7 @@ impl PPU {
                 PPUReg::Wy   => self.cfg.wy   = val,
                 PPUReg::Wx   => self.cfg.wx   = val,
                 PPUReg::Vbk  => self.cfg.vbk_enable = val == 1,
+                PPUReg::Opri => self.cfg.obj_prio_by_x = (val & 0x01) != 0,
             }
         }
     }
@@ -412,46 +1206,65 @@ impl PPU {
     fn push_registers(&mut self) {
         let regs: Vec<PPUReg> = self.cfg.regs.iter().cloned().collect();
         for reg in regs {
-            // Encode our current config state into actual register values
-            let val = match reg {
-                PPUReg::Lcdc => {
-                    (if self.cfg.lcd_enabled        { 1 } else { 0 } << 7) |
-                    (if self.cfg.win_map_high_bank  { 1 } else { 0 } << 6) |
-                    (if self.cfg.win_en             { 1 } else { 0 } << 5) |
-                    (if self.cfg.bg_data_low_bank   { 1 } else { 0 } << 4) |
-                    (if self.cfg.bg_map_high_bank   { 1 } else { 0 } << 3) |
-                    (if self.cfg.tall_objs          { 1 } else { 0 } << 2) |
-                    (if self.cfg.obj_en             { 1 } else { 0 } << 1) |
-                    (if self.cfg.bg_priority        { 1 } else { 0 } << 0)
-                },
-                PPUReg::Stat => {
-                    (0x1 << 7) | // Bit 7 of STAT always returns 1
-                    (if self.cfg.ly_eq_lyc_intr     { 1 } else { 0 } << 6) |
-                    (if self.cfg.oam_intr           { 1 } else { 0 } << 5) |
-                    (if self.cfg.vblank_intr        { 1 } else { 0 } << 4) |
-                    (if self.cfg.hblank_intr        { 1 } else { 0 } << 3) |
-                    (if self.cfg.ly_eq_lyc          { 1 } else { 0 } << 2) |
-                    (if self.cfg.lcd_enabled { (self.cfg.state as u8) & 0x3 } else { 0 })
-                },
-                PPUReg::Bgp => {
-                    self.cfg.bgp //TODO: split this up
-                },
-                PPUReg::Scy  => self.cfg.scy,
-                PPUReg::Scx  => self.cfg.scx,
-                PPUReg::Ly   => self.cfg.ly,
-                PPUReg::Lyc  => self.cfg.lyc,
-                PPUReg::Dma  => self.cfg.dma,
-                PPUReg::Obp0 => self.cfg.obp0,
-                PPUReg::Obp1 => self.cfg.obp1,
-                PPUReg::Wy   => self.cfg.wy,
-                PPUReg::Wx   => self.cfg.wx,
-                PPUReg::Vbk  => if self.cfg.vbk_enable { 1 } else { 0 },
-            };
-
+            let val = self.reg_value(reg);
             self.mem_set(reg as u16, val);
         }
     }
 
+    // Encode our current config state into the actual register value
+    fn reg_value(&self, reg: PPUReg) -> u8 {
+        match reg {
+            PPUReg::Lcdc => {
+                (if self.cfg.lcd_enabled        { 1 } else { 0 } << 7) |
+                (if self.cfg.win_map_high_bank  { 1 } else { 0 } << 6) |
+                (if self.cfg.win_en             { 1 } else { 0 } << 5) |
+                (if self.cfg.bg_data_low_bank   { 1 } else { 0 } << 4) |
+                (if self.cfg.bg_map_high_bank   { 1 } else { 0 } << 3) |
+                (if self.cfg.tall_objs          { 1 } else { 0 } << 2) |
+                (if self.cfg.obj_en             { 1 } else { 0 } << 1) |
+                (if self.cfg.bg_priority        { 1 } else { 0 } << 0)
+            },
+            PPUReg::Stat => {
+                (0x1 << 7) | // Bit 7 of STAT always returns 1
+                (if self.cfg.ly_eq_lyc_intr     { 1 } else { 0 } << 6) |
+                (if self.cfg.oam_intr           { 1 } else { 0 } << 5) |
+                (if self.cfg.vblank_intr        { 1 } else { 0 } << 4) |
+                (if self.cfg.hblank_intr        { 1 } else { 0 } << 3) |
+                (if self.cfg.ly_eq_lyc          { 1 } else { 0 } << 2) |
+                (if self.cfg.lcd_enabled { (self.cfg.state as u8) & 0x3 } else { 0 })
+            },
+            PPUReg::Bgp => {
+                self.cfg.bgp //TODO: split this up
+            },
+            PPUReg::Scy  => self.cfg.scy,
+            PPUReg::Scx  => self.cfg.scx,
+            PPUReg::Ly   => self.cfg.ly,
+            PPUReg::Lyc  => self.cfg.lyc,
+            PPUReg::Dma  => self.cfg.dma,
+            PPUReg::Obp0 => self.cfg.obp0,
+            PPUReg::Obp1 => self.cfg.obp1,
+            PPUReg::Wy   => self.cfg.wy,
+            PPUReg::Wx   => self.cfg.wx,
+            PPUReg::Vbk  => if self.cfg.vbk_enable { 1 } else { 0 },
+            PPUReg::Opri => if self.cfg.obj_prio_by_x { 1 } else { 0 },
+        }
+    }
+
+    // Describe each write to a register that affects rendering since the last pull, with the PC of
+    // the instruction responsible.
+    fn register_changes(&self) -> Vec<String> {
+        let watched = [PPUReg::Lcdc, PPUReg::Scx, PPUReg::Scy, PPUReg::Bgp,
+                       PPUReg::Obp0, PPUReg::Obp1, PPUReg::Wx, PPUReg::Wy];
+        watched.iter().filter_map(|&reg| {
+            let (old, new) = (self.reg_value(reg), self.mem_get(reg as u16));
+            if old == new {
+                None
+            } else {
+                Some(format!("PC=0x{:04x} {}: 0x{:02x} -> 0x{:02x}", self.cpu_pc, reg, old, new))
+            }
+        }).collect()
+    }
+
     // VRAM data access, given absolute memory address
     // VRAM [0x8000, 0xa000) -> [0x0, 0x2000]
     // OAM RAM access [0xFE00, 0xFEA0) -> []
@@ -465,3 +1278,575 @@ impl PPU {
         (*mref).set(val, addr, MemClient::PPU)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{DMA_ADDR, IF_ADDR, OCPD_ADDR, OCPS_ADDR};
+
+    fn headless_ppu() -> PPU {
+        let mem = Arc::new(Mutex::new(Memory::new(0x10000)));
+        PPU::new_headless(mem, &RuntimeConfig::new())
+    }
+
+    // A headless PPU running a ROM that enables CGB features.
+    fn headless_cgb_ppu() -> PPU {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        let mut mem = Memory::new(0x10000);
+        mem.load_rom(rom);
+        PPU::new_headless(Arc::new(Mutex::new(mem)), &RuntimeConfig::new())
+    }
+
+    #[test]
+    fn dma_register_reads_back_written_page() {
+        let mut ppu = headless_ppu();
+        ppu.mem.lock().unwrap().set(0xc0, DMA_ADDR, MemClient::CPU);
+
+        // The value survives the PPU pulling and pushing its registers, both mid-copy and after.
+        for _ in 0..4 {
+            ppu.tick();
+        }
+        let mut mref = ppu.mem.lock().unwrap();
+        assert!(mref.dma_active());
+        assert_eq!(mref.get(DMA_ADDR, MemClient::CPU), 0xc0);
+        mref.advance_clock(0xa0 * 4);
+        assert!(!mref.dma_active());
+        drop(mref);
+        ppu.tick();
+        assert_eq!(ppu.cfg.dma, 0xc0);
+        assert_eq!(ppu.mem.lock().unwrap().get(DMA_ADDR, MemClient::CPU), 0xc0);
+    }
+
+    #[test]
+    fn mode_transitions_for_one_frame() {
+        let mut ppu = headless_ppu();
+
+        // Run until the first line of a frame starts.
+        while ppu.cfg.state != PPUState::OAMSearch {
+            ppu.tick();
+        }
+
+        let mut transitions = vec![ppu.cfg.state];
+        loop {
+            ppu.tick();
+            if ppu.cfg.state != *transitions.last().unwrap() {
+                transitions.push(ppu.cfg.state);
+                if ppu.cfg.state == PPUState::OAMSearch && ppu.cfg.ly == 0 {
+                    break;
+                }
+            }
+        }
+
+        let mut expected = Vec::new();
+        for _ in 0..144 {
+            expected.extend_from_slice(&[PPUState::OAMSearch, PPUState::Draw, PPUState::HBlank]);
+        }
+        expected.push(PPUState::VBlank);
+        expected.push(PPUState::OAMSearch);
+        assert!(transitions == expected);
+    }
+
+    // Set up 12 non-overlapping sprites of solid color 3 on line 0, over a BG of color 0, and run
+    // OAM search for that line.
+    fn twelve_sprites_on_line_zero(ppu: &mut PPU) {
+        {
+            let mut mem = ppu.mem.lock().unwrap();
+            mem.set(0x93, PPUReg::Lcdc as u16, MemClient::CPU); // LCD, BG, and OBJs on.
+            mem.set(0xe4, PPUReg::Obp0 as u16, MemClient::CPU);
+
+            // Tile 1 is solid color 3, the BG map is all tile 0 (color 0).
+            for i in 0..16 {
+                mem.set(0xff, 0x8010 + i, MemClient::CPU);
+            }
+
+            for i in 0..12 {
+                let addr = PPU::OAM_ADDR + i*4;
+                mem.set(16, addr, MemClient::CPU);
+                mem.set(8 + i as u8 * 8, addr+1, MemClient::CPU);
+                mem.set(1, addr+2, MemClient::CPU);
+                mem.set(0, addr+3, MemClient::CPU);
+            }
+        }
+
+        ppu.pull_registers();
+        ppu.cfg.ly = 0;
+        ppu.oam_search();
+    }
+
+    // Set up one sprite of solid color 3 at the left of line 0 using CGB palette 2, with that color
+    // written as orange (R=31, G=16, B=0), and run OAM search for that line.
+    fn cgb_palette_sprite_on_line_zero(ppu: &mut PPU) {
+        {
+            let mut mem = ppu.mem.lock().unwrap();
+            mem.set(0x93, PPUReg::Lcdc as u16, MemClient::CPU);
+            mem.set(0xe4, PPUReg::Obp0 as u16, MemClient::CPU);
+            for i in 0..16 {
+                mem.set(0xff, 0x8010 + i, MemClient::CPU);
+            }
+
+            mem.set(0x80 | (2*8 + 3*2), OCPS_ADDR, MemClient::CPU);
+            mem.set(0x1f, OCPD_ADDR, MemClient::CPU);
+            mem.set(0x02, OCPD_ADDR, MemClient::CPU);
+
+            mem.set(16, PPU::OAM_ADDR, MemClient::CPU);
+            mem.set(8, PPU::OAM_ADDR+1, MemClient::CPU);
+            mem.set(1, PPU::OAM_ADDR+2, MemClient::CPU);
+            mem.set(0x02, PPU::OAM_ADDR+3, MemClient::CPU);
+        }
+
+        ppu.pull_registers();
+        ppu.cfg.ly = 0;
+        ppu.oam_search();
+    }
+
+    #[test]
+    fn oam_search_limits_sprites_per_line() {
+        let mut ppu = headless_ppu();
+        twelve_sprites_on_line_zero(&mut ppu);
+        ppu.render_line();
+
+        assert_eq!(ppu.line_sprites.len(), 10);
+        let black = (0..PPU::WIDTH).filter(|x| ppu.pixels[x*3] == 0x00).count();
+        assert_eq!(black, 80);
+        assert_eq!(ppu.pixels[79*3], 0x00);
+        assert_eq!(ppu.pixels[80*3], 0xff);
+    }
+
+    #[test]
+    fn unlimited_sprites_draws_all() {
+        let mut ppu = headless_ppu();
+        ppu.unlimited_sprites = true;
+        twelve_sprites_on_line_zero(&mut ppu);
+        ppu.render_line();
+
+        assert_eq!(ppu.line_sprites.len(), 12);
+        let black = (0..PPU::WIDTH).filter(|x| ppu.pixels[x*3] == 0x00).count();
+        assert_eq!(black, 96);
+    }
+
+    // Fill VRAM with a scrolled BG of varied tiles, and OAM with sprites that overlap, hang off
+    // the left edge, flip, sit behind the BG, and use both palettes.
+    fn static_scene(mem: &mut Memory) {
+        mem.set(0x93, PPUReg::Lcdc as u16, MemClient::CPU); // LCD, BG, and OBJs on.
+        mem.set(0xe4, PPUReg::Bgp as u16, MemClient::CPU);
+        mem.set(0xd2, PPUReg::Obp0 as u16, MemClient::CPU);
+        mem.set(0x1b, PPUReg::Obp1 as u16, MemClient::CPU);
+        mem.set(3, PPUReg::Scx as u16, MemClient::CPU);
+        mem.set(5, PPUReg::Scy as u16, MemClient::CPU);
+
+        for i in 0..0x100u16 {
+            mem.set((i * 37 + (i >> 4)) as u8, 0x8000 + i, MemClient::CPU);
+        }
+        for i in 0..0x400u16 {
+            mem.set((i % 13) as u8, 0x9800 + i, MemClient::CPU);
+        }
+
+        let sprites: [(u8, u8, u8, u8); 6] = [
+            (16, 8, 3, 0x00),
+            (20, 12, 5, 0x10),   // Overlaps the first.
+            (16, 4, 7, 0x20),    // Partly off the left edge.
+            (60, 80, 9, 0x80),   // Behind the BG.
+            (60, 80, 2, 0x40),   // Same X, later in OAM.
+            (100, 163, 11, 0x60),
+        ];
+        for (i, (y, x, tile, attrs)) in sprites.iter().enumerate() {
+            let addr = PPU::OAM_ADDR + i as u16 * 4;
+            for (offset, val) in [*y, *x, *tile, *attrs].iter().enumerate() {
+                mem.set(*val, addr + offset as u16, MemClient::CPU);
+            }
+        }
+    }
+
+    #[test]
+    fn fifo_renderer_matches_line_renderer() {
+        let mut frames = Vec::new();
+        for &fifo_renderer in &[false, true] {
+            let mut rcfg = RuntimeConfig::new();
+            rcfg.fifo_renderer = fifo_renderer;
+            let mut ppu = PPU::new_headless(Arc::new(Mutex::new(Memory::new(0x10000))), &rcfg);
+            static_scene(&mut ppu.mem.lock().unwrap());
+            while ppu.frame_count() < 2 {
+                ppu.tick();
+            }
+            frames.push(ppu.framebuffer());
+        }
+
+        assert!(frames[0] == frames[1]);
+    }
+
+    #[test]
+    fn tile_map_text_grid() {
+        let mut ppu = headless_ppu();
+        {
+            let mut mem = ppu.mem.lock().unwrap();
+            mem.set(0x08, PPUReg::Lcdc as u16, MemClient::CPU); // BG map at 0x9c00.
+            for i in 0..0x400 {
+                mem.set((i % 7) as u8, 0x9c00 + i, MemClient::CPU);
+            }
+            mem.set(0xab, 0x9c00 + 31*32 + 31, MemClient::CPU);
+        }
+        ppu.pull_registers();
+
+        let grid = ppu.tile_map_text();
+        assert_eq!(grid.len(), 32);
+        assert!(grid[0].starts_with("00 01 02 03 04 05 06 00 01"));
+        assert_eq!(grid[0].split(' ').count(), 32);
+        assert!(grid[31].ends_with(" ab"));
+    }
+
+    #[test]
+    fn opri_selects_sprite_priority() {
+        let mut ppu = headless_cgb_ppu();
+        {
+            let mut mem = ppu.mem.lock().unwrap();
+            mem.set(0x93, PPUReg::Lcdc as u16, MemClient::CPU);
+            for i in 0..16 {
+                mem.set(0xff, 0x8010 + i, MemClient::CPU);
+            }
+
+            // Palette 0, color 3 stays black. Palette 1, color 3 is white.
+            mem.set(0x80 | (8 + 3*2), OCPS_ADDR, MemClient::CPU);
+            mem.set(0xff, OCPD_ADDR, MemClient::CPU);
+            mem.set(0x7f, OCPD_ADDR, MemClient::CPU);
+
+            // OAM entry 0 covers x in [8, 16), entry 1 has a lower X and covers [4, 12).
+            for (i, (x, attrs)) in [(16, 0x00), (12, 0x01)].iter().enumerate() {
+                let addr = PPU::OAM_ADDR + i as u16 * 4;
+                mem.set(16, addr, MemClient::CPU);
+                mem.set(*x, addr+1, MemClient::CPU);
+                mem.set(1, addr+2, MemClient::CPU);
+                mem.set(*attrs, addr+3, MemClient::CPU);
+            }
+        }
+
+        let mut overlap_shade = |opri: u8| {
+            ppu.mem_set(PPUReg::Opri as u16, opri);
+            ppu.pull_registers();
+            ppu.cfg.ly = 0;
+            ppu.oam_search();
+            ppu.render_line();
+            ppu.pixels[9*3]
+        };
+
+        // DMG-style: the lower X wins. CGB-style: the lower OAM index wins.
+        assert_eq!(overlap_shade(0x01), 0xff);
+        assert_eq!(overlap_shade(0x00), 0x00);
+        assert_eq!(overlap_shade(0x01), 0xff);
+    }
+
+    #[test]
+    fn cgb_object_palette() {
+        let mut ppu = headless_cgb_ppu();
+        cgb_palette_sprite_on_line_zero(&mut ppu);
+
+        // OCPS auto-incremented past both bytes of the color.
+        assert_eq!(ppu.mem.lock().unwrap().get(OCPS_ADDR, MemClient::CPU), 0x80 | 0x40 | (2*8 + 4*2));
+        ppu.render_line();
+        assert_eq!(ppu.pixels[0..3], [0xff, 0x84, 0x00]);
+    }
+
+    #[test]
+    fn dmg_game_renders_with_dmg_palettes() {
+        use crate::memory::Model;
+
+        // Draw a sprite of color 3 from CGB palette 2 after trying to set that color to orange.
+        let sprite_pixel = |cgb_flag: u8, model: Model| {
+            let mut rom = vec![0; 0x8000];
+            rom[0x143] = cgb_flag;
+            let mut mem = Memory::new(0x10000);
+            mem.set_model(model);
+            mem.load_rom(rom);
+            let mut ppu = PPU::new_headless(Arc::new(Mutex::new(mem)), &RuntimeConfig::new());
+            cgb_palette_sprite_on_line_zero(&mut ppu);
+            ppu.render_line();
+            (ppu.pixels[0], ppu.pixels[1], ppu.pixels[2])
+        };
+
+        // Only a CGB game on a CGB gets the palette RAM, everything else gets OBP0's darkest shade.
+        assert_eq!(sprite_pixel(0x80, Model::Cgb), (0xff, 0x84, 0x00));
+        assert_eq!(sprite_pixel(0x00, Model::Cgb), DMG_GRAYS[3]);
+        assert_eq!(sprite_pixel(0x80, Model::Dmg), DMG_GRAYS[3]);
+    }
+
+    #[test]
+    fn slow_frame_warning() {
+        assert_eq!(PPU::slow_frame_warning(Duration::from_millis(16)), None);
+        assert_eq!(PPU::slow_frame_warning(Duration::from_millis(20)).unwrap(),
+                   "Slow frame: took 20000 us, 3257 us over the 16742 us frame period.");
+
+        let mut ppu = headless_ppu();
+        assert_eq!(ppu.end_frame(Duration::from_millis(25)), Duration::from_millis(25));
+        assert_eq!(ppu.end_frame(Duration::from_millis(40)), Duration::from_millis(15));
+    }
+
+    #[test]
+    fn scx_change_logged() {
+        let mut ppu = headless_ppu();
+        assert!(ppu.register_changes().is_empty());
+
+        ppu.set_cpu_pc(0x1234);
+        ppu.mem.lock().unwrap().set(0x10, PPUReg::Scx as u16, MemClient::CPU);
+        assert_eq!(ppu.register_changes(), ["PC=0x1234 SCX: 0x00 -> 0x10"]);
+
+        ppu.pull_registers();
+        assert!(ppu.register_changes().is_empty());
+    }
+
+    #[test]
+    fn mid_line_bgp_change() {
+        let mut ppu = headless_ppu();
+        {
+            // Tile 0 is solid color 1, which BGP initially maps to black.
+            let mut mem = ppu.mem.lock().unwrap();
+            for i in 0..8 {
+                mem.set(0xff, 0x8000 + i*2, MemClient::CPU);
+            }
+        }
+
+        while !(ppu.cfg.state == PPUState::Draw && ppu.cfg.ly == 0) {
+            ppu.tick();
+        }
+        while ppu.line_chunks < 10 {
+            ppu.tick();
+        }
+
+        // Map color 1 to white for the rest of the line.
+        ppu.mem.lock().unwrap().set(0xf0, PPUReg::Bgp as u16, MemClient::CPU);
+        while ppu.cfg.state != PPUState::OAMSearch {
+            ppu.tick();
+        }
+
+        let pixel = |x: usize| ppu.pixels[x*3];
+        assert!((0..80).all(|x| pixel(x) == 0x00));
+        assert!((80..PPU::WIDTH).all(|x| pixel(x) == 0xff));
+    }
+
+    #[test]
+    fn line_callback_once_per_line() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut ppu = headless_ppu();
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let lines_cb = lines.clone();
+        ppu.set_line_callback(move |ly, pixels| {
+            assert_eq!(pixels.len(), PPU::WIDTH * 3);
+            lines_cb.borrow_mut().push(ly);
+        });
+
+        while !(ppu.cfg.state == PPUState::OAMSearch && ppu.cfg.ly == 0) {
+            ppu.tick();
+        }
+        lines.borrow_mut().clear();
+        for _ in 0..(154 * 114) {
+            ppu.tick();
+        }
+
+        assert!(*lines.borrow() == (0..144).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn scanline_filter_dims_odd_rows() {
+        let mut ppu = headless_ppu();
+        ppu.set_scanline_filter(true);
+        while !(ppu.cfg.state == PPUState::OAMSearch && ppu.cfg.ly == 0) {
+            ppu.tick();
+        }
+        for _ in 0..(154 * 114) {
+            ppu.tick();
+        }
+
+        // The blank screen is one shade, so every odd row is the even rows' color, dimmed.
+        let fb = ppu.framebuffer();
+        let shade = fb[0];
+        assert!(shade > 0);
+        for y in 0..PPU::HEIGHT {
+            let expected = if y % 2 == 1 { shade - shade / 4 } else { shade };
+            assert!(fb[y * PPU::WIDTH * 3..(y + 1) * PPU::WIDTH * 3].iter().all(|&c| c == expected), "row {}", y);
+        }
+    }
+
+    #[test]
+    fn vblank_interrupt_once_per_frame() {
+        let mut ppu = headless_ppu();
+        ppu.mem_set(PPUReg::Lcdc as u16, 0x91);
+        let mut requests = Vec::new();
+        while ppu.frames < 3 || ppu.cfg.ly != 0 {
+            ppu.tick();
+            let mut mem = ppu.mem.lock().unwrap();
+            let flags = mem.get(IF_ADDR, MemClient::CPU);
+            if flags & 0x01 != 0 {
+                requests.push((ppu.cfg.ly, ppu.frames));
+                mem.set(flags & !0x01, IF_ADDR, MemClient::CPU);
+            }
+        }
+        assert_eq!(requests, [(144, 1), (144, 2), (144, 3)]);
+    }
+
+    #[test]
+    fn overlapping_stat_conditions_interrupt_once() {
+        let mut ppu = headless_ppu();
+        ppu.mem_set(PPUReg::Lcdc as u16, 0x91);
+        while ppu.cfg.ly != 144 {
+            ppu.tick();
+        }
+        {
+            // HBlank and LY==LYC interrupts, with LYC=0.
+            let mut mem = ppu.mem.lock().unwrap();
+            mem.set(0x48, PPUReg::Stat as u16, MemClient::CPU);
+            mem.set(0x00, PPUReg::Lyc as u16, MemClient::CPU);
+        }
+
+        let stat_interrupts = |ppu: &mut PPU, until_ly: u8| {
+            let mut count = 0;
+            while !(ppu.cfg.ly == until_ly && ppu.cfg.state == PPUState::OAMSearch) {
+                ppu.tick();
+                let mut mem = ppu.mem.lock().unwrap();
+                if mem.get(IF_ADDR, MemClient::CPU) & 0x02 != 0 {
+                    count += 1;
+                    mem.clear_interrupt(1);
+                }
+            }
+            count
+        };
+
+        // On line 0 the coincidence holds the line high through HBlank, so only one interrupt.
+        assert_eq!(stat_interrupts(&mut ppu, 1), 1);
+        // On line 1 the line drops, and HBlank raises it again.
+        assert_eq!(stat_interrupts(&mut ppu, 2), 1);
+    }
+
+    #[test]
+    fn stat_interrupt_sources() {
+        // Count STAT interrupts over one frame with only the given STAT enable bit set.
+        let frame_interrupts = |enable: u8| {
+            let mut ppu = headless_ppu();
+            ppu.mem_set(PPUReg::Lcdc as u16, 0x91);
+            while !(ppu.cfg.ly == 0 && ppu.cfg.state == PPUState::OAMSearch) {
+                ppu.tick();
+            }
+            {
+                let mut mem = ppu.mem.lock().unwrap();
+                mem.set(enable, PPUReg::Stat as u16, MemClient::CPU);
+                mem.set(0x90, PPUReg::Lyc as u16, MemClient::CPU);
+                mem.clear_interrupt(1);
+            }
+
+            // Stop just before the next frame's line 0.
+            let mut lines = Vec::new();
+            for _ in 0..(154 * 114 - 1) {
+                ppu.tick();
+                let mut mem = ppu.mem.lock().unwrap();
+                if mem.get(IF_ADDR, MemClient::CPU) & 0x02 != 0 {
+                    lines.push(ppu.cfg.ly);
+                    mem.clear_interrupt(1);
+                }
+            }
+            lines
+        };
+
+        // Enabling the OAM interrupt during line 0's OAM search raises the line right away.
+        assert_eq!(frame_interrupts(0x20), (0..144).collect::<Vec<u8>>());
+        assert_eq!(frame_interrupts(0x08), (0..144).collect::<Vec<u8>>());
+        assert_eq!(frame_interrupts(0x10), [144]);
+        assert_eq!(frame_interrupts(0x40), [0x90]);
+    }
+
+    #[test]
+    fn lyc_coincidence_flag_on_matching_line() {
+        let mut ppu = headless_ppu();
+        ppu.mem_set(PPUReg::Lcdc as u16, 0x91);
+        ppu.mem.lock().unwrap().set(0x90, PPUReg::Lyc as u16, MemClient::CPU);
+
+        // Every cycle of line 0x90 has STAT bit 2 set, and no other cycle does.
+        let mut coincidence_cycles = 0;
+        for _ in 0..(154 * 114) {
+            ppu.tick();
+            let ly = ppu.mem_get(PPUReg::Ly as u16);
+            let coincidence = ppu.mem_get(PPUReg::Stat as u16) & 0x04 != 0;
+            assert_eq!(coincidence, ly == 0x90, "LY=0x{:02x}", ly);
+            coincidence_cycles += coincidence as u32;
+        }
+        assert_eq!(coincidence_cycles, 114);
+    }
+
+    #[test]
+    fn stat_writes_keep_mode_bits() {
+        let mut ppu = headless_ppu();
+        while ppu.cfg.state != PPUState::Draw {
+            ppu.tick();
+        }
+
+        ppu.mem.lock().unwrap().set(0xff, PPUReg::Stat as u16, MemClient::CPU);
+        let stat = ppu.mem_get(PPUReg::Stat as u16);
+        assert_eq!(stat & 0x03, PPUState::Draw as u8);
+        assert_eq!(stat & 0x78, 0x78);
+
+        ppu.tick();
+        assert!(ppu.cfg.hblank_intr && ppu.cfg.ly_eq_lyc_intr);
+    }
+
+    #[test]
+    fn test_pattern_contents() {
+        let mut ppu = headless_ppu();
+        ppu.render_test_pattern();
+
+        let pixel = |x: usize, y: usize| ppu.pixels[(y * PPU::WIDTH + x) * 3];
+        assert_eq!(pixel(0, 0), 0xff);
+        assert_eq!(pixel(7, 7), 0xff);
+        assert_eq!(pixel(8, 0), 0xaa);
+        assert_eq!(pixel(8, 8), 0x55);
+        assert_eq!(pixel(24, 0), 0x00);
+        assert_eq!(pixel(159, 143), ppu.shade_to_rgb((19 + 17) % 4).0);
+    }
+
+    #[test]
+    fn custom_dmg_palette() {
+        let palette = parse_dmg_palette("#e0f8d0 88c070\n346856, 081820\n").unwrap();
+        assert_eq!(palette, [(0xe0, 0xf8, 0xd0), (0x88, 0xc0, 0x70), (0x34, 0x68, 0x56), (0x08, 0x18, 0x20)]);
+
+        let mut ppu = headless_ppu();
+        ppu.set_dmg_palette(palette);
+        for shade in 0..4 {
+            assert_eq!(ppu.shade_to_rgb(shade), palette[shade as usize]);
+        }
+
+        assert!(parse_dmg_palette("e0f8d0 88c070 346856").is_err());
+        assert!(parse_dmg_palette("e0f8d0 88c070 346856 081820 ffffff").is_err());
+        assert!(parse_dmg_palette("e0f8d0 88c070 346856 08182").is_err());
+        assert!(parse_dmg_palette("e0f8d0 88c070 346856 zz1820").is_err());
+    }
+
+    #[test]
+    fn framebuffer_is_packed_rgb() {
+        let mut rcfg = RuntimeConfig::new();
+        rcfg.pixel_format = PixelFormat::Bgra32;
+        let mut ppu = PPU::new_headless(Arc::new(Mutex::new(Memory::new(0x10000))), &rcfg);
+        ppu.mem_set(PPUReg::Lcdc as u16, 0x91);
+        while ppu.frame_count() == 0 {
+            ppu.tick();
+        }
+        ppu.put_pixel(159, 143, (0x11, 0x22, 0x33));
+
+        let fb = ppu.framebuffer();
+        assert_eq!(fb.len(), 69120);
+        assert_eq!(fb[69117..], [0x11, 0x22, 0x33]);
+    }
+
+    #[test]
+    fn pixel_format_byte_order() {
+        let mut rcfg = RuntimeConfig::new();
+        rcfg.pixel_format = PixelFormat::Bgra32;
+        let mut ppu = PPU::new_headless(Arc::new(Mutex::new(Memory::new(0x10000))), &rcfg);
+        assert_eq!(ppu.pixels.len(), PPU::WIDTH * PPU::HEIGHT * 4);
+
+        ppu.put_pixel(2, 1, (0x11, 0x22, 0x33));
+        let addr = (PPU::WIDTH + 2) * 4;
+        assert_eq!(ppu.pixels[addr..addr + 4], [0x33, 0x22, 0x11, 0xff]);
+
+        ppu.pixel_format = PixelFormat::Argb32;
+        ppu.put_pixel(2, 1, (0x11, 0x22, 0x33));
+        assert_eq!(ppu.pixels[addr..addr + 4], [0xff, 0x11, 0x22, 0x33]);
+    }
+}
@@ -31,6 +31,17 @@ pub enum Reg16 {
     PC
 }
 
+// An 8-bit instruction operand: either a register, or the byte in memory pointed to by HL.
+#[derive(Copy, Clone)]
+pub enum Operand8 {
+    Reg(Reg8),
+    HlPtr
+}
+
+impl From<Reg8> for Operand8 {
+    fn from(r: Reg8) -> Operand8 { Operand8::Reg(r) }
+}
+
 pub trait Reg : Clone + Copy {}
 impl Reg for Reg8  {}
 impl Reg for Reg16 {}
@@ -164,6 +175,19 @@ impl RegisterCache {
         self.set(Reg8::F, flags);
     }
 
+    // All flags as a byte, with Z/N/H/CY in bits 7-4 and the low nibble always zero.
+    pub fn flags_byte(&self) -> u8 {
+        [Flag::Z, Flag::N, Flag::H, Flag::CY].iter()
+            .filter(|&&f| self.get_flag(f))
+            .fold(0, |acc, &f| acc | RegisterCache::flag_mask(f))
+    }
+
+    pub fn set_flags_byte(&mut self, flags: u8) {
+        for &f in [Flag::Z, Flag::N, Flag::H, Flag::CY].iter() {
+            self.set_flag(f, (flags & RegisterCache::flag_mask(f)) != 0);
+        }
+    }
+
     pub fn print_registers(&self) {
         print!("AF: ");
         self.af.print_contents();
@@ -233,3 +257,21 @@ impl RegOps<Reg16, u16> for RegisterCache {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_byte_round_trip() {
+        let mut regs = RegisterCache::new();
+        for nibble in 0..16u8 {
+            let flags = nibble << 4;
+            regs.set_flags_byte(flags | 0x0f);
+            assert_eq!(regs.flags_byte(), flags);
+            assert_eq!(regs.get(Reg8::F), flags);
+            assert_eq!(regs.get_flag(Flag::Z), (flags & 0x80) != 0);
+            assert_eq!(regs.get_flag(Flag::CY), (flags & 0x10) != 0);
+        }
+    }
+}
@@ -0,0 +1,272 @@
+// The serial link port, through the SB (data) and SC (control) registers. A transfer starts when
+// the game writes SC with bit 7 set. The side using its internal clock (SC bit 0 set) drives the
+// transfer: it sends its SB byte to the peer and waits for the peer's byte in return. The
+// externally clocked side answers with its own SB byte once it has started a transfer too. When
+// the bytes are swapped, SB holds the received byte, SC bit 7 clears, and the Serial interrupt is
+// requested. With no link connected, an internally clocked transfer still finishes after its 8 bit
+// times, shifting in 1s from the idle line, while an externally clocked one waits forever.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+pub const SB_ADDR: u16 = 0xff01;
+pub const SC_ADDR: u16 = 0xff02;
+
+// Clock cycles per bit with the 8192 Hz internal clock.
+const BIT_CLOCKS: u64 = 512;
+
+// A link to the peer's serial port.
+pub trait Transport: Send {
+    // Send a byte to the peer.
+    fn send(&mut self, byte: u8);
+
+    // The next byte from the peer, if one has arrived. Must not block.
+    fn recv(&mut self) -> Option<u8>;
+}
+
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    sent: bool, // Our byte was sent for the transfer in progress, we're waiting on the reply.
+    clocks_left: u64, // Until an internally clocked transfer finishes, with no link connected.
+    transport: Option<Box<dyn Transport>>,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Serial::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Serial {
+            sb: 0,
+            sc: 0,
+            sent: false,
+            clocks_left: 0,
+            transport: None,
+        }
+    }
+
+    pub fn connect(&mut self, transport: Box<dyn Transport>) {
+        self.transport = Some(transport);
+    }
+
+    // Clear the registers and any transfer in progress, leaving the link connected.
+    pub fn reset(&mut self) {
+        let transport = self.transport.take();
+        *self = Serial::new();
+        self.transport = transport;
+    }
+
+    // The registers and the transfer in progress, for save states. The link itself isn't saved.
+    pub fn state(&self) -> [u8; 11] {
+        let mut state = [0; 11];
+        state[0] = self.sb;
+        state[1] = self.sc;
+        state[2] = self.sent as u8;
+        state[3..].copy_from_slice(&self.clocks_left.to_le_bytes());
+        state
+    }
+
+    pub fn set_state(&mut self, state: [u8; 11]) {
+        self.sb = state[0];
+        self.sc = state[1];
+        self.sent = state[2] != 0;
+        let mut clocks_left = [0; 8];
+        clocks_left.copy_from_slice(&state[3..11]);
+        self.clocks_left = u64::from_le_bytes(clocks_left);
+    }
+
+    // SC bits 1-6 don't exist on DMG and read as 1.
+    pub fn read(&self, addr: u16) -> u8 {
+        if addr == SC_ADDR { self.sc | 0x7e } else { self.sb }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        if addr == SC_ADDR {
+            self.sc = val & 0x81;
+            self.sent = false;
+            self.clocks_left = 8 * BIT_CLOCKS;
+        } else {
+            self.sb = val;
+        }
+    }
+
+    // Advance the transfer in progress, if any, by the given clock cycles. Returns true when one
+    // completes, so the caller can request the Serial interrupt.
+    pub fn step(&mut self, cycles: u64) -> bool {
+        if self.sc & 0x80 == 0 { return false; }
+        let transport = match &mut self.transport {
+            Some(t) => t,
+            None => return self.step_unconnected(cycles),
+        };
+
+        let received = if self.sc & 0x01 != 0 {
+            if !self.sent {
+                transport.send(self.sb);
+                self.sent = true;
+            }
+            transport.recv()
+        } else {
+            let received = transport.recv();
+            if received.is_some() {
+                transport.send(self.sb);
+            }
+            received
+        };
+
+        match received {
+            Some(byte) => {
+                self.sb = byte;
+                self.sc &= 0x7f;
+                self.sent = false;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn step_unconnected(&mut self, cycles: u64) -> bool {
+        if self.sc & 0x01 == 0 { return false; }
+        self.clocks_left = self.clocks_left.saturating_sub(cycles);
+        if self.clocks_left > 0 { return false; }
+
+        self.sb = 0xff;
+        self.sc &= 0x7f;
+        true
+    }
+}
+
+// A link between two emulators over TCP, one listening and one connecting.
+pub struct TcpTransport {
+    stream: TcpStream,
+}
+
+impl TcpTransport {
+    // Wait for a peer to connect on the given address.
+    pub fn listen(addr: &str) -> io::Result<TcpTransport> {
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        TcpTransport::new(stream)
+    }
+
+    pub fn connect(addr: &str) -> io::Result<TcpTransport> {
+        TcpTransport::new(TcpStream::connect(addr)?)
+    }
+
+    fn new(stream: TcpStream) -> io::Result<TcpTransport> {
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+        Ok(TcpTransport { stream })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&mut self, byte: u8) {
+        if let Err(e) = self.stream.write_all(&[byte]) {
+            println!("Serial link error: {}", e);
+        }
+    }
+
+    fn recv(&mut self) -> Option<u8> {
+        let mut buf = [0];
+        match self.stream.read(&mut buf) {
+            Ok(1) => Some(buf[0]),
+            _ => None,
+        }
+    }
+}
+
+// A link between two emulators in the same process.
+pub struct ChannelTransport {
+    tx: Sender<u8>,
+    rx: Receiver<u8>,
+}
+
+impl ChannelTransport {
+    // Two transports connected to each other.
+    pub fn pair() -> (ChannelTransport, ChannelTransport) {
+        let (tx_a, rx_a) = mpsc::channel();
+        let (tx_b, rx_b) = mpsc::channel();
+        (ChannelTransport { tx: tx_a, rx: rx_b }, ChannelTransport { tx: tx_b, rx: rx_a })
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn send(&mut self, byte: u8) {
+        // The peer hanging up just leaves our transfer waiting, like a pulled cable.
+        self.tx.send(byte).ok();
+    }
+
+    fn recv(&mut self) -> Option<u8> {
+        self.rx.try_recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+    use crate::emulator::Emulator;
+    use crate::memory::{MemClient, IF_ADDR};
+    use crate::RuntimeConfig;
+
+    // A CPU that loads SB with the given byte and starts a transfer with the given SC value.
+    fn linked_cpu(sb: u8, sc: u8, transport: ChannelTransport) -> CPU {
+        let program = [
+            0x3e, sb,   // LD A,sb
+            0xe0, 0x01, // LDH (SB),A
+            0x3e, sc,   // LD A,sc
+            0xe0, 0x02, // LDH (SC),A
+            0x18, 0xfe, // JR -2
+        ];
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+
+        let cpu = Emulator::headless(rom, &RuntimeConfig::new()).into_cpu();
+        cpu.mem.lock().unwrap().connect_serial(Box::new(transport));
+        cpu
+    }
+
+    #[test]
+    fn loopback_exchange() {
+        let (a, b) = ChannelTransport::pair();
+        let mut master = linked_cpu(0x42, 0x81, a);
+        let mut slave = linked_cpu(0x99, 0x80, b);
+        for _ in 0..8 {
+            master.tick();
+            slave.tick();
+        }
+
+        for (cpu, received) in [(&master, 0x99), (&slave, 0x42)].iter() {
+            let mem = cpu.mem.lock().unwrap();
+            assert_eq!(mem.get(SB_ADDR, MemClient::CPU), *received);
+            assert_eq!(mem.get(SC_ADDR, MemClient::CPU) & 0x80, 0);
+            assert_eq!(mem.get(IF_ADDR, MemClient::CPU) & 0x08, 0x08);
+        }
+    }
+
+    #[test]
+    fn unconnected_transfer() {
+        // With the internal clock, the transfer finishes after 8 bits, receiving 0xff.
+        let mut serial = Serial::new();
+        serial.write(SB_ADDR, 0x42);
+        serial.write(SC_ADDR, 0x81);
+        assert!(!serial.step(8 * BIT_CLOCKS - 4));
+        assert_eq!(serial.read(SB_ADDR), 0x42);
+        assert_eq!(serial.read(SC_ADDR), 0xff);
+        assert!(serial.step(4));
+        assert_eq!(serial.read(SB_ADDR), 0xff);
+        assert_eq!(serial.read(SC_ADDR), 0x7f);
+        assert!(!serial.step(8 * BIT_CLOCKS));
+
+        // With the external clock, nothing ever drives it.
+        serial.write(SB_ADDR, 0x42);
+        serial.write(SC_ADDR, 0x80);
+        assert!(!serial.step(100 * BIT_CLOCKS));
+        assert_eq!(serial.read(SB_ADDR), 0x42);
+        assert_eq!(serial.read(SC_ADDR), 0xfe);
+    }
+}
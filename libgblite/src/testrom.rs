@@ -0,0 +1,73 @@
+// Assembles small programs into complete 32 KiB ROM images, so tests can run targeted code
+// without shipping real cartridges. The image has a valid header: the entry point jumps to the
+// program at 0x150, the Nintendo logo is in place, and both checksums are filled in. The cart is
+// ROM only, with no RAM.
+
+// Where the program starts, right after the header.
+pub const PROGRAM_ADDR: u16 = 0x150;
+
+const ROM_SIZE: usize = 0x8000;
+
+const LOGO: [u8; 48] = [
+    0xce, 0xed, 0x66, 0x66, 0xcc, 0x0d, 0x00, 0x0b, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0c, 0x00, 0x0d,
+    0x00, 0x08, 0x11, 0x1f, 0x88, 0x89, 0x00, 0x0e, 0xdc, 0xcc, 0x6e, 0xe6, 0xdd, 0xdd, 0xd9, 0x99,
+    0xbb, 0xbb, 0x67, 0x63, 0x6e, 0x0e, 0xec, 0xcc, 0xdd, 0xdc, 0x99, 0x9f, 0xbb, 0xb9, 0x33, 0x3e,
+];
+
+// Build a ROM that runs the given opcodes from PROGRAM_ADDR. Panics if they don't fit.
+pub fn build(program: &[u8]) -> Vec<u8> {
+    assert!(program.len() <= ROM_SIZE - PROGRAM_ADDR as usize, "program too large for a 32 KiB ROM");
+
+    let mut rom = vec![0; ROM_SIZE];
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xc3, PROGRAM_ADDR as u8, (PROGRAM_ADDR >> 8) as u8]); // NOP, JP
+    rom[0x104..0x134].copy_from_slice(&LOGO);
+    rom[0x134..0x13c].copy_from_slice(b"TESTROM\0");
+    rom[0x14d] = header_checksum(&rom);
+
+    let start = PROGRAM_ADDR as usize;
+    rom[start..start + program.len()].copy_from_slice(program);
+
+    let global = global_checksum(&rom);
+    rom[0x14e] = (global >> 8) as u8;
+    rom[0x14f] = global as u8;
+    rom
+}
+
+// The checksum over [0x134, 0x14c] that the boot ROM verifies, stored at 0x14d.
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[0x134..0x14d].iter().fold(0u8, |sum, b| sum.wrapping_sub(*b).wrapping_sub(1))
+}
+
+// The sum of every byte but the checksum itself, stored big endian at 0x14e.
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter().enumerate()
+        .filter(|(i, _)| *i != 0x14e && *i != 0x14f)
+        .fold(0u16, |sum, (_, b)| sum.wrapping_add(*b as u16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+    use crate::registers::*;
+    use crate::RuntimeConfig;
+
+    #[test]
+    fn runs_to_halt() {
+        let rom = build(&[
+            0x0e, 0x05, // LD C,5
+            0x04,       // INC B
+            0x0d,       // DEC C
+            0x20, 0xfc, // JR NZ,-4
+            0x76,       // HALT
+        ]);
+        assert_eq!(rom.len(), ROM_SIZE);
+        assert_eq!(rom[0x14d], header_checksum(&rom));
+        assert_eq!(((rom[0x14e] as u16) << 8) | rom[0x14f] as u16, global_checksum(&rom));
+
+        let mut cpu = Emulator::headless(rom, &RuntimeConfig::new()).into_cpu();
+
+        assert!(cpu.run_until(|cpu, _| cpu.halted(), 1000));
+        assert_eq!(cpu.regs.get(Reg8::B), 5);
+    }
+}
@@ -0,0 +1,152 @@
+// The divider and timer, through the DIV, TIMA, TMA, and TAC registers. An internal 16 bit counter
+// runs at the 4.194304 MHz clock, and DIV is its upper byte, so DIV counts at 16384 Hz. TIMA counts
+// on each falling edge of the counter bit selected by TAC, while TAC bit 2 enables it. So a DIV
+// reset or TAC change that drops the selected bit also counts, as on hardware. When TIMA overflows
+// it reloads from TMA and the Timer interrupt is requested.
+
+pub const DIV_ADDR: u16 = 0xff04;
+pub const TIMA_ADDR: u16 = 0xff05;
+pub const TMA_ADDR: u16 = 0xff06;
+pub const TAC_ADDR: u16 = 0xff07;
+
+// The counter bit TIMA follows for each TAC clock select: 4096, 262144, 65536, and 16384 Hz.
+const TAC_BITS: [u16; 4] = [9, 3, 5, 7];
+
+pub struct Timer {
+    counter: u16,    // Internal divider counter, DIV is its upper byte.
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    overflowed: bool, // TIMA overflowed since the last take_interrupt.
+}
+
+impl Timer {
+    pub fn new(counter: u16) -> Self {
+        Timer {
+            counter,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflowed: false,
+        }
+    }
+
+    // TAC bits 3-7 don't exist and read as 1.
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            DIV_ADDR => (self.counter >> 8) as u8,
+            TIMA_ADDR => self.tima,
+            TMA_ADDR => self.tma,
+            _ => self.tac | 0xf8,
+        }
+    }
+
+    // Any write to DIV resets the whole counter.
+    pub fn write(&mut self, addr: u16, val: u8) {
+        let input = self.input();
+        match addr {
+            DIV_ADDR => self.counter = 0,
+            TIMA_ADDR => self.tima = val,
+            TMA_ADDR => self.tma = val,
+            _ => self.tac = val & 0x07,
+        }
+        if input && !self.input() {
+            self.count();
+        }
+    }
+
+    // Run the given number of clock cycles, a machine cycle at a time.
+    pub fn step(&mut self, cycles: u64) {
+        for _ in 0..cycles / 4 {
+            let input = self.input();
+            self.counter = self.counter.wrapping_add(4);
+            if input && !self.input() {
+                self.count();
+            }
+        }
+    }
+
+    // True once for each TIMA overflow, when the Timer interrupt should be requested.
+    pub fn take_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.overflowed)
+    }
+
+    // The signal TIMA counts the falling edges of: the selected counter bit, if enabled.
+    fn input(&self) -> bool {
+        self.tac & 0x04 != 0 && self.counter & (1 << TAC_BITS[(self.tac & 0x03) as usize]) != 0
+    }
+
+    // Hardware reloads TIMA a machine cycle after it overflows. This reloads right away.
+    fn count(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        if overflow {
+            self.tima = self.tma;
+            self.overflowed = true;
+        } else {
+            self.tima = tima;
+        }
+    }
+
+    // The raw counter and registers, for save states.
+    pub fn state(&self) -> [u8; 5] {
+        let [lo, hi] = self.counter.to_le_bytes();
+        [lo, hi, self.tima, self.tma, self.tac]
+    }
+
+    pub fn set_state(&mut self, state: [u8; 5]) {
+        self.counter = u16::from_le_bytes([state[0], state[1]]);
+        self.tima = state[2];
+        self.tma = state[3];
+        self.tac = state[4] & 0x07;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn div_and_tima_rates() {
+        let mut timer = Timer::new(0);
+        timer.step(256 * 3);
+        assert_eq!(timer.read(DIV_ADDR), 3);
+
+        // At 262144 Hz TIMA counts every 16 clocks. Overflow reloads from TMA and interrupts once.
+        timer.write(DIV_ADDR, 0x55);
+        timer.write(TMA_ADDR, 0xf0);
+        timer.write(TIMA_ADDR, 0xfe);
+        timer.write(TAC_ADDR, 0x05);
+        assert_eq!(timer.read(TAC_ADDR), 0xfd);
+        timer.step(16);
+        assert_eq!(timer.read(TIMA_ADDR), 0xff);
+        assert!(!timer.take_interrupt());
+        timer.step(16);
+        assert_eq!(timer.read(TIMA_ADDR), 0xf0);
+        assert!(timer.take_interrupt());
+        assert!(!timer.take_interrupt());
+
+        // At 4096 Hz it takes 1024 clocks.
+        timer.write(TAC_ADDR, 0x04);
+        timer.write(DIV_ADDR, 0);
+        timer.write(TIMA_ADDR, 0);
+        timer.step(1020);
+        assert_eq!(timer.read(TIMA_ADDR), 0);
+        timer.step(4);
+        assert_eq!(timer.read(TIMA_ADDR), 1);
+
+        // Disabled, it doesn't count at all.
+        timer.write(TAC_ADDR, 0x01);
+        timer.step(1024);
+        assert_eq!(timer.read(TIMA_ADDR), 1);
+    }
+
+    #[test]
+    fn div_reset_counts_falling_edge() {
+        // With bit 9 of the counter set, resetting DIV drops it, which TIMA counts.
+        let mut timer = Timer::new(0x0200);
+        timer.write(TAC_ADDR, 0x04);
+        timer.write(DIV_ADDR, 0x00);
+        assert_eq!(timer.read(DIV_ADDR), 0);
+        assert_eq!(timer.read(TIMA_ADDR), 1);
+    }
+}
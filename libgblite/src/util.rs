@@ -1,5 +1,7 @@
 #![allow(dead_code)]
 
+use std::path::{Path, PathBuf};
+
 use chrono::{Utc, Datelike, Timelike};
 
 /// Join two u8 bytes into a single u16, little endian.
@@ -48,10 +50,49 @@ pub fn is_bit_set(word: u8, bit: u8) -> bool {
     (word & (1 << bit)) != 0
 }
 
-pub fn create_file_name(suffix: &str) -> String {
+/// Advance a xorshift64 PRNG and return the next value. A zero state is replaced with a fixed
+/// nonzero seed, since xorshift would otherwise only ever produce zero.
+///
+/// ```
+/// use libgblite::util;
+/// let (mut a, mut b) = (42, 42);
+/// assert_eq!(util::xorshift64(&mut a), util::xorshift64(&mut b));
+/// assert_ne!(util::xorshift64(&mut a), 0);
+/// ```
+pub fn xorshift64(state: &mut u64) -> u64 {
+    if *state == 0 {
+        *state = 0x9e3779b97f4a7c15;
+    }
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+/// Create a timestamped log file name, placed in the given directory if there is one.
+pub fn create_file_name(dir: Option<&str>, suffix: &str) -> String {
     let dt = Utc::now();
-    format!("gblite_{}_{:02}_{:02}_{}{}.log", dt.year(), dt.month(),dt.day(),
-            dt.num_seconds_from_midnight(), suffix)
+    let name = format!("gblite_{}_{:02}_{:02}_{}{}.log", dt.year(), dt.month(),dt.day(),
+            dt.num_seconds_from_midnight(), suffix);
+    match dir {
+        Some(d) => Path::new(d).join(name).to_string_lossy().into_owned(),
+        None => name,
+    }
+}
+
+/// Build the path of a save file for the given ROM: the ROM's base name with the given
+/// extension, in the save directory if there is one, or the working directory otherwise.
+///
+/// ```
+/// use libgblite::util;
+/// assert_eq!(util::save_file_path(None, "roms/tetris.gb", "sav").to_str(), Some("tetris.sav"));
+/// ```
+pub fn save_file_path(save_dir: Option<&str>, rom_file: &str, ext: &str) -> PathBuf {
+    // Append the extension rather than setting it, which would cut a dotted name at its last dot.
+    let stem = Path::new(rom_file).file_stem().unwrap_or_default();
+    let mut path = PathBuf::from(save_dir.unwrap_or(""));
+    path.push(format!("{}.{}", stem.to_string_lossy(), ext));
+    path
 }
 
 
@@ -95,4 +136,13 @@ mod test {
             assert_eq!(is_bit_set(word, bit), false);
         }
     }
+
+    #[test]
+    fn test_save_file_path() {
+        let path = save_file_path(Some("/home/user/saves"), "/home/user/roms/Pokemon Red.gb", "sav");
+        assert_eq!(path, Path::new("/home/user/saves/Pokemon Red.sav"));
+        assert_eq!(save_file_path(None, "roms/Pokemon.Red.gb", "sav"), Path::new("Pokemon.Red.sav"));
+        assert_eq!(save_file_path(None, "roms/Pokemon.Blue.gb", "sav"), Path::new("Pokemon.Blue.sav"));
+        assert!(create_file_name(Some("logs"), "_mem").starts_with("logs/gblite_"));
+    }
 }
\ No newline at end of file
@@ -6,6 +6,25 @@ use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
 
+use crate::joypad::Button;
+use crate::ppu::PixelFormat;
+use crate::RuntimeConfig;
+
+// Options for opening the window, taken from the runtime config.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct WindowConfig {
+    pub vsync: bool, // Wait for the display's vertical blank to present. Off by default, which can
+                     // tear but adds no latency.
+}
+
+impl WindowConfig {
+    pub fn new(rcfg: &RuntimeConfig) -> Self {
+        WindowConfig {
+            vsync: rcfg.vsync,
+        }
+    }
+}
+
 pub struct Window {
     sdl: sdl2::Sdl,
     canvas: render::Canvas<video::Window>,
@@ -13,10 +32,11 @@ pub struct Window {
     height: u32,
     event_cnt: u32,
     open: bool,
+    filter_toggle: bool, // F was pressed since the last take_filter_toggle.
 }
 
 impl Window {
-    pub fn new(w: usize, h: usize) -> Self {
+    pub fn new(w: usize, h: usize, wcfg: WindowConfig) -> Self {
         let (wi, hi) = (w as u32, h as u32);
         let sdl = sdl2::init().unwrap();
         let video = sdl.video().unwrap();
@@ -25,7 +45,9 @@ impl Window {
                        .build()
                        .unwrap();
 
-        let mut can = win.into_canvas().build().unwrap();
+        let builder = win.into_canvas();
+        let builder = if wcfg.vsync { builder.present_vsync() } else { builder };
+        let mut can = builder.build().unwrap();
         can.set_draw_color(Color::RGB(0, 255, 255));
 
         Window {
@@ -35,14 +57,21 @@ impl Window {
             height: hi,
             event_cnt: 0,
             open: true,
+            filter_toggle: false,
         }
     }
 
-    pub fn draw(&mut self, pixels: &[u8]) {
+    pub fn draw(&mut self, pixels: &[u8], format: PixelFormat) {
+        let sdl_format = match format {
+            PixelFormat::Rgb24  => PixelFormatEnum::RGB24,
+            PixelFormat::Bgra32 => PixelFormatEnum::BGRA32,
+            PixelFormat::Argb32 => PixelFormatEnum::ARGB32,
+        };
+
         let tex_creator = self.canvas.texture_creator();
         let mut tex = tex_creator.create_texture_streaming(
-            PixelFormatEnum::RGB24, self.width, self.height).unwrap();
-        tex.update(None, &pixels, 3 * self.width as usize).unwrap();
+            sdl_format, self.width, self.height).unwrap();
+        tex.update(None, &pixels, format.bytes_per_pixel() * self.width as usize).unwrap();
 
         self.canvas.clear();
         self.canvas.copy(&tex, None, None).unwrap();
@@ -51,10 +80,12 @@ impl Window {
 
     // TODO: Move this to another thread. Maybe the entire window could be run in a binary package
     // on a separate thread? It could set up channels to communicate with the PPU/CPU.
-    pub fn get_events(&mut self) {
+    // Poll window events, and return any joypad button presses (true) or releases (false).
+    pub fn get_events(&mut self) -> Vec<(Button, bool)> {
+        let mut buttons = Vec::new();
         self.event_cnt += 1;
         if self.event_cnt < 250 {
-            return;
+            return buttons;
         } else {
             self.event_cnt = 0;
         }
@@ -65,9 +96,39 @@ impl Window {
                 Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                     self.close();
                 },
+                Event::KeyDown { keycode: Some(Keycode::F), repeat: false, .. } => {
+                    self.filter_toggle = true;
+                },
+                Event::KeyDown { keycode: Some(key), repeat: false, .. } => {
+                    if let Some(b) = Window::map_key(key) { buttons.push((b, true)); }
+                },
+                Event::KeyUp { keycode: Some(key), .. } => {
+                    if let Some(b) = Window::map_key(key) { buttons.push((b, false)); }
+                },
                 _ => ()
             }
         }
+
+        buttons
+    }
+
+    fn map_key(key: Keycode) -> Option<Button> {
+        match key {
+            Keycode::Right     => Some(Button::Right),
+            Keycode::Left      => Some(Button::Left),
+            Keycode::Up        => Some(Button::Up),
+            Keycode::Down      => Some(Button::Down),
+            Keycode::Z         => Some(Button::A),
+            Keycode::X         => Some(Button::B),
+            Keycode::Backspace => Some(Button::Select),
+            Keycode::Return    => Some(Button::Start),
+            _ => None
+        }
+    }
+
+    // True once per press of the scanline filter key.
+    pub fn take_filter_toggle(&mut self) -> bool {
+        std::mem::take(&mut self.filter_toggle)
     }
 
     pub fn is_open(&self) -> bool {
@@ -78,3 +139,16 @@ impl Window {
         self.open = false;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vsync_from_runtime_config() {
+        let mut rcfg = RuntimeConfig::new();
+        assert!(!WindowConfig::new(&rcfg).vsync);
+        rcfg.vsync = true;
+        assert!(WindowConfig::new(&rcfg).vsync);
+    }
+}